@@ -16,10 +16,9 @@
  *
  */
 
-use std::io::{BufWriter, Read, Write};
+use std::io::{Read, Write};
 use std::path::PathBuf;
 use std::result::Result::Ok;
-use std::sync::mpsc::{channel, TryRecvError};
 use std::time::Duration;
 use std::{env, io, thread};
 
@@ -97,49 +96,40 @@ fn main() -> anyhow::Result<()> {
 
     assert_eq!(handle.view().uart_channels.len(), 1);
 
-    let (sender, receiver) = channel();
-
-    thread::spawn(move || loop {
-        let mut line = String::new();
-        print!("$> ");
-        io::stdout().flush().unwrap();
-        io::stdin().read_line(&mut line).unwrap();
-        line.pop(); // pop away the new line
-        if line.is_empty() || line == "\n" || line == "~QUIT" || sender.send(line).is_err() {
-            println!("EXITING THREAD!");
-            return;
-        }
-    });
+    thread::scope(|scope| {
+        let (mut uart0_rx, mut uart0_tx) = handle.view().uart_channels[0].split();
+
+        // Feeds stdin to the sketch until an empty line or ~QUIT
+        let input = scope.spawn(move || {
+            loop {
+                let mut line = String::new();
+                print!("$> ");
+                io::stdout().flush().unwrap();
+                io::stdin().read_line(&mut line).unwrap();
+                line.pop(); // pop away the new line
+                if line.is_empty() || line == "~QUIT" {
+                    println!("EXITING THREAD!");
+                    return;
+                }
+                let _ = uart0_tx.write(line.as_bytes());
+            }
+        });
 
-    {
-        let mut uart0 = &handle.view().uart_channels[0];
-        let mut uart0_writebuf = BufWriter::new(uart0);
         let mut read_buf = String::new();
-        loop {
-            if uart0.read_to_string(&mut read_buf).unwrap() > 0 {
+        while !input.is_finished() {
+            if uart0_rx.read_to_string(&mut read_buf).unwrap() > 0 {
                 println!("arduino: \"{}\"", read_buf.escape_default());
                 read_buf.clear();
             }
 
-            match receiver.try_recv() {
-                Ok(line) => {
-                    let _ = uart0_writebuf.write(line.as_bytes());
-                }
-                Err(TryRecvError::Disconnected) => {
-                    break;
-                }
-                _ => {}
-            }
-
             thread::sleep(Duration::from_millis(1));
 
             if handle.tick().is_err() {
+                println!("Sketch exited, press enter to quit");
                 break;
             }
-
-            let _ = uart0_writebuf.flush();
         }
-    }
+    });
 
     println!("Stopped with exit code: {}", handle.stop());
 
@@ -0,0 +1,415 @@
+/*
+ *  actuator.rs
+ *  Copyright 2021 ItJustWorksTM
+ *
+ *  Licensed under the Apache License, Version 2.0 (the "License");
+ *  you may not use this file except in compliance with the License.
+ *  You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ *  Unless required by applicable law or agreed to in writing, software
+ *  distributed under the License is distributed on an "AS IS" BASIS,
+ *  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ *  See the License for the specific language governing permissions and
+ *  limitations under the License.
+ *
+ */
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::board_view::BoardView;
+use crate::meter::EdgeLog;
+use crate::simulation::{require_output, Peripheral, PeripheralError};
+
+// How often pins are sampled between simulation steps to time edges on them by default
+pub const EDGE_SAMPLE_INTERVAL: Duration = Duration::from_micros(50);
+
+#[derive(Debug, Copy, Clone, Eq, Hash, PartialEq)]
+pub enum ServoSignal {
+    // High pulses on the pin, measured as precisely as `sample_interval`
+    Pulse,
+    // analogWrite duty cycle, `max` being always high over the PWM `period`
+    Duty { max: u16, period: Duration },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ServoConfig {
+    pub signal: ServoSignal,
+    // Pulse widths for the ends of the range, the Arduino library defaults
+    pub min_pulse: Duration,
+    pub max_pulse: Duration,
+    // Degrees between both ends
+    pub range: f64,
+    // Degrees per second the horn turns at most
+    pub speed: f64,
+    // Pulses are timed on a worker sampling the pin this often, `None` samples only when the
+    // simulation steps. Widths are off by up to this much, at the default that is about
+    // 5 degrees, and a zero interval samples as fast as it can at the cost of a core.
+    pub sample_interval: Option<Duration>,
+}
+
+impl Default for ServoConfig {
+    fn default() -> Self {
+        ServoConfig {
+            signal: ServoSignal::Pulse,
+            min_pulse: Duration::from_micros(544),
+            max_pulse: Duration::from_micros(2400),
+            range: 180.0,
+            // Typical 0.1 s per 60 degrees
+            speed: 600.0,
+            sample_interval: Some(EDGE_SAMPLE_INTERVAL),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub struct ServoSample {
+    pub time: Duration,
+    // Degrees
+    pub target: f64,
+    pub angle: f64,
+}
+
+/// Hobby servo on an output of the sketch, turning towards the angle it is told.
+///
+/// Starts centered, the history gets a sample whenever the target or angle changes.
+#[derive(Debug, Clone)]
+pub struct Servo {
+    pin: usize,
+    config: ServoConfig,
+    time: Duration,
+    target: f64,
+    angle: f64,
+    // Start of the current high pulse
+    rise: Option<Duration>,
+    edges: Option<Arc<EdgeLog>>,
+    history: Vec<ServoSample>,
+}
+
+impl Servo {
+    pub fn new(pin: usize, config: ServoConfig) -> Self {
+        let center = config.range / 2.0;
+        Servo {
+            pin,
+            config,
+            time: Duration::ZERO,
+            target: center,
+            angle: center,
+            rise: None,
+            edges: None,
+            history: vec![ServoSample {
+                time: Duration::ZERO,
+                target: center,
+                angle: center,
+            }],
+        }
+    }
+
+    pub fn angle(&self) -> f64 {
+        self.angle
+    }
+
+    // Angle the servo is turning to
+    pub fn target(&self) -> f64 {
+        self.target
+    }
+
+    pub fn history(&self) -> &[ServoSample] {
+        &self.history
+    }
+
+    fn pulse_to_angle(&self, width: Duration) -> f64 {
+        let min = self.config.min_pulse.as_secs_f64();
+        let max = self.config.max_pulse.as_secs_f64();
+        let fraction = (width.as_secs_f64() - min) / (max - min);
+        fraction.clamp(0.0, 1.0) * self.config.range
+    }
+
+    // The pin is at `level` since `at`, pulses are timed by whatever clock `at` comes from
+    fn level(&mut self, level: bool, at: Duration) {
+        match (level, self.rise) {
+            (true, None) => self.rise = Some(at),
+            (false, Some(rise)) => {
+                self.rise = None;
+                self.target = self.pulse_to_angle(at.saturating_sub(rise));
+            }
+            _ => {}
+        }
+    }
+
+    // Advances by `dt`, with the level and analog value of the pin
+    fn update(&mut self, level: bool, analog: u16, dt: Duration) {
+        self.time += dt;
+        match self.config.signal {
+            ServoSignal::Pulse => self.level(level, self.time),
+            // Servos ignore a signal that stays low
+            ServoSignal::Duty { max, period } if analog > 0 => {
+                let width = period.mul_f64(analog.min(max) as f64 / max as f64);
+                self.target = self.pulse_to_angle(width);
+            }
+            ServoSignal::Duty { .. } => {}
+        }
+        self.turn(dt);
+    }
+
+    fn turn(&mut self, dt: Duration) {
+        let travel = self.config.speed * dt.as_secs_f64();
+        self.angle += (self.target - self.angle).clamp(-travel, travel);
+
+        let last = self.history.last().copied().unwrap_or_default();
+        if last.target != self.target || last.angle != self.angle {
+            self.history.push(ServoSample {
+                time: self.time,
+                target: self.target,
+                angle: self.angle,
+            });
+        }
+    }
+}
+
+impl Peripheral for Servo {
+    fn attach(&mut self, view: &BoardView) -> Result<(), PeripheralError> {
+        let pin = require_output(view, self.pin)?;
+        if let (ServoSignal::Pulse, Some(interval)) =
+            (self.config.signal, self.config.sample_interval)
+        {
+            self.edges = Some(Arc::new(EdgeLog::spawn(&view.workers, &[pin], interval)));
+        }
+        Ok(())
+    }
+
+    fn step(&mut self, view: &BoardView, dt: Duration) {
+        match self.edges.as_ref().map(|edges| edges.take()) {
+            Some(changes) => {
+                self.time += dt;
+                for (at, levels) in changes {
+                    self.level(levels[0], at);
+                }
+                self.turn(dt);
+            }
+            None => {
+                let pin = &view.pins[self.pin];
+                self.update(pin.digital_read(), pin.analog_read(), dt);
+            }
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, Hash, PartialEq)]
+pub struct StepperSample {
+    pub time: Duration,
+    pub position: i64,
+}
+
+/// Stepper driver counting rising edges on STEP, DIR high steps forward unless `reversed`.
+///
+/// A worker samples the pins every [`EDGE_SAMPLE_INTERVAL`] by default, STEP pulses or gaps
+/// shorter than the sample interval can still be missed. Without a sample interval the pins
+/// are only read when the simulation steps, so at most one step is taken per interval.
+#[derive(Debug, Clone)]
+pub struct Stepper {
+    step: usize,
+    dir: usize,
+    // Active low, like on the common driver boards
+    enable: Option<usize>,
+    reversed: bool,
+    steps_per_revolution: u32,
+    sample_interval: Option<Duration>,
+    edges: Option<Arc<EdgeLog>>,
+    time: Duration,
+    stepping: bool,
+    position: i64,
+    history: Vec<StepperSample>,
+}
+
+impl Stepper {
+    pub fn new(step: usize, dir: usize) -> Self {
+        Stepper {
+            step,
+            dir,
+            enable: None,
+            reversed: false,
+            steps_per_revolution: 200,
+            sample_interval: Some(EDGE_SAMPLE_INTERVAL),
+            edges: None,
+            time: Duration::ZERO,
+            stepping: false,
+            position: 0,
+            history: vec![StepperSample {
+                time: Duration::ZERO,
+                position: 0,
+            }],
+        }
+    }
+
+    pub fn with_enable(self, enable: usize) -> Self {
+        Stepper {
+            enable: Some(enable),
+            ..self
+        }
+    }
+
+    pub fn reversed(self, reversed: bool) -> Self {
+        Stepper { reversed, ..self }
+    }
+
+    pub fn with_steps_per_revolution(self, steps_per_revolution: u32) -> Self {
+        Stepper {
+            steps_per_revolution,
+            ..self
+        }
+    }
+
+    // `None` only reads the pins when the simulation steps
+    pub fn with_sample_interval(self, sample_interval: Option<Duration>) -> Self {
+        Stepper {
+            sample_interval,
+            ..self
+        }
+    }
+
+    // Steps from the starting position
+    pub fn position(&self) -> i64 {
+        self.position
+    }
+
+    // Degrees from the starting position
+    pub fn angle(&self) -> f64 {
+        self.position as f64 * 360.0 / self.steps_per_revolution as f64
+    }
+
+    pub fn history(&self) -> &[StepperSample] {
+        &self.history
+    }
+
+    // Advances by `dt` with the levels of the pins
+    fn update(&mut self, step: bool, dir: bool, enabled: bool, dt: Duration) {
+        self.time += dt;
+        self.level(step, dir, enabled);
+    }
+
+    fn level(&mut self, step: bool, dir: bool, enabled: bool) {
+        let rising = step && !self.stepping;
+        self.stepping = step;
+        if rising && enabled {
+            self.position += if dir != self.reversed { 1 } else { -1 };
+            self.history.push(StepperSample {
+                time: self.time,
+                position: self.position,
+            });
+        }
+    }
+}
+
+impl Peripheral for Stepper {
+    fn attach(&mut self, view: &BoardView) -> Result<(), PeripheralError> {
+        let mut pins = vec![
+            require_output(view, self.step)?,
+            require_output(view, self.dir)?,
+        ];
+        if let Some(enable) = self.enable {
+            pins.push(require_output(view, enable)?);
+        }
+        self.stepping = pins[0].digital_read();
+        if let Some(interval) = self.sample_interval {
+            self.edges = Some(Arc::new(EdgeLog::spawn(&view.workers, &pins, interval)));
+        }
+        Ok(())
+    }
+
+    fn step(&mut self, view: &BoardView, dt: Duration) {
+        if let Some(changes) = self.edges.as_ref().map(|edges| edges.take()) {
+            self.time += dt;
+            for (_, levels) in changes {
+                // Levels come in the order the pins were handed over in attach
+                self.level(levels[0], levels[1], levels.get(2) != Some(&true));
+            }
+            return;
+        }
+
+        let enabled = !matches!(self.enable, Some(enable) if view.pins[enable].digital_read());
+        self.update(
+            view.pins[self.step].digital_read(),
+            view.pins[self.dir].digital_read(),
+            enabled,
+            dt,
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use crate::actuator::{Servo, ServoConfig, ServoSignal, Stepper};
+
+    #[test]
+    fn servo_pulses_and_slew() {
+        let ms = Duration::from_millis(1);
+        let mut servo = Servo::new(0, ServoConfig::default());
+        assert_eq!(servo.angle(), 90.0);
+
+        // 2.4 ms high asks for the far end
+        servo.update(true, 0, ms);
+        servo.update(false, 0, Duration::from_micros(2400));
+        assert_eq!(servo.target(), 180.0);
+        // 2.4 ms at 600 degrees per second
+        assert!((servo.angle() - 91.44).abs() < 1e-9);
+
+        for _ in 0..200 {
+            servo.update(false, 0, ms);
+        }
+        assert_eq!(servo.angle(), 180.0);
+        let last = *servo.history().last().unwrap();
+        assert_eq!((last.target, last.angle), (180.0, 180.0));
+    }
+
+    #[test]
+    fn servo_times_edges() {
+        let us = Duration::from_micros;
+        let mut servo = Servo::new(0, ServoConfig::default());
+        // Timed by the sampling clock, a simulation step would see at most 1 ms resolution
+        servo.level(true, us(10_000));
+        servo.level(false, us(11_936));
+        assert!((servo.target() - 135.0).abs() < 1e-9);
+        servo.level(false, us(12_000));
+        assert!((servo.target() - 135.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn servo_duty() {
+        let config = ServoConfig {
+            signal: ServoSignal::Duty {
+                max: 255,
+                period: Duration::from_millis(20),
+            },
+            speed: f64::INFINITY,
+            ..Default::default()
+        };
+        let mut servo = Servo::new(0, config);
+        servo.update(false, 13, Duration::from_millis(1));
+        let width = 13.0 / 255.0 * 20000.0;
+        assert!((servo.angle() - (width - 544.0) / (2400.0 - 544.0) * 180.0).abs() < 1e-3);
+        servo.update(false, 0, Duration::from_millis(1));
+        assert_eq!(servo.history().len(), 2);
+    }
+
+    #[test]
+    fn stepper_counts() {
+        let dt = Duration::from_millis(1);
+        let mut stepper = Stepper::new(0, 1).with_steps_per_revolution(4);
+        for i in 0..10 {
+            stepper.update(i % 2 == 0, true, true, dt);
+        }
+        assert_eq!(stepper.position(), 5);
+        for i in 0..4 {
+            stepper.update(i % 2 == 0, false, true, dt);
+        }
+        stepper.update(true, false, false, dt);
+        assert_eq!(stepper.position(), 3);
+        assert_eq!(stepper.angle(), 270.0);
+        assert_eq!(stepper.history().len(), 8);
+    }
+}
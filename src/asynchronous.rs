@@ -0,0 +1,184 @@
+/*
+ *  asynchronous.rs
+ *  Copyright 2021 ItJustWorksTM
+ *
+ *  Licensed under the Apache License, Version 2.0 (the "License");
+ *  you may not use this file except in compliance with the License.
+ *  You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ *  Unless required by applicable law or agreed to in writing, software
+ *  distributed under the License is distributed on an "AS IS" BASIS,
+ *  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ *  See the License for the specific language governing permissions and
+ *  limitations under the License.
+ *
+ */
+
+use std::future::Future;
+use std::io::{self, Read, Write};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::{Instant, Sleep};
+
+use crate::board_view::{uart_exited, UartChannel, UartRx, UartTx};
+use crate::toolchain::BuildLogReader;
+
+// How long pending operations wait before trying again
+const POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+// Stands in for native readiness notifications by waking the task again shortly
+#[derive(Default)]
+pub(crate) struct Poller {
+    // Only created once polled, as that is when a runtime is guaranteed
+    sleep: Option<Pin<Box<Sleep>>>,
+}
+
+impl Poller {
+    pub(crate) fn retry<T>(&mut self, cx: &mut Context<'_>) -> Poll<T> {
+        let deadline = Instant::now() + POLL_INTERVAL;
+        let sleep = self
+            .sleep
+            .get_or_insert_with(|| Box::pin(tokio::time::sleep_until(deadline)));
+        sleep.as_mut().reset(deadline);
+        if sleep.as_mut().poll(cx).is_ready() {
+            cx.waker().wake_by_ref();
+        }
+        Poll::Pending
+    }
+}
+
+// Reads at least one byte if there is any, the native read never fills the last byte of buf
+fn read_some(reader: &mut impl Read, buf: &mut ReadBuf<'_>) -> io::Result<usize> {
+    if buf.remaining() > 1 {
+        let read = reader.read(buf.initialize_unfilled())?;
+        buf.advance(read);
+        Ok(read)
+    } else {
+        let mut byte = [0; 2];
+        let read = reader.read(&mut byte)?;
+        buf.put_slice(&byte[..read]);
+        Ok(read)
+    }
+}
+
+impl UartChannel {
+    // Like split(), with halves that wait for the sketch instead of returning early
+    pub fn split_async(&self) -> (AsyncUartRx<'_>, AsyncUartTx<'_>) {
+        let (rx, tx) = self.split();
+        (
+            AsyncUartRx {
+                inner: rx,
+                poller: Poller::default(),
+            },
+            AsyncUartTx {
+                inner: tx,
+                poller: Poller::default(),
+            },
+        )
+    }
+}
+
+/// Receiving half of a [`UartChannel`], reads wait until the sketch has written something.
+pub struct AsyncUartRx<'a> {
+    inner: UartRx<'a>,
+    poller: Poller,
+}
+
+impl AsyncRead for AsyncUartRx<'_> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if buf.remaining() == 0 {
+            return Poll::Ready(Ok(()));
+        }
+        match read_some(&mut this.inner, buf)? {
+            0 => this.poller.retry(cx),
+            _ => Poll::Ready(Ok(())),
+        }
+    }
+}
+
+/// Sending half of a [`UartChannel`], writes wait for the sketch to make room and flushing waits
+/// for it to read everything.
+///
+/// Both fail once the board noticed the sketch exited, which takes something ticking the board,
+/// [`BoardHandle::wait_async`](crate::board::BoardHandle::wait_async) for one.
+pub struct AsyncUartTx<'a> {
+    inner: UartTx<'a>,
+    poller: Poller,
+}
+
+impl AsyncWrite for AsyncUartTx<'_> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        match this.inner.write(buf) {
+            Err(err) if err.kind() == io::ErrorKind::WriteZero && !this.inner.alive() => {
+                Poll::Ready(Err(uart_exited(buf.len())))
+            }
+            Err(err) if err.kind() == io::ErrorKind::WriteZero => this.poller.retry(cx),
+            result => Poll::Ready(result),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        match this.inner.pending() {
+            0 => Poll::Ready(Ok(())),
+            left if !this.inner.alive() => Poll::Ready(Err(uart_exited(left))),
+            _ => this.poller.retry(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.poll_flush(cx)
+    }
+}
+
+impl BuildLogReader {
+    pub fn into_async(self) -> AsyncBuildLog {
+        AsyncBuildLog {
+            inner: self,
+            poller: Poller::default(),
+        }
+    }
+}
+
+/// Build log that waits for more output, ending once the compilation is done.
+pub struct AsyncBuildLog {
+    inner: BuildLogReader,
+    poller: Poller,
+}
+
+impl AsyncRead for AsyncBuildLog {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if buf.remaining() == 0 || read_some(&mut this.inner, buf)? > 0 {
+            return Poll::Ready(Ok(()));
+        }
+        if this.inner.disconnected() {
+            // Output may have arrived right before the compilation finished
+            read_some(&mut this.inner, buf)?;
+            return Poll::Ready(Ok(()));
+        }
+        this.poller.retry(cx)
+    }
+}
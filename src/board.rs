@@ -16,7 +16,14 @@
  *
  */
 
-use std::{cell::UnsafeCell, io, io::Read};
+use std::cell::{Cell, RefCell, UnsafeCell};
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant, SystemTime};
+use std::{io, io::Read, thread};
 
 use cxx::UniquePtr;
 use thiserror::Error;
@@ -25,12 +32,66 @@ use crate::board_config::BoardConfig;
 use crate::board_view::{
     BoardView, FrameBuffer, FrameBuffers, GpioPin, Pins, UartChannel, UartChannels,
 };
+use crate::events::{self, BoardEvent, EventConfig};
 use crate::ffi::{board_new, ExitInfo, OpaqueBoard, OpaqueBoardStatus, OpaqueBoardView};
+use crate::limits::{Limit, LimitMonitor, ResourceLimits};
+use crate::meter::{MeterConfig, PinMeter};
+use crate::process::{self, Pid, Termination};
+use crate::runtime_log::{LogHistory, LogStream, RuntimeLog};
+use crate::sandbox::{self, Confinement, Sandbox};
+use crate::signal::{self, Signal, SignalConfig, SignalDriver, SignalError};
 use crate::sketch::Sketch;
+use crate::stimulus::{self, Playback, Schedule, StimulusError};
+use crate::vcd::{self, VcdConfig, VcdRecording};
+use crate::worker::Workers;
+
+// How often wait() checks on the sketch
+const WAIT_INTERVAL: Duration = Duration::from_millis(10);
+
+// How often the output of a running sketch is read, which is when its lines get timestamped
+const LOG_POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+// Held while preparing, see Board::prepare
+static PREPARING: Mutex<()> = Mutex::new(());
 
 #[derive(Default)]
 pub struct Board {
-    internal: Option<(UnsafeCell<UniquePtr<OpaqueBoard>>, BoardView)>,
+    internal: Option<BoardInternal>,
+    limits: ResourceLimits,
+    sandbox: Option<Sandbox>,
+    // Shared with the worker reading the sketch output
+    log: Arc<Mutex<RuntimeLog>>,
+}
+
+struct BoardInternal {
+    native: UnsafeCell<UniquePtr<OpaqueBoard>>,
+    view: BoardView,
+    confinement: Option<Confinement>,
+    pid: Cell<Option<Pid>>,
+    // How the sketch ended as told by the wait status, when it could be looked at
+    termination: Cell<Option<Termination>>,
+    monitor: RefCell<Option<LimitMonitor>>,
+    exit_status: Cell<Option<ExitStatus>>,
+    // Last status reported to event receivers
+    status: Cell<Status>,
+    // Subscribers, with what their view poller was asked to watch
+    events: RefCell<Vec<(EventConfig, Sender<BoardEvent>)>>,
+    workers: Workers,
+}
+
+impl BoardInternal {
+    fn emit(&self, event: BoardEvent) {
+        self.events
+            .borrow_mut()
+            .retain(|(_, sender)| sender.send(event.clone()).is_ok());
+    }
+}
+
+impl Drop for BoardInternal {
+    // Workers use handles into the board's shared memory, so they go first
+    fn drop(&mut self) {
+        self.workers.shutdown();
+    }
 }
 
 pub struct BoardHandle<'a> {
@@ -41,7 +102,30 @@ type ExitCode = i32;
 
 impl Board {
     pub fn new() -> Self {
-        Self { internal: None }
+        Self {
+            internal: None,
+            limits: ResourceLimits::default(),
+            sandbox: None,
+            log: Arc::default(),
+        }
+    }
+
+    // Applies to sketches started after this call
+    pub fn set_limits(&mut self, limits: ResourceLimits) {
+        self.limits = limits;
+    }
+
+    pub fn limits(&self) -> &ResourceLimits {
+        &self.limits
+    }
+
+    // Applies to boards prepared after this call
+    pub fn set_sandbox(&mut self, sandbox: Option<Sandbox>) {
+        self.sandbox = sandbox;
+    }
+
+    pub fn sandbox(&self) -> Option<&Sandbox> {
+        self.sandbox.as_ref()
     }
 
     pub fn prepare(
@@ -55,21 +139,17 @@ impl Board {
         if !sketch.compiled() {
             return Err(BoardError::SketchNotCompiled);
         }
+        if !self.limits.is_unlimited() && !cfg!(target_os = "linux") {
+            return Err(BoardError::LimitsUnsupported);
+        }
+        if self.sandbox.is_some() && !cfg!(target_os = "linux") {
+            return Err(BoardError::SandboxUnsupported);
+        }
 
-        let mut board: UniquePtr<OpaqueBoard> = unsafe { board_new() };
-        let native_config = config.as_native();
-        assert!(!board.is_null() && !native_config.is_null());
-
-        // configure
-        assert!(unsafe { board.pin_mut().configure(&native_config) });
-
-        // attach
-        assert!(unsafe { board.pin_mut().attach_sketch(&sketch.internal) });
-
-        // prepare
-        assert!(unsafe { board.pin_mut().prepare() });
+        let (mut board, segments) = Self::prepare_native(config, sketch, self.sandbox.is_some());
 
         let mut bv: UniquePtr<OpaqueBoardView> = unsafe { board.pin_mut().view() };
+        let workers = Workers::default();
 
         let bvstr = BoardView {
             pins: Pins {
@@ -85,6 +165,8 @@ impl Board {
                                         bv.pin_mut().get_pin(a.pin_id as usize)
                                     }),
                                     info: a.clone(),
+                                    taps: Default::default(),
+                                    workers: workers.clone(),
                                 },
                             )
                         })
@@ -103,6 +185,7 @@ impl Board {
                             ret
                         }),
                         info: info.clone(),
+                        workers: workers.clone(),
                     })
                     .collect(),
             },
@@ -123,12 +206,206 @@ impl Board {
                     })
                     .collect(),
             },
+            workers: workers.clone(),
         };
 
-        self.internal = Some((UnsafeCell::new(board), bvstr));
+        self.internal = Some(BoardInternal {
+            native: UnsafeCell::new(board),
+            view: bvstr,
+            confinement: self
+                .sandbox
+                .as_ref()
+                .map(|sandbox| sandbox.confinement(config, sketch, segments)),
+            pid: Cell::new(None),
+            termination: Cell::new(None),
+            monitor: RefCell::new(None),
+            exit_status: Cell::new(None),
+            status: Cell::new(Status::Stopped),
+            events: RefCell::new(vec![]),
+            workers,
+        });
         Ok(self.handle().unwrap())
     }
 
+    // A configured native board with the sketch attached and prepared, and the shared memory
+    // segments it created when asked for
+    fn prepare_native(
+        config: &BoardConfig,
+        sketch: &Sketch,
+        segments: bool,
+    ) -> (UniquePtr<OpaqueBoard>, Vec<PathBuf>) {
+        let mut board: UniquePtr<OpaqueBoard> = unsafe { board_new() };
+        let native_config = config.as_native();
+        assert!(!board.is_null() && !native_config.is_null());
+
+        // configure
+        assert!(unsafe { board.pin_mut().configure(&native_config) });
+
+        // attach
+        assert!(unsafe { board.pin_mut().attach_sketch(&sketch.internal) });
+
+        // prepare, one board at a time so the shared memory that shows up is its own
+        let _preparing = PREPARING.lock().unwrap_or_else(PoisonError::into_inner);
+        let before = if segments {
+            Some(sandbox::shm_segments())
+        } else {
+            None
+        };
+        assert!(unsafe { board.pin_mut().prepare() });
+        let segments = before
+            .map(|before| {
+                sandbox::shm_segments()
+                    .difference(&before)
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        (board, segments)
+    }
+
+    // Prepares the sketch again after it exited, for the supervisor. Unlike stop() followed by
+    // prepare() this keeps the view, event streams and log subscribers, the view is pointed at
+    // the new board and bytes the sketch did not read yet are handed to the new sketch.
+    // Everything else running in the background, like meters, recordings, playbacks, signal
+    // drivers and bridges, ended with the old sketch.
+    pub(crate) fn prepare_again(
+        &mut self,
+        config: &BoardConfig,
+        sketch: &Sketch,
+    ) -> Result<(), BoardError> {
+        if self.internal.is_none() {
+            return self.prepare(config, sketch).map(drop);
+        }
+        if !sketch.compiled() {
+            return Err(BoardError::SketchNotCompiled);
+        }
+        if !self.limits.is_unlimited() && !cfg!(target_os = "linux") {
+            return Err(BoardError::LimitsUnsupported);
+        }
+        if self.sandbox.is_some() && !cfg!(target_os = "linux") {
+            return Err(BoardError::SandboxUnsupported);
+        }
+        if let Some(handle) = self.handle() {
+            if handle.tick().is_ok() {
+                return Err(BoardError::AlreadyRunning);
+            }
+        }
+        self.pump_log();
+        let internal = self.internal.as_mut().unwrap();
+
+        let mut buf = [0; 256];
+        let pending: Vec<Vec<u8>> = internal
+            .view
+            .uart_channels
+            .inner
+            .iter_mut()
+            .map(|uart| {
+                let mut pending = vec![];
+                loop {
+                    let read = unsafe { uart.inner.get_mut().pin_mut().take_pending(&mut buf) };
+                    if read == 0 {
+                        break pending;
+                    }
+                    pending.extend_from_slice(&buf[..read]);
+                }
+            })
+            .collect();
+
+        // Workers hold handles into the old board
+        internal.workers.shutdown();
+        let workers = Workers::default();
+
+        let (mut board, segments) = Self::prepare_native(config, sketch, self.sandbox.is_some());
+        let mut bv: UniquePtr<OpaqueBoardView> = unsafe { board.pin_mut().view() };
+        for (&id, pin) in internal.view.pins.inner.iter_mut() {
+            *pin.inner.get_mut() = unsafe { bv.pin_mut().get_pin(id) };
+            pin.workers = workers.clone();
+        }
+        for (i, (uart, pending)) in internal
+            .view
+            .uart_channels
+            .inner
+            .iter_mut()
+            .zip(pending)
+            .enumerate()
+        {
+            let mut native = unsafe { bv.pin_mut().get_uart(i) };
+            assert!(!native.is_null());
+            let mut pending = pending.as_slice();
+            while !pending.is_empty() {
+                let written = unsafe { native.pin_mut().write(pending) };
+                if written == 0 {
+                    break;
+                }
+                pending = &pending[written..];
+            }
+            *uart.inner.get_mut() = native;
+            uart.workers = workers.clone();
+        }
+        for (&key, fb) in internal.view.frame_buffers.inner.iter_mut() {
+            *fb.inner.get_mut() = unsafe { bv.pin_mut().get_framebuffer(key) };
+        }
+
+        // The view no longer points into the old board, so it can go
+        *internal.native.get_mut() = board;
+        internal.confinement = self
+            .sandbox
+            .as_ref()
+            .map(|sandbox| sandbox.confinement(config, sketch, segments));
+        internal.pid.set(None);
+        internal.termination.set(None);
+        *internal.monitor.get_mut() = None;
+        internal.exit_status.set(None);
+        internal.view.workers = workers.clone();
+        internal.workers = workers;
+
+        // View pollers ended with the old sketch
+        for (config, sender) in internal.events.get_mut().iter() {
+            events::spawn_poller(
+                &internal.workers,
+                &internal.view,
+                config.clone(),
+                sender.clone(),
+            );
+        }
+        Ok(())
+    }
+
+    // Outlives the sketch, so it can still be inspected after it exited
+    pub fn runtime_log(&self) -> LogHistory {
+        self.pump_log();
+        self.lock_log().history()
+    }
+
+    pub fn runtime_log_mut(&mut self) -> MutexGuard<'_, RuntimeLog> {
+        self.pump_log();
+        self.lock_log()
+    }
+
+    fn lock_log(&self) -> MutexGuard<'_, RuntimeLog> {
+        lock(&self.log)
+    }
+
+    // Moves everything the native runtime log has gathered into ours
+    fn pump_log(&self) {
+        if let Some(internal) = &self.internal {
+            pump_stderr(unsafe { &*internal.native.get() }, &mut self.lock_log());
+        }
+    }
+
+    fn log_notice(&self, notice: String) {
+        self.pump_log();
+        let mut log = self.lock_log();
+        log.flush(LogStream::Stdout);
+        log.flush(LogStream::Stderr);
+        log.record(LogStream::Runtime, notice);
+    }
+
+    pub(crate) fn view(&self) -> Option<&BoardView> {
+        self.internal.as_ref().map(|internal| &internal.view)
+    }
+
     pub fn handle(&mut self) -> Option<BoardHandle<'_>> {
         if self.internal.is_some() {
             Some(BoardHandle { board: self })
@@ -136,6 +413,77 @@ impl Board {
             None
         }
     }
+
+    // Drops the prepared board, which ends all event streams
+    fn release(&mut self) {
+        self.internal = None;
+        self.lock_log().unsubscribe_all();
+    }
+}
+
+fn lock(log: &Mutex<RuntimeLog>) -> MutexGuard<'_, RuntimeLog> {
+    log.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn pump_stderr(native: &OpaqueBoard, log: &mut RuntimeLog) {
+    let mut buf = [0; 1024];
+    loop {
+        let read = unsafe { native.runtime_log(&mut buf) };
+        if read == 0 {
+            break;
+        }
+        log.feed(LogStream::Stderr, &buf[..read]);
+    }
+}
+
+// False once the sketch closed its stdout
+fn pump_stdout(stdout: &mut File, log: &mut RuntimeLog) -> bool {
+    let mut buf = [0; 1024];
+    loop {
+        match stdout.read(&mut buf) {
+            Ok(0) => return false,
+            Ok(read) => log.feed(LogStream::Stdout, &buf[..read]),
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return err.kind() == io::ErrorKind::WouldBlock,
+        }
+    }
+}
+
+// The native board for the log pump, only its runtime log is used which libSMCE guards
+struct NativeLog(*const OpaqueBoard);
+
+unsafe impl Send for NativeLog {}
+
+// Reads the sketch output while it runs, so lines are timestamped close to when they were written
+fn spawn_log_pump(
+    workers: &Workers,
+    native: &OpaqueBoard,
+    stdout: Option<File>,
+    log: Arc<Mutex<RuntimeLog>>,
+) {
+    let native = NativeLog(native);
+    workers.spawn(move |liveness| {
+        let native = native;
+        let mut stdout = stdout;
+        loop {
+            // One more round after the sketch exited picks up what it wrote last
+            let alive = liveness.alive();
+            {
+                let mut log = lock(&log);
+                pump_stderr(unsafe { &*native.0 }, &mut log);
+                if let Some(pipe) = &mut stdout {
+                    if !pump_stdout(pipe, &mut log) {
+                        log.flush(LogStream::Stdout);
+                        stdout = None;
+                    }
+                }
+            }
+            if !alive {
+                break;
+            }
+            thread::sleep(LOG_POLL_INTERVAL);
+        }
+    });
 }
 
 #[derive(Debug, Copy, Clone, Eq, Hash, Ord, PartialEq, PartialOrd)]
@@ -145,19 +493,93 @@ pub enum Status {
     Stopped,
 }
 
+#[derive(Debug, Copy, Clone, Eq, Hash, PartialEq)]
+pub enum ExitStatus {
+    Exited(ExitCode),
+    LimitExceeded(Limit),
+}
+
+#[derive(Debug, Copy, Clone, Eq, Hash, PartialEq)]
+pub enum StopOutcome {
+    // The sketch had already exited before it was asked to stop
+    AlreadyExited(ExitCode),
+    // The sketch exited within the grace period after receiving SIGTERM
+    Graceful(ExitCode),
+    // The sketch outlived the grace period, or could not be signalled, and got terminated.
+    // Also when SIGTERM killed it as the sketch does not handle it.
+    Terminated,
+}
+
 impl BoardHandle<'_> {
     // unwrap is safe as we only exist when active
     #[doc(hidden)]
-    fn internal(&self) -> &(UnsafeCell<UniquePtr<OpaqueBoard>>, BoardView) {
+    fn internal(&self) -> &BoardInternal {
         self.board.internal.as_ref().unwrap()
     }
 
+    // Spawns the sketch process, inside the sandbox and with the resource limits if set. Fails if
+    // either could not be applied, see ResourceLimits for limits that fail in the sketch process.
     pub fn start(&self) -> bool {
-        unsafe { (*self.internal().0.get()).pin_mut().start() }
+        let internal = self.internal();
+        let spawned_at = SystemTime::now();
+        let native = unsafe { &mut *internal.native.get() };
+        let rlimits = self.board.limits.rlimits();
+        let unlimited = self.board.limits.is_unlimited();
+        let mut spawn = || {
+            let start = || process::spawned_by(|| unsafe { native.pin_mut().start() });
+            if unlimited {
+                Some(process::stdout_captured(start))
+            } else {
+                process::limited_forks(rlimits, || process::stdout_captured(start)).ok()
+            }
+        };
+
+        let spawned = match &internal.confinement {
+            Some(confinement) => confinement.run(spawn).ok().flatten(),
+            None => spawn(),
+        };
+        let ((started, spawned), stdout) = match spawned {
+            Some(spawned) => spawned,
+            None => return false,
+        };
+        if !started {
+            return false;
+        }
+
+        internal.pid.set(spawned.first().copied());
+        internal.termination.set(None);
+        internal.exit_status.set(None);
+        spawn_log_pump(&internal.workers, native, stdout, self.board.log.clone());
+        self.board.log_notice(match internal.pid.get() {
+            Some(pid) => format!("Sketch started with pid {}", pid),
+            None => "Sketch started".into(),
+        });
+
+        self.report_status();
+
+        if self.board.limits.is_unlimited() {
+            return true;
+        }
+
+        // Open files and processes can not be watched without knowing which process to look at
+        match internal
+            .pid
+            .get()
+            .map(|pid| LimitMonitor::attach(pid, self.board.limits.clone(), spawned_at))
+        {
+            Some(monitor) => {
+                *internal.monitor.borrow_mut() = Some(monitor);
+                true
+            }
+            None => {
+                unsafe { (*internal.native.get()).pin_mut().terminate() };
+                false
+            }
+        }
     }
 
     pub fn status(&self) -> Status {
-        match unsafe { (*self.internal().0.get()).pin_mut().status() } {
+        match unsafe { (*self.internal().native.get()).pin_mut().status() } {
             OpaqueBoardStatus::Running => Status::Running,
             OpaqueBoardStatus::Suspended => Status::Suspended,
             _ => Status::Stopped,
@@ -165,45 +587,258 @@ impl BoardHandle<'_> {
     }
 
     pub fn suspend(&self) -> bool {
-        unsafe { (*self.internal().0.get()).pin_mut().suspend() }
+        let suspended = unsafe { (*self.internal().native.get()).pin_mut().suspend() };
+        self.report_status();
+        suspended
     }
 
     pub fn resume(&self) -> bool {
-        unsafe { (*self.internal().0.get()).pin_mut().resume() }
+        let resumed = unsafe { (*self.internal().native.get()).pin_mut().resume() };
+        self.report_status();
+        resumed
+    }
+
+    fn report_status(&self) {
+        let internal = self.internal();
+        let new = self.status();
+        let old = internal.status.replace(new);
+        if old != new {
+            internal.emit(BoardEvent::StatusChanged { old, new });
+        }
+    }
+
+    /// Stream of everything happening on the board until the sketch exits or is stopped.
+    ///
+    /// The view is polled and log lines are read on background threads, while status changes and
+    /// the exit are only picked up when this handle is used, so keep calling [`tick`](Self::tick).
+    pub fn events(&self, config: EventConfig) -> Receiver<BoardEvent> {
+        let internal = self.internal();
+        let (sender, receiver) = mpsc::channel();
+
+        if internal.exit_status.get().is_none() {
+            events::spawn_poller(
+                &internal.workers,
+                &internal.view,
+                config.clone(),
+                sender.clone(),
+            );
+        }
+
+        let log_sender = sender.clone();
+        self.board.lock_log().subscribe(Box::new(move |record| {
+            log_sender.send(BoardEvent::Log(record.clone())).is_ok()
+        }));
+
+        internal.events.borrow_mut().push((config, sender));
+        receiver
+    }
+
+    // Records all pins to a Value Change Dump file until the sketch exits or the recording
+    // is stopped
+    pub fn record_vcd<P: AsRef<Path>>(
+        &self,
+        path: P,
+        config: VcdConfig,
+    ) -> io::Result<VcdRecording> {
+        let internal = self.internal();
+        let out = BufWriter::new(File::create(path)?);
+        vcd::spawn_recorder(&internal.workers, &internal.view, out, config)
+    }
+
+    // Plays the schedule onto the pins on a background thread, fails if it uses unknown pins
+    pub fn play(&self, schedule: Schedule) -> Result<Playback, StimulusError> {
+        let internal = self.internal();
+        stimulus::spawn_playback(&internal.workers, &internal.view, schedule)
+    }
+
+    // Measures a pin over a sliding window, None if the pin is not part of the board
+    pub fn meter(&self, pin: usize, config: MeterConfig) -> Option<PinMeter> {
+        let internal = self.internal();
+        let pin = internal.view.pins.get(pin)?;
+        Some(PinMeter::spawn(&internal.workers, pin, config))
+    }
+
+    // Keeps writing the signal to an input pin of the sketch on a background thread
+    pub fn drive_analog(
+        &self,
+        pin: usize,
+        signal: impl Into<Signal>,
+        config: SignalConfig,
+    ) -> Result<SignalDriver, SignalError> {
+        let internal = self.internal();
+        signal::spawn_driver(
+            &internal.workers,
+            &internal.view,
+            pin,
+            &signal.into(),
+            config,
+        )
     }
 
     pub fn view(&self) -> &BoardView {
-        &self.internal().1
+        &self.internal().view
     }
 
     pub fn log(&self) -> BoardLogReader {
         BoardLogReader { handle: self }
     }
 
+    pub fn runtime_log(&self) -> LogHistory {
+        self.board.runtime_log()
+    }
+
     // Calls tick() once, if the sketch is still running we explicitly terminate
     pub fn stop(self) -> ExitCode {
         let exit_code = match self.tick() {
             Err(exit_code) => exit_code,
             _ => {
-                unsafe { (*self.internal().0.get()).pin_mut().terminate() };
+                unsafe { (*self.internal().native.get()).pin_mut().terminate() };
+                self.board.log_notice("Sketch terminated".into());
+                self.report_status();
                 0
             }
         };
-        self.board.internal = None;
+        self.board.release();
         exit_code
     }
 
+    // Sends SIGTERM to the sketch so it can finish up, and only terminates it
+    // if it is still running after the grace period.
+    pub fn stop_graceful(self, grace: Duration) -> StopOutcome {
+        let outcome = self.wait_graceful(grace);
+        if outcome == StopOutcome::Terminated && self.exit_status().is_none() {
+            unsafe { (*self.internal().native.get()).pin_mut().terminate() };
+            self.board
+                .log_notice("Sketch terminated after grace period".into());
+            self.report_status();
+        }
+        self.board.release();
+        outcome
+    }
+
+    fn wait_graceful(&self, grace: Duration) -> StopOutcome {
+        if let Err(exit_code) = self.tick() {
+            return StopOutcome::AlreadyExited(exit_code);
+        }
+
+        // A suspended sketch would not get to handle the signal
+        if self.status() == Status::Suspended {
+            self.resume();
+        }
+
+        let signalled = match self.internal().pid.get() {
+            Some(pid) => process::kill(pid, process::SIGTERM).is_ok(),
+            None => false,
+        };
+        if !signalled {
+            return StopOutcome::Terminated;
+        }
+
+        let deadline = Instant::now() + grace;
+        loop {
+            if let Err(exit_code) = self.tick() {
+                let killed = Termination::Signalled(process::SIGTERM);
+                if self.internal().termination.get() == Some(killed) {
+                    return StopOutcome::Terminated;
+                }
+                return StopOutcome::Graceful(exit_code);
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return StopOutcome::Terminated;
+            }
+            thread::sleep((deadline - now).min(Duration::from_millis(10)));
+        }
+    }
+
     // Checks whether the sketch has died, returning the exit code if it has,
     // handle will still be valid, but in unstable state.
     pub fn tick(&self) -> Result<(), ExitCode> {
-        match unsafe { (*self.internal().0.get()).pin_mut().tick() } {
+        let internal = self.internal();
+        // libSMCE only passes on a number that is either the exit code or the signal, so the
+        // wait status is looked at first. The native board is left alone until the sketch
+        // has exited, as it would reap the sketch in between.
+        let peeked = match internal.pid.get() {
+            Some(pid) if internal.exit_status.get().is_none() => process::peek_exit(pid).ok(),
+            _ => None,
+        };
+        let exit_info = match peeked {
+            Some(None) => ExitInfo {
+                exited: false,
+                exit_code: 0,
+            },
+            termination => {
+                if let Some(termination) = termination.flatten() {
+                    internal.termination.set(Some(termination));
+                }
+                unsafe { (*internal.native.get()).pin_mut().tick() }
+            }
+        };
+        match exit_info {
             ExitInfo {
                 exit_code,
                 exited: true,
-            } => Err(exit_code),
-            _ => Ok(()),
+            } => {
+                if internal.exit_status.get().is_none() {
+                    // The abort message tells whether the sketch ran out of memory
+                    self.board.pump_log();
+                    let limit = internal.monitor.borrow().as_ref().and_then(|monitor| {
+                        monitor.exceeded(internal.termination.get(), &self.board.lock_log())
+                    });
+                    let exit_status = match limit {
+                        Some(limit) => ExitStatus::LimitExceeded(limit),
+                        None => ExitStatus::Exited(exit_code),
+                    };
+                    internal.exit_status.set(Some(exit_status));
+                    internal.workers.sketch_exited();
+                    self.board
+                        .log_notice(format!("Sketch exited: {:?}", exit_status));
+                    self.report_status();
+                    internal.emit(BoardEvent::Exited(exit_status));
+                }
+                Err(exit_code)
+            }
+            _ => {
+                self.board.pump_log();
+                self.report_status();
+                if let Some(monitor) = internal.monitor.borrow_mut().as_mut() {
+                    monitor.sample();
+                }
+                Ok(())
+            }
+        }
+    }
+
+    // Why the sketch exited, only available after tick() has reported the exit
+    pub fn exit_status(&self) -> Option<ExitStatus> {
+        self.internal().exit_status.get()
+    }
+
+    // Ticks until the sketch exits
+    pub fn wait(&self) -> ExitStatus {
+        loop {
+            if let Some(exit_status) = self.exited() {
+                return exit_status;
+            }
+            thread::sleep(WAIT_INTERVAL);
         }
     }
+
+    // Takes the handle for itself, as the future could not be Send if others used it meanwhile
+    #[cfg(feature = "tokio")]
+    pub async fn wait_async(&mut self) -> ExitStatus {
+        loop {
+            if let Some(exit_status) = self.exited() {
+                return exit_status;
+            }
+            tokio::time::sleep(WAIT_INTERVAL).await;
+        }
+    }
+
+    fn exited(&self) -> Option<ExitStatus> {
+        let exit_code = self.tick().err()?;
+        Some(self.exit_status().unwrap_or(ExitStatus::Exited(exit_code)))
+    }
 }
 
 #[derive(Clone, Copy, Error, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
@@ -212,6 +847,12 @@ pub enum BoardError {
     SketchNotCompiled,
     #[error("Board is already running a sketch")]
     AlreadyRunning,
+    #[error("Resource limits are not supported on this platform")]
+    LimitsUnsupported,
+    #[error("Sandboxing is not supported on this platform")]
+    SandboxUnsupported,
+    #[error("Failed to start the sketch")]
+    StartFailed,
 }
 
 pub struct BoardLogReader<'a> {
@@ -220,6 +861,7 @@ pub struct BoardLogReader<'a> {
 
 impl Read for BoardLogReader<'_> {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        Ok(unsafe { (*self.handle.internal().0.get()).pin_mut().runtime_log(buf) })
+        self.handle.board.pump_log();
+        Ok(self.handle.board.lock_log().read_raw(buf))
     }
 }
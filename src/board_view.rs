@@ -20,9 +20,14 @@ use std::collections::hash_map::Iter as MapIter;
 use std::collections::HashMap;
 use std::io;
 use std::io::{Read, Write};
+use std::marker::PhantomData;
 use std::ops::Index;
 use std::pin::Pin;
 use std::slice::Iter as VecIter;
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 use std::{cell::UnsafeCell, fmt};
 
 use cxx::UniquePtr;
@@ -31,11 +36,14 @@ use crate::board_config::{
     FrameBuffer as FrameBufferInfo, GpioDriver as GpioDriverInfo, UartChannel as UartChannelInfo,
 };
 use crate::ffi::{OpaqueFramebuffer, OpaqueVirtualPin, OpaqueVirtualUart};
+use crate::worker::{Liveness, Workers};
 
 pub struct BoardView {
     pub pins: Pins,
     pub uart_channels: UartChannels,
     pub frame_buffers: FrameBuffers,
+    // Background threads of the board, for peripherals that watch pins between steps
+    pub(crate) workers: Workers,
 }
 
 pub struct Pins {
@@ -166,9 +174,22 @@ impl<'a> Iterator for FrameBuffersIterator<'a> {
     }
 }
 
+// Value the host drove a pin to, handed to recorders
+#[derive(Debug, Copy, Clone, Eq, Hash, PartialEq)]
+pub(crate) enum HostWrite {
+    Digital(bool),
+    Analog(u16),
+}
+
+pub(crate) type WriteTap = Sender<(Instant, usize, HostWrite)>;
+
 pub struct GpioPin {
     pub(crate) inner: UnsafeCell<UniquePtr<OpaqueVirtualPin>>,
     pub(crate) info: GpioDriverInfo,
+    // Shared between duplicates of the pin
+    pub(crate) taps: Arc<Mutex<Vec<WriteTap>>>,
+    // Those of the board, for duplicates that end up in futures
+    pub(crate) workers: Workers,
 }
 
 impl GpioPin {
@@ -182,6 +203,7 @@ impl GpioPin {
 
     pub fn analog_write(&self, val: u16) {
         unsafe { (*self.inner.get()).pin_mut().analog_write(val) }
+        self.tap(HostWrite::Analog(val));
     }
 
     pub fn digital_read(&self) -> bool {
@@ -190,6 +212,25 @@ impl GpioPin {
 
     pub fn digital_write(&self, val: bool) {
         unsafe { (*self.inner.get()).pin_mut().digital_write(val) }
+        self.tap(HostWrite::Digital(val));
+    }
+
+    fn tap(&self, write: HostWrite) {
+        let mut taps = self.taps.lock().unwrap();
+        if !taps.is_empty() {
+            let write = (Instant::now(), self.info.pin_id as usize, write);
+            taps.retain(|tap| tap.send(write).is_ok());
+        }
+    }
+
+    // Second handle to the same pin, for use on another thread
+    pub(crate) fn duplicate(&self) -> GpioPin {
+        GpioPin {
+            inner: UnsafeCell::new(unsafe { (*self.inner.get()).pin_mut().clone() }),
+            info: self.info.clone(),
+            taps: self.taps.clone(),
+            workers: self.workers.clone(),
+        }
     }
 }
 
@@ -202,40 +243,271 @@ impl fmt::Debug for GpioPin {
 pub struct UartChannel {
     pub(crate) inner: UnsafeCell<UniquePtr<OpaqueVirtualUart>>,
     pub(crate) info: UartChannelInfo,
+    // Those of the board, for bridges that keep running in the background
+    pub(crate) workers: Workers,
 }
 
-// TODO: consider a split tx and rx read / writer
+// How often blocking writes check on the sketch
+const UART_POLL_INTERVAL: Duration = Duration::from_millis(1);
+
 impl UartChannel {
     // Returns original BoardConfig::UartChannel
     pub fn info(&self) -> &UartChannelInfo {
         &self.info
     }
+
+    // Every use of the native handle ends within the call that borrowed it
+    #[allow(clippy::mut_from_ref)]
+    fn inner(&self) -> Pin<&mut OpaqueVirtualUart> {
+        unsafe { (*self.inner.get()).pin_mut() }
+    }
+
+    // Bytes written by the sketch, ready to be read
+    pub fn readable(&self) -> usize {
+        uart_readable(self.inner())
+    }
+
+    pub fn max_read(&self) -> usize {
+        uart_max_read(self.inner())
+    }
+
+    pub fn max_write(&self) -> usize {
+        uart_max_write(self.inner())
+    }
+
+    // Bytes written to the sketch it has not read yet
+    pub fn pending(&self) -> usize {
+        uart_pending(self.inner())
+    }
+
+    // Next byte a read would return, without consuming it
+    pub fn peek(&self) -> Option<u8> {
+        uart_peek(self.inner())
+    }
+
+    // Writes all of buf, waiting for the sketch to make room when the buffer is full
+    pub fn write_all_timeout(&self, buf: &[u8], timeout: Duration) -> io::Result<()> {
+        uart_write_all_timeout(self.inner(), buf, timeout)
+    }
+
+    // Waits for the sketch to read everything written to it, or for it to exit
+    pub fn flush_timeout(&self, timeout: Duration) -> io::Result<()> {
+        uart_flush_timeout(self.inner(), &self.workers.liveness(), Some(timeout))
+    }
+
+    // Independent reading and writing halves, each of which can be moved to its own thread
+    pub fn split(&self) -> (UartRx<'_>, UartTx<'_>) {
+        (
+            UartRx {
+                inner: unsafe { self.inner().clone() },
+                _channel: PhantomData,
+            },
+            UartTx {
+                inner: unsafe { self.inner().clone() },
+                liveness: self.workers.liveness(),
+                _channel: PhantomData,
+            },
+        )
+    }
+
+    // Second handle to the same channel, for use on another thread
+    pub(crate) fn duplicate(&self) -> UartChannel {
+        UartChannel {
+            inner: UnsafeCell::new(unsafe { self.inner().clone() }),
+            info: self.info.clone(),
+            workers: self.workers.clone(),
+        }
+    }
+}
+
+fn uart_readable(mut uart: Pin<&mut OpaqueVirtualUart>) -> usize {
+    unsafe { uart.as_mut().readable() }
+}
+
+fn uart_max_read(mut uart: Pin<&mut OpaqueVirtualUart>) -> usize {
+    unsafe { uart.as_mut().max_read() }
+}
+
+fn uart_max_write(mut uart: Pin<&mut OpaqueVirtualUart>) -> usize {
+    unsafe { uart.as_mut().max_write() }
+}
+
+fn uart_pending(mut uart: Pin<&mut OpaqueVirtualUart>) -> usize {
+    unsafe { uart.as_mut().pending() }
+}
+
+fn uart_peek(mut uart: Pin<&mut OpaqueVirtualUart>) -> Option<u8> {
+    if uart_readable(uart.as_mut()) > 0 {
+        Some(unsafe { uart.as_mut().front() })
+    } else {
+        None
+    }
+}
+
+fn uart_read(mut uart: Pin<&mut OpaqueVirtualUart>, buf: &mut [u8]) -> io::Result<usize> {
+    Ok(unsafe { uart.as_mut().read(buf) })
+}
+
+fn uart_write(mut uart: Pin<&mut OpaqueVirtualUart>, buf: &[u8]) -> io::Result<usize> {
+    let written = unsafe { uart.as_mut().write(buf) };
+    if written > 0 {
+        Ok(written)
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::WriteZero,
+            "Uart buffer is full, increase the max buffer size or try again",
+        ))
+    }
+}
+
+fn uart_timed_out(left: usize) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::TimedOut,
+        format!("Timed out with {left} bytes left, the sketch is not reading the uart"),
+    )
+}
+
+fn uart_write_all_timeout(
+    mut uart: Pin<&mut OpaqueVirtualUart>,
+    mut buf: &[u8],
+    timeout: Duration,
+) -> io::Result<()> {
+    let deadline = Instant::now() + timeout;
+    while !buf.is_empty() {
+        let written = unsafe { uart.as_mut().write(buf) };
+        buf = &buf[written..];
+        if written == 0 {
+            if Instant::now() >= deadline {
+                return Err(uart_timed_out(buf.len()));
+            }
+            thread::sleep(UART_POLL_INTERVAL);
+        }
+    }
+    Ok(())
+}
+
+pub(crate) fn uart_exited(left: usize) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::BrokenPipe,
+        format!("The sketch exited with {left} bytes left unread"),
+    )
+}
+
+// Without a timeout, waits for as long as the sketch takes. Gives up once the board has
+// noticed the sketch exited, which takes a tick or a status check on the board's handle.
+fn uart_flush_timeout(
+    mut uart: Pin<&mut OpaqueVirtualUart>,
+    liveness: &Liveness,
+    timeout: Option<Duration>,
+) -> io::Result<()> {
+    let start = Instant::now();
+    while uart_pending(uart.as_mut()) > 0 {
+        if !liveness.alive() {
+            return Err(uart_exited(uart_pending(uart)));
+        }
+        if matches!(timeout, Some(timeout) if start.elapsed() >= timeout) {
+            return Err(uart_timed_out(uart_pending(uart)));
+        }
+        thread::sleep(UART_POLL_INTERVAL);
+    }
+    Ok(())
 }
 
 impl Read for &UartChannel {
     // Will never fail, expect 0 size reads.
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        Ok(unsafe { (*self.inner.get()).pin_mut().read(buf) })
+        uart_read(self.inner(), buf)
     }
 }
 
 impl Write for &UartChannel {
     // Will fail with an WriteZero error if the buffer is full.
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        let written = unsafe { (*self.inner.get()).pin_mut().write(buf) };
-        if written > 0 {
-            Ok(written)
-        } else {
-            Err(io::Error::new(
-                io::ErrorKind::WriteZero,
-                "Uart buffer is full, increase the max buffer size or try again",
-            ))
-        }
+        uart_write(self.inner(), buf)
     }
 
-    // TODO: decide if this will block for arduino land to read all the bytes
+    /// Blocks until the sketch has read everything.
+    ///
+    /// Fails once the board has noticed the sketch exited, but only a tick or status check on
+    /// its handle notices that. Nothing does while this thread is stuck here, so without another
+    /// thread ticking the board this waits forever on a sketch that stopped reading or exited.
+    /// Use [`UartChannel::flush_timeout`] instead when that can happen.
     fn flush(&mut self) -> io::Result<()> {
-        Ok(())
+        uart_flush_timeout(self.inner(), &self.workers.liveness(), None)
+    }
+}
+
+/// Receiving half of a [`UartChannel`], reads what the sketch wrote.
+pub struct UartRx<'a> {
+    inner: UniquePtr<OpaqueVirtualUart>,
+    // Can not outlive the board, but unlike the channel may be sent to another thread
+    _channel: PhantomData<&'a ()>,
+}
+
+impl UartRx<'_> {
+    pub fn readable(&mut self) -> usize {
+        uart_readable(self.inner.pin_mut())
+    }
+
+    pub fn max_read(&mut self) -> usize {
+        uart_max_read(self.inner.pin_mut())
+    }
+
+    pub fn peek(&mut self) -> Option<u8> {
+        uart_peek(self.inner.pin_mut())
+    }
+}
+
+impl Read for UartRx<'_> {
+    // Will never fail, expect 0 size reads.
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        uart_read(self.inner.pin_mut(), buf)
+    }
+}
+
+/// Sending half of a [`UartChannel`], writes what the sketch reads.
+pub struct UartTx<'a> {
+    inner: UniquePtr<OpaqueVirtualUart>,
+    liveness: Liveness,
+    _channel: PhantomData<&'a ()>,
+}
+
+impl UartTx<'_> {
+    // False once the board noticed the sketch exited, or is going away
+    #[cfg(feature = "tokio")]
+    pub(crate) fn alive(&self) -> bool {
+        self.liveness.alive()
+    }
+
+    pub fn max_write(&mut self) -> usize {
+        uart_max_write(self.inner.pin_mut())
+    }
+
+    pub fn pending(&mut self) -> usize {
+        uart_pending(self.inner.pin_mut())
+    }
+
+    pub fn write_all_timeout(&mut self, buf: &[u8], timeout: Duration) -> io::Result<()> {
+        uart_write_all_timeout(self.inner.pin_mut(), buf, timeout)
+    }
+
+    pub fn flush_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+        uart_flush_timeout(self.inner.pin_mut(), &self.liveness, Some(timeout))
+    }
+}
+
+impl Write for UartTx<'_> {
+    // Will fail with an WriteZero error if the buffer is full.
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        uart_write(self.inner.pin_mut(), buf)
+    }
+
+    /// Blocks until the sketch has read everything, or the board noticed it exited.
+    ///
+    /// As with [`UartChannel`]'s flush, something has to tick the board for the exit to be
+    /// noticed, use [`UartTx::flush_timeout`] when nothing does.
+    fn flush(&mut self) -> io::Result<()> {
+        uart_flush_timeout(self.inner.pin_mut(), &self.liveness, None)
     }
 }
 
@@ -257,6 +529,7 @@ pub struct FrameBuffer {
 }
 
 impl FrameBuffer {
+    #[allow(clippy::mut_from_ref)]
     fn inner(&self) -> Pin<&mut OpaqueFramebuffer> {
         unsafe { (*self.inner.get()).pin_mut() }
     }
@@ -295,4 +568,17 @@ impl FrameBuffer {
             FrameBufferFormat::Rgb444 => unsafe { self.inner().write_rgb444(buf) },
         }
     }
+
+    // Copies the current frame into buf as RGB888, see expected_buf_size()
+    pub fn read(&self, buf: &mut [u8]) -> bool {
+        unsafe { self.inner().read_rgb888(buf) }
+    }
+
+    // Second handle to the same frame buffer, for use on another thread
+    pub(crate) fn duplicate(&self) -> FrameBuffer {
+        FrameBuffer {
+            inner: UnsafeCell::new(unsafe { self.inner().clone() }),
+            info: self.info.clone(),
+        }
+    }
 }
@@ -0,0 +1,843 @@
+/*
+ *  bridge.rs
+ *  Copyright 2021 ItJustWorksTM
+ *
+ *  Licensed under the Apache License, Version 2.0 (the "License");
+ *  you may not use this file except in compliance with the License.
+ *  You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ *  Unless required by applicable law or agreed to in writing, software
+ *  distributed under the License is distributed on an "AS IS" BASIS,
+ *  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ *  See the License for the specific language governing permissions and
+ *  limitations under the License.
+ *
+ */
+
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use crate::board_view::UartChannel;
+
+// How long bridges sleep when neither side had anything to say
+const IDLE_INTERVAL: Duration = Duration::from_millis(1);
+
+#[derive(Debug, Copy, Clone, Eq, Hash, PartialEq)]
+pub(crate) enum Flow {
+    Busy,
+    Idle,
+    // The endpoint has gone away
+    Closed,
+}
+
+// Moves bytes both ways between a uart and a non-blocking endpoint, holding on to whatever
+// one side could not take yet.
+#[derive(Debug, Default)]
+pub(crate) struct Pump {
+    to_sketch: Vec<u8>,
+    to_host: Vec<u8>,
+}
+
+fn would_block(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted
+    )
+}
+
+impl Pump {
+    pub(crate) fn run(
+        &mut self,
+        mut uart: &UartChannel,
+        endpoint: &mut (impl Read + Write),
+    ) -> io::Result<Flow> {
+        let mut busy = false;
+        let mut chunk = [0; 256];
+
+        if self.to_sketch.is_empty() {
+            match endpoint.read(&mut chunk) {
+                Ok(0) => return Ok(Flow::Closed),
+                Ok(read) => self.to_sketch.extend_from_slice(&chunk[..read]),
+                Err(err) if would_block(&err) => {}
+                Err(err) => return Err(err),
+            }
+        }
+        if !self.to_sketch.is_empty() {
+            // Fails when the sketch has not made room yet
+            if let Ok(written) = uart.write(&self.to_sketch) {
+                self.to_sketch.drain(..written);
+                busy = true;
+            }
+        }
+
+        if self.to_host.is_empty() {
+            let read = uart.read(&mut chunk)?;
+            self.to_host.extend_from_slice(&chunk[..read]);
+        }
+        if !self.to_host.is_empty() {
+            match endpoint.write(&self.to_host) {
+                Ok(0) => return Ok(Flow::Closed),
+                Ok(written) => {
+                    self.to_host.drain(..written);
+                    busy = true;
+                }
+                Err(err) if would_block(&err) => {}
+                Err(err) => return Err(err),
+            }
+        }
+
+        Ok(if busy { Flow::Busy } else { Flow::Idle })
+    }
+}
+
+/// Pseudo-terminal connected to a uart, its slave side can be opened like a serial port.
+///
+/// Runs until stopped or dropped, the sketch exits or the board is stopped. Dropping it waits
+/// for the pump to end and removes the link just like [`PtyBridge::stop`], without the error.
+pub struct PtyBridge {
+    path: PathBuf,
+    link: Option<PathBuf>,
+    stop: Arc<AtomicBool>,
+    done: Receiver<io::Result<()>>,
+}
+
+impl PtyBridge {
+    // Slave side of the terminal, like `/dev/pts/3`
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn link(&self) -> Option<&Path> {
+        self.link.as_deref()
+    }
+
+    // Stops pumping and removes the link, returns the error that ended the bridge if any
+    pub fn stop(self) -> io::Result<()> {
+        self.finish()
+    }
+
+    fn finish(&self) -> io::Result<()> {
+        self.stop.store(true, Ordering::SeqCst);
+        // Fails right away once the pump has already reported back
+        self.done.recv().unwrap_or(Ok(()))
+    }
+}
+
+impl Drop for PtyBridge {
+    fn drop(&mut self) {
+        let _ = self.finish();
+    }
+}
+
+impl UartChannel {
+    // Opens a pseudo-terminal and pumps bytes between it and the channel on a background thread.
+    // `link` gets a symlink to the slave side, replacing an existing symlink there.
+    pub fn bridge_pty(&self, link: Option<&Path>) -> io::Result<PtyBridge> {
+        let mut pty = pty::open()?;
+        if let Some(link) = link {
+            if matches!(link.symlink_metadata(), Ok(meta) if meta.file_type().is_symlink()) {
+                std::fs::remove_file(link)?;
+            }
+            pty::symlink(&pty.path, link)?;
+        }
+
+        let path = pty.path.clone();
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopped = stop.clone();
+        let (done_tx, done) = mpsc::channel();
+        let uart = self.duplicate();
+        let owned_link = link.map(Path::to_path_buf);
+        self.workers.spawn(move |liveness| {
+            let mut pump = Pump::default();
+            let mut result = Ok(());
+            while liveness.alive() && !stopped.load(Ordering::SeqCst) {
+                match pump.run(&uart, &mut pty.master) {
+                    Ok(Flow::Busy) => {}
+                    // The slave is kept open, so the terminal never closes on its own
+                    Ok(_) => thread::sleep(IDLE_INTERVAL),
+                    Err(err) => {
+                        result = Err(err);
+                        break;
+                    }
+                }
+            }
+            if let Some(link) = owned_link {
+                let _ = std::fs::remove_file(link);
+            }
+            let _ = done_tx.send(result);
+        });
+
+        Ok(PtyBridge {
+            path,
+            link: link.map(Path::to_path_buf),
+            stop,
+            done,
+        })
+    }
+}
+
+/// TCP server forwarding one client connection at a time to and from a uart.
+///
+/// Runs until stopped or dropped, the sketch exits or the board is stopped. Dropping it waits
+/// for the server to end and frees the port just like [`TcpBridge::stop`], without the error.
+pub struct TcpBridge {
+    local_addr: SocketAddr,
+    connected: Arc<AtomicBool>,
+    baud_rate: Arc<AtomicU32>,
+    stop: Arc<AtomicBool>,
+    done: Receiver<io::Result<()>>,
+}
+
+impl TcpBridge {
+    // Actual address listened on, useful when binding to port 0
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.connected.load(Ordering::SeqCst)
+    }
+
+    // Last baud rate a telnet client asked for
+    pub fn baud_rate(&self) -> Option<u32> {
+        match self.baud_rate.load(Ordering::SeqCst) {
+            0 => None,
+            baud_rate => Some(baud_rate),
+        }
+    }
+
+    // Stops serving and disconnects the client, returns the error that ended the bridge if any
+    pub fn stop(self) -> io::Result<()> {
+        self.finish()
+    }
+
+    fn finish(&self) -> io::Result<()> {
+        self.stop.store(true, Ordering::SeqCst);
+        // Fails right away once the server has already reported back
+        self.done.recv().unwrap_or(Ok(()))
+    }
+}
+
+impl Drop for TcpBridge {
+    fn drop(&mut self) {
+        let _ = self.finish();
+    }
+}
+
+impl UartChannel {
+    // Serves the channel as a raw byte stream, like ser2net's raw mode
+    pub fn serve_tcp(&self, addr: impl ToSocketAddrs) -> io::Result<TcpBridge> {
+        self.serve(addr, false)
+    }
+
+    // Serves the channel over telnet with RFC 2217 com port control, clients may ask for
+    // baud rate changes which are reported through the bridge but do not affect the sketch
+    pub fn serve_telnet(&self, addr: impl ToSocketAddrs) -> io::Result<TcpBridge> {
+        self.serve(addr, true)
+    }
+
+    fn serve(&self, addr: impl ToSocketAddrs, telnet: bool) -> io::Result<TcpBridge> {
+        let listener = TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        let local_addr = listener.local_addr()?;
+
+        let connected = Arc::new(AtomicBool::new(false));
+        let baud_rate = Arc::new(AtomicU32::new(0));
+        let stop = Arc::new(AtomicBool::new(false));
+        let (done_tx, done) = mpsc::channel();
+
+        let uart = self.duplicate();
+        let default_baud = self.info.baud_rate as u32;
+        let (client_connected, requested_baud, stopped) =
+            (connected.clone(), baud_rate.clone(), stop.clone());
+        self.workers.spawn(move |liveness| {
+            let running = || liveness.alive() && !stopped.load(Ordering::SeqCst);
+            let mut client = None;
+            let mut pump = Pump::default();
+            let mut result = Ok(());
+
+            while running() {
+                let flow = match &mut client {
+                    Some(client) => pump.run(&uart, client),
+                    None => Ok(Flow::Closed),
+                };
+                match flow {
+                    Ok(Flow::Busy) => continue,
+                    Ok(Flow::Idle) => {}
+                    // Errors on a connection only end that connection
+                    Ok(Flow::Closed) | Err(_) => {
+                        client = None;
+                        client_connected.store(false, Ordering::SeqCst);
+                    }
+                }
+
+                match listener.accept() {
+                    // Only one client at a time, anyone else is turned away
+                    Ok((stream, _)) if client.is_some() => {
+                        let _ = stream.shutdown(Shutdown::Both);
+                    }
+                    Ok((stream, _)) => {
+                        if let Err(err) = stream.set_nonblocking(true) {
+                            result = Err(err);
+                            break;
+                        }
+                        let _ = stream.set_nodelay(true);
+                        let telnet =
+                            telnet.then(|| Telnet::new(default_baud, requested_baud.clone()));
+                        client = Some(Client { stream, telnet });
+                        pump = Pump::default();
+                        client_connected.store(true, Ordering::SeqCst);
+                    }
+                    Err(err) if would_block(&err) => thread::sleep(IDLE_INTERVAL),
+                    Err(err) => {
+                        result = Err(err);
+                        break;
+                    }
+                }
+            }
+            if let Some(client) = client {
+                let _ = client.stream.shutdown(Shutdown::Both);
+            }
+            // The port is free again by the time the bridge hears back
+            drop(listener);
+            let _ = done_tx.send(result);
+        });
+
+        Ok(TcpBridge {
+            local_addr,
+            connected,
+            baud_rate,
+            stop,
+            done,
+        })
+    }
+}
+
+// Connection of a tcp bridge, optionally speaking telnet
+struct Client<S> {
+    stream: S,
+    telnet: Option<Telnet>,
+}
+
+impl<S: Read + Write> Read for Client<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match &mut self.telnet {
+            Some(telnet) => telnet.read(&mut self.stream, buf),
+            None => self.stream.read(buf),
+        }
+    }
+}
+
+impl<S: Read + Write> Write for Client<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match &mut self.telnet {
+            Some(telnet) => telnet.write(&mut self.stream, buf),
+            None => self.stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+const IAC: u8 = 255;
+const DONT: u8 = 254;
+const DO: u8 = 253;
+const WONT: u8 = 252;
+const WILL: u8 = 251;
+const SB: u8 = 250;
+const SE: u8 = 240;
+const BINARY: u8 = 0;
+const SUPPRESS_GO_AHEAD: u8 = 3;
+const COM_PORT_OPTION: u8 = 44;
+const SET_BAUDRATE: u8 = 1;
+// Added to a com port command in the server's replies
+const SERVER_REPLY: u8 = 100;
+
+// Doubles IAC so it is not taken for a command
+fn escape(out: &mut Vec<u8>, bytes: &[u8]) {
+    for &byte in bytes {
+        out.push(byte);
+        if byte == IAC {
+            out.push(IAC);
+        }
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+enum TelnetState {
+    Data,
+    Command,
+    // WILL, WONT, DO or DONT, waiting for the option it is about
+    Negotiation(u8),
+    Subnegotiation(Vec<u8>),
+    SubnegotiationCommand(Vec<u8>),
+}
+
+// Telnet server side, negotiates a binary session and accepts com port control from clients
+// that offer it, as RFC 2217 has the client announce it
+#[derive(Debug)]
+struct Telnet {
+    state: TelnetState,
+    // Not yet sent, on top of the data the caller knows about
+    outgoing: Vec<u8>,
+    // Whether the client's offer of com port control was taken
+    com_port: bool,
+    default_baud: u32,
+    baud_rate: Arc<AtomicU32>,
+    // Reused for reading from the stream
+    scratch: Vec<u8>,
+}
+
+impl Telnet {
+    fn new(default_baud: u32, baud_rate: Arc<AtomicU32>) -> Self {
+        let mut outgoing = vec![];
+        for option in [BINARY, SUPPRESS_GO_AHEAD] {
+            outgoing.extend_from_slice(&[IAC, WILL, option, IAC, DO, option]);
+        }
+        Telnet {
+            state: TelnetState::Data,
+            outgoing,
+            com_port: false,
+            default_baud,
+            baud_rate,
+            scratch: vec![],
+        }
+    }
+
+    // Sends as much of what is queued as the stream takes, false if some is left
+    fn send(&mut self, stream: &mut impl Write) -> io::Result<bool> {
+        while !self.outgoing.is_empty() {
+            match stream.write(&self.outgoing) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(written) => {
+                    self.outgoing.drain(..written);
+                }
+                Err(err) if would_block(&err) => return Ok(false),
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(true)
+    }
+
+    // Only takes data once everything queued before it went out, what the stream does not take
+    // right away is queued and holds back the next write
+    fn write(&mut self, stream: &mut impl Write, buf: &[u8]) -> io::Result<usize> {
+        if !self.send(stream)? {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        escape(&mut self.outgoing, buf);
+        let _ = self.send(stream);
+        Ok(buf.len())
+    }
+
+    fn read(&mut self, stream: &mut (impl Read + Write), buf: &mut [u8]) -> io::Result<usize> {
+        self.send(stream)?;
+        // Taken out while the bytes go through the protocol, which needs all of self
+        let mut raw = std::mem::take(&mut self.scratch);
+        if raw.len() < buf.len() {
+            raw.resize(buf.len(), 0);
+        }
+        let received = stream
+            .read(&mut raw[..buf.len()])
+            .map(|read| (read, self.decode(&raw[..read], buf)));
+        self.scratch = raw;
+        let (read, data) = received?;
+        if read == 0 {
+            return Ok(0);
+        }
+        self.send(stream)?;
+        // Only commands came in, which must not look like the end of the stream
+        if data == 0 {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        Ok(data)
+    }
+
+    // Puts the data among the received bytes into buf, returns how much there was
+    fn decode(&mut self, raw: &[u8], buf: &mut [u8]) -> usize {
+        let mut data = 0;
+        for &byte in raw {
+            if let Some(byte) = self.receive(byte) {
+                buf[data] = byte;
+                data += 1;
+            }
+        }
+        data
+    }
+
+    // Feeds one received byte through the protocol, returns it if it is data
+    fn receive(&mut self, byte: u8) -> Option<u8> {
+        let state = std::mem::replace(&mut self.state, TelnetState::Data);
+        self.state = match (state, byte) {
+            (TelnetState::Data, IAC) => TelnetState::Command,
+            (TelnetState::Data, _) => return Some(byte),
+            (TelnetState::Command, IAC) => return Some(IAC),
+            (TelnetState::Command, SB) => TelnetState::Subnegotiation(vec![]),
+            (TelnetState::Command, WILL..=DONT) => TelnetState::Negotiation(byte),
+            (TelnetState::Command, _) => TelnetState::Data,
+            (TelnetState::Negotiation(command), _) => {
+                self.negotiate(command, byte);
+                TelnetState::Data
+            }
+            (TelnetState::Subnegotiation(sub), IAC) => TelnetState::SubnegotiationCommand(sub),
+            (TelnetState::Subnegotiation(mut sub), _) => {
+                sub.push(byte);
+                TelnetState::Subnegotiation(sub)
+            }
+            (TelnetState::SubnegotiationCommand(mut sub), IAC) => {
+                sub.push(IAC);
+                TelnetState::Subnegotiation(sub)
+            }
+            (TelnetState::SubnegotiationCommand(sub), SE) => {
+                self.subnegotiation(&sub);
+                TelnetState::Data
+            }
+            (TelnetState::SubnegotiationCommand(_), _) => TelnetState::Data,
+        };
+        None
+    }
+
+    // Replies only when an option would change, per RFC 854, so negotiations can not loop.
+    // Binary and suppress go ahead were asked for both ways up front, so the client agreeing
+    // needs no reply, and refusing leaves them off.
+    fn negotiate(&mut self, command: u8, option: u8) {
+        let reply = match (command, option) {
+            (WILL, COM_PORT_OPTION) if !self.com_port => {
+                self.com_port = true;
+                DO
+            }
+            (WONT, COM_PORT_OPTION) if self.com_port => {
+                self.com_port = false;
+                DONT
+            }
+            (WILL, BINARY | SUPPRESS_GO_AHEAD) | (DO, BINARY | SUPPRESS_GO_AHEAD) => return,
+            (WILL, COM_PORT_OPTION) => return,
+            (WILL, _) => DONT,
+            (DO, _) => WONT,
+            _ => return,
+        };
+        self.outgoing.extend_from_slice(&[IAC, reply, option]);
+    }
+
+    // Answers com port commands by confirming them, only the baud rate is kept track of
+    fn subnegotiation(&mut self, sub: &[u8]) {
+        let (command, payload) = match sub {
+            [COM_PORT_OPTION, command, payload @ ..] if self.com_port => (*command, payload),
+            _ => return,
+        };
+        let mut reply = payload.to_vec();
+        if let (SET_BAUDRATE, &[a, b, c, d]) = (command, payload) {
+            let requested = u32::from_be_bytes([a, b, c, d]);
+            // Zero only asks for the current rate
+            if requested != 0 {
+                self.baud_rate.store(requested, Ordering::SeqCst);
+            }
+            let current = match self.baud_rate.load(Ordering::SeqCst) {
+                0 => self.default_baud,
+                baud_rate => baud_rate,
+            };
+            reply = current.to_be_bytes().to_vec();
+        }
+
+        self.outgoing
+            .extend_from_slice(&[IAC, SB, COM_PORT_OPTION, command + SERVER_REPLY]);
+        escape(&mut self.outgoing, &reply);
+        self.outgoing.extend_from_slice(&[IAC, SE]);
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod pty {
+    use std::ffi::{CStr, OsStr};
+    use std::fs::{File, OpenOptions};
+    use std::io;
+    use std::mem::MaybeUninit;
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::fs::OpenOptionsExt;
+    use std::os::unix::io::{AsRawFd, FromRawFd};
+    use std::path::{Path, PathBuf};
+
+    pub(super) struct Pty {
+        pub(super) master: File,
+        // Held open so reading the master does not fail while no one else has the slave open
+        _slave: File,
+        pub(super) path: PathBuf,
+    }
+
+    fn check(ret: libc::c_int) -> io::Result<()> {
+        if ret == 0 {
+            Ok(())
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+
+    pub(super) fn open() -> io::Result<Pty> {
+        let fd = unsafe { libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY | libc::O_NONBLOCK) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let master = unsafe { File::from_raw_fd(fd) };
+        check(unsafe { libc::grantpt(fd) })?;
+        check(unsafe { libc::unlockpt(fd) })?;
+
+        let mut name = [0 as libc::c_char; 128];
+        check(unsafe { libc::ptsname_r(fd, name.as_mut_ptr(), name.len()) })?;
+        let name = unsafe { CStr::from_ptr(name.as_ptr()) };
+        let path = PathBuf::from(OsStr::from_bytes(name.to_bytes()));
+
+        let slave = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NOCTTY)
+            .open(&path)?;
+        // Bytes pass through untouched, serial tools set up their own mode anyway
+        unsafe {
+            let mut termios = MaybeUninit::<libc::termios>::uninit();
+            check(libc::tcgetattr(slave.as_raw_fd(), termios.as_mut_ptr()))?;
+            let mut termios = termios.assume_init();
+            libc::cfmakeraw(&mut termios);
+            check(libc::tcsetattr(slave.as_raw_fd(), libc::TCSANOW, &termios))?;
+        }
+
+        Ok(Pty {
+            master,
+            _slave: slave,
+            path,
+        })
+    }
+
+    pub(super) fn symlink(original: &Path, link: &Path) -> io::Result<()> {
+        std::os::unix::fs::symlink(original, link)
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod pty {
+    use std::fs::File;
+    use std::io;
+    use std::path::{Path, PathBuf};
+
+    pub(super) struct Pty {
+        pub(super) master: File,
+        pub(super) path: PathBuf,
+    }
+
+    pub(super) fn open() -> io::Result<Pty> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "Pseudo-terminal bridges are only supported on Linux",
+        ))
+    }
+
+    pub(super) fn symlink(_original: &Path, _link: &Path) -> io::Result<()> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #[cfg(target_os = "linux")]
+    use std::fs::OpenOptions;
+    use std::io::{self, Read, Write};
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::Arc;
+    #[cfg(target_os = "linux")]
+    use std::thread;
+    #[cfg(target_os = "linux")]
+    use std::time::Duration;
+
+    #[cfg(target_os = "linux")]
+    use crate::bridge::pty;
+    use crate::bridge::{
+        Client, Telnet, BINARY, COM_PORT_OPTION, DO, DONT, IAC, SB, SE, WILL, WONT,
+    };
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn pty_round_trip() {
+        let mut pty = pty::open().unwrap();
+        assert!(pty.path.starts_with("/dev/pts"));
+        let mut slave = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&pty.path)
+            .unwrap();
+
+        slave.write_all(b"raw\n").unwrap();
+        let mut buf = [0; 16];
+        let mut read = 0;
+        for _ in 0..1000 {
+            read += pty.master.read(&mut buf[read..]).unwrap_or(0);
+            if read >= 4 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        // No line discipline translating the newline
+        assert_eq!(&buf[..read], b"raw\n");
+
+        pty.master.write_all(b"back").unwrap();
+        let mut buf = [0; 4];
+        slave.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"back");
+    }
+
+    // Hands out queued input once, collects everything written up to `capacity`
+    #[derive(Default)]
+    struct Stream {
+        input: Vec<u8>,
+        output: Vec<u8>,
+        capacity: Option<usize>,
+    }
+
+    fn telnet_client() -> (Client<Stream>, Arc<AtomicU32>) {
+        let baud_rate = Arc::new(AtomicU32::new(0));
+        let mut client = Client {
+            stream: Stream::default(),
+            telnet: Some(Telnet::new(9600, baud_rate.clone())),
+        };
+        // Past the server's opening negotiation
+        assert_eq!(client.write(&[]).unwrap(), 0);
+        client.stream.output.clear();
+        (client, baud_rate)
+    }
+
+    impl Read for Stream {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.input.is_empty() {
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+            let read = buf.len().min(self.input.len());
+            buf[..read].copy_from_slice(&self.input[..read]);
+            self.input.drain(..read);
+            Ok(read)
+        }
+    }
+
+    impl Write for Stream {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let room = match self.capacity {
+                Some(capacity) => capacity.saturating_sub(self.output.len()),
+                None => buf.len(),
+            };
+            if room == 0 {
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+            let written = room.min(buf.len());
+            self.output.extend_from_slice(&buf[..written]);
+            Ok(written)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn telnet_data_and_baud_rate() {
+        let (mut client, baud_rate) = telnet_client();
+
+        // Escaped data around a negotiation answer, the offer of com port control and a baud
+        // rate query
+        client.stream.input = vec![b'a', IAC, IAC, IAC, DO, BINARY, b'b'];
+        client
+            .stream
+            .input
+            .extend_from_slice(&[IAC, WILL, COM_PORT_OPTION]);
+        client
+            .stream
+            .input
+            .extend_from_slice(&[IAC, SB, COM_PORT_OPTION, 1, 0, 0, 0, 0, IAC, SE]);
+        let mut buf = [0; 32];
+        let read = client.read(&mut buf).unwrap();
+        assert_eq!(&buf[..read], &[b'a', IAC, b'b']);
+        let mut reply = vec![IAC, DO, COM_PORT_OPTION];
+        reply.extend_from_slice(&[IAC, SB, COM_PORT_OPTION, 101, 0, 0, 0x25, 0x80, IAC, SE]);
+        assert_eq!(client.stream.output, reply);
+        assert_eq!(baud_rate.load(Ordering::SeqCst), 0);
+
+        // 115200 baud
+        client.stream.output.clear();
+        client.stream.input = vec![IAC, SB, COM_PORT_OPTION, 1, 0, 1, 0xc2, 0, IAC, SE];
+        assert_eq!(
+            client.read(&mut buf).unwrap_err().kind(),
+            io::ErrorKind::WouldBlock
+        );
+        assert_eq!(baud_rate.load(Ordering::SeqCst), 115200);
+        assert_eq!(
+            client.stream.output,
+            [IAC, SB, COM_PORT_OPTION, 101, 0, 1, 0xc2, 0, IAC, SE]
+        );
+
+        client.stream.output.clear();
+        assert_eq!(client.write(&[1, IAC, 2]).unwrap(), 3);
+        assert_eq!(client.stream.output, [1, IAC, IAC, 2]);
+    }
+
+    #[test]
+    fn telnet_refuses_unknown_options() {
+        let (mut client, baud_rate) = telnet_client();
+
+        // Terminal type both ways, asking the server for com port control, a refusal of an
+        // option that is off and com port commands without having agreed on them
+        client.stream.input = vec![IAC, WILL, 24, IAC, DO, 24, IAC, DO, COM_PORT_OPTION];
+        client.stream.input.extend_from_slice(&[IAC, WONT, 24]);
+        client.stream.input.extend_from_slice(&[
+            IAC,
+            SB,
+            COM_PORT_OPTION,
+            1,
+            0,
+            1,
+            0xc2,
+            0,
+            IAC,
+            SE,
+        ]);
+        let mut buf = [0; 32];
+        assert_eq!(
+            client.read(&mut buf).unwrap_err().kind(),
+            io::ErrorKind::WouldBlock
+        );
+        assert_eq!(
+            client.stream.output,
+            [IAC, DONT, 24, IAC, WONT, 24, IAC, WONT, COM_PORT_OPTION]
+        );
+        assert_eq!(baud_rate.load(Ordering::SeqCst), 0);
+
+        // Offering com port control twice only gets one answer
+        client.stream.output.clear();
+        client.stream.input = vec![IAC, WILL, COM_PORT_OPTION, IAC, WILL, COM_PORT_OPTION];
+        let _ = client.read(&mut buf);
+        assert_eq!(client.stream.output, [IAC, DO, COM_PORT_OPTION]);
+    }
+
+    #[test]
+    fn telnet_write_waits_for_queue() {
+        let (mut client, _) = telnet_client();
+        client.stream.capacity = Some(2);
+
+        // Takes the data but only two bytes fit, the rest stays queued
+        assert_eq!(client.write(&[1, IAC, 2]).unwrap(), 3);
+        assert_eq!(client.stream.output, [1, IAC]);
+        assert_eq!(
+            client.write(&[3]).unwrap_err().kind(),
+            io::ErrorKind::WouldBlock
+        );
+
+        client.stream.capacity = None;
+        assert_eq!(client.write(&[3]).unwrap(), 1);
+        assert_eq!(client.stream.output, [1, IAC, IAC, 2, 3]);
+    }
+}
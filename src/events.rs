@@ -0,0 +1,196 @@
+/*
+ *  events.rs
+ *  Copyright 2021 ItJustWorksTM
+ *
+ *  Licensed under the Apache License, Version 2.0 (the "License");
+ *  you may not use this file except in compliance with the License.
+ *  You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ *  Unless required by applicable law or agreed to in writing, software
+ *  distributed under the License is distributed on an "AS IS" BASIS,
+ *  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ *  See the License for the specific language governing permissions and
+ *  limitations under the License.
+ *
+ */
+
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::io::Read;
+use std::sync::mpsc::Sender;
+use std::thread;
+use std::time::Duration;
+
+use crate::board::{ExitStatus, Status};
+use crate::board_view::{BoardView, FrameBuffer, GpioPin, UartChannel};
+use crate::runtime_log::LogRecord;
+use crate::worker::Workers;
+
+#[derive(Debug, Clone, Eq, Hash, PartialEq)]
+pub enum BoardEvent {
+    DigitalChanged { pin: usize, old: bool, new: bool },
+    AnalogChanged { pin: usize, old: u16, new: u16 },
+    UartReceived { channel: usize, bytes: Vec<u8> },
+    // The contents of a frame buffer changed
+    FrameWritten { key: usize },
+    Log(LogRecord),
+    StatusChanged { old: Status, new: Status },
+    Exited(ExitStatus),
+}
+
+/// What [`BoardHandle::events`](crate::board::BoardHandle::events) keeps an eye on.
+///
+/// Pins, UART channels and frame buffers are polled on a background thread every `interval`.
+/// Log lines are emitted as the sketch output is read in the background. Status changes and the
+/// exit are only noticed inside [`BoardHandle::tick`](crate::board::BoardHandle::tick) and the
+/// calls built on it, so nothing of the sort arrives on a board that is not being ticked.
+#[derive(Debug, Clone, Eq, Hash, PartialEq)]
+pub struct EventConfig {
+    pub interval: Duration,
+    /// Reads the sketch output of every UART channel, which leaves nothing for other readers.
+    /// Off by default for that reason.
+    pub uart: bool,
+    pub frame_buffers: bool,
+}
+
+impl Default for EventConfig {
+    fn default() -> Self {
+        EventConfig {
+            interval: Duration::from_millis(10),
+            uart: false,
+            frame_buffers: true,
+        }
+    }
+}
+
+struct PinState {
+    id: usize,
+    pin: GpioPin,
+    digital: bool,
+    analog: u16,
+}
+
+struct FrameState {
+    key: usize,
+    frame_buffer: FrameBuffer,
+    hash: Option<u64>,
+}
+
+fn frame_hash(frame_buffer: &FrameBuffer, buf: &mut Vec<u8>) -> Option<u64> {
+    let size = frame_buffer.width() as usize * frame_buffer.height() as usize * 3;
+    if size == 0 {
+        return None;
+    }
+    buf.resize(size, 0);
+    if !frame_buffer.read(buf) {
+        return None;
+    }
+    let mut hasher = DefaultHasher::new();
+    buf.hash(&mut hasher);
+    Some(hasher.finish())
+}
+
+// Polls duplicated view handles until the sketch exits, the board goes away or the receiver
+// hangs up
+pub(crate) fn spawn_poller(
+    workers: &Workers,
+    view: &BoardView,
+    config: EventConfig,
+    sender: Sender<BoardEvent>,
+) {
+    let mut pins: Vec<_> = view
+        .pins
+        .iter()
+        .map(|(&id, pin)| PinState {
+            id,
+            digital: pin.digital_read(),
+            analog: pin.analog_read(),
+            pin: pin.duplicate(),
+        })
+        .collect();
+    pins.sort_by_key(|state| state.id);
+
+    let channels: Vec<UartChannel> = if config.uart {
+        view.uart_channels
+            .iter()
+            .map(UartChannel::duplicate)
+            .collect()
+    } else {
+        vec![]
+    };
+
+    let mut frame_buf = vec![];
+    let mut frames: Vec<_> = if config.frame_buffers {
+        view.frame_buffers
+            .iter()
+            .map(|(&key, frame_buffer)| FrameState {
+                key,
+                hash: frame_hash(frame_buffer, &mut frame_buf),
+                frame_buffer: frame_buffer.duplicate(),
+            })
+            .collect()
+    } else {
+        vec![]
+    };
+    frames.sort_by_key(|state| state.key);
+
+    workers.spawn(move |liveness| {
+        let mut uart_buf = [0; 256];
+        loop {
+            let mut events = vec![];
+
+            for state in &mut pins {
+                let digital = state.pin.digital_read();
+                if digital != state.digital {
+                    events.push(BoardEvent::DigitalChanged {
+                        pin: state.id,
+                        old: state.digital,
+                        new: digital,
+                    });
+                    state.digital = digital;
+                }
+                let analog = state.pin.analog_read();
+                if analog != state.analog {
+                    events.push(BoardEvent::AnalogChanged {
+                        pin: state.id,
+                        old: state.analog,
+                        new: analog,
+                    });
+                    state.analog = analog;
+                }
+            }
+
+            for (i, mut channel) in channels.iter().enumerate() {
+                let mut bytes = vec![];
+                while let Ok(read @ 1..) = channel.read(&mut uart_buf) {
+                    bytes.extend_from_slice(&uart_buf[..read]);
+                }
+                if !bytes.is_empty() {
+                    events.push(BoardEvent::UartReceived { channel: i, bytes });
+                }
+            }
+
+            for state in &mut frames {
+                let hash = frame_hash(&state.frame_buffer, &mut frame_buf);
+                if hash.is_some() && hash != state.hash {
+                    events.push(BoardEvent::FrameWritten { key: state.key });
+                }
+                state.hash = hash.or(state.hash);
+            }
+
+            for event in events {
+                if sender.send(event).is_err() {
+                    return;
+                }
+            }
+
+            // One last round after the exit so trailing output is not lost
+            if !liveness.alive() {
+                return;
+            }
+            thread::sleep(config.interval);
+        }
+    });
+}
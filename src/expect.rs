@@ -0,0 +1,298 @@
+/*
+ *  expect.rs
+ *  Copyright 2021 ItJustWorksTM
+ *
+ *  Licensed under the Apache License, Version 2.0 (the "License");
+ *  you may not use this file except in compliance with the License.
+ *  You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ *  Unless required by applicable law or agreed to in writing, software
+ *  distributed under the License is distributed on an "AS IS" BASIS,
+ *  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ *  See the License for the specific language governing permissions and
+ *  limitations under the License.
+ *
+ */
+
+use std::fmt;
+use std::io::{self, Read};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use regex::bytes::Regex;
+use thiserror::Error;
+
+use crate::board_view::UartChannel;
+
+// How often the uart is read while expecting
+const POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+#[derive(Debug, Copy, Clone, Eq, Hash, PartialEq)]
+pub enum Direction {
+    // Written to the sketch
+    Sent,
+    // Written by the sketch
+    Received,
+}
+
+#[derive(Debug, Clone, Eq, Hash, PartialEq)]
+pub struct Exchange {
+    // Since the session started
+    pub at: Duration,
+    pub direction: Direction,
+    pub bytes: Vec<u8>,
+}
+
+/// Everything sent and received during a session, one line per exchange.
+#[derive(Debug, Clone, Default, Eq, Hash, PartialEq)]
+pub struct Transcript {
+    exchanges: Vec<Exchange>,
+}
+
+impl Transcript {
+    pub fn exchanges(&self) -> &[Exchange] {
+        &self.exchanges
+    }
+
+    fn push(&mut self, at: Duration, direction: Direction, bytes: &[u8]) {
+        self.exchanges.push(Exchange {
+            at,
+            direction,
+            bytes: bytes.to_vec(),
+        });
+    }
+}
+
+impl fmt::Display for Transcript {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for exchange in &self.exchanges {
+            let arrow = match exchange.direction {
+                Direction::Sent => ">>",
+                Direction::Received => "<<",
+            };
+            writeln!(
+                f,
+                "{:>9.3}s {} \"{}\"",
+                exchange.at.as_secs_f64(),
+                arrow,
+                String::from_utf8_lossy(&exchange.bytes).escape_debug()
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum ExpectError {
+    #[error("Failed to talk to the sketch")]
+    Io(#[from] io::Error),
+    #[error("Invalid pattern")]
+    Pattern(#[from] regex::Error),
+    #[error("Timed out after {timeout:?} expecting {patterns:?}, transcript:\n{transcript}")]
+    Timeout {
+        patterns: Vec<String>,
+        timeout: Duration,
+        transcript: Transcript,
+    },
+}
+
+/// What a pattern matched, text that is not valid UTF-8 is replaced.
+#[derive(Debug, Clone, Default, Eq, Hash, PartialEq)]
+pub struct Captures {
+    // Group 0 is the whole match
+    groups: Vec<Option<String>>,
+    names: Vec<(String, usize)>,
+    before: String,
+}
+
+impl Captures {
+    pub fn as_str(&self) -> &str {
+        self.get(0).unwrap_or_default()
+    }
+
+    pub fn get(&self, group: usize) -> Option<&str> {
+        self.groups.get(group)?.as_deref()
+    }
+
+    pub fn name(&self, name: &str) -> Option<&str> {
+        let &(_, group) = self.names.iter().find(|(n, _)| n == name)?;
+        self.get(group)
+    }
+
+    // Received before the match, skipped over by it
+    pub fn before(&self) -> &str {
+        &self.before
+    }
+}
+
+// Earliest match of any of the patterns, on ties the first pattern wins.
+// Returns the index of the pattern and where the match ended.
+fn find(buffer: &[u8], patterns: &[Regex]) -> Option<(usize, Captures, usize)> {
+    let (index, captures) = patterns
+        .iter()
+        .enumerate()
+        .filter_map(|(i, pattern)| Some((i, pattern.captures(buffer)?)))
+        .min_by_key(|(i, captures)| (captures.get(0).unwrap().start(), *i))?;
+
+    let whole = captures.get(0).unwrap();
+    let text = |bytes: &[u8]| String::from_utf8_lossy(bytes).into_owned();
+    let found = Captures {
+        groups: captures
+            .iter()
+            .map(|group| group.map(|group| text(group.as_bytes())))
+            .collect(),
+        names: patterns[index]
+            .capture_names()
+            .enumerate()
+            .filter_map(|(group, name)| Some((name?.to_string(), group)))
+            .collect(),
+        before: text(&buffer[..whole.start()]),
+    };
+    Some((index, found, whole.end()))
+}
+
+/// Scripted conversation with a sketch over a uart, like pexpect.
+///
+/// Received bytes are kept until a pattern matches them, everything up to the end of the match
+/// is consumed by it.
+pub struct Expect<'a> {
+    uart: &'a UartChannel,
+    line_ending: String,
+    send_timeout: Duration,
+    start: Instant,
+    buffer: Vec<u8>,
+    transcript: Transcript,
+}
+
+impl<'a> Expect<'a> {
+    pub fn new(uart: &'a UartChannel) -> Self {
+        Expect {
+            uart,
+            line_ending: "\n".into(),
+            send_timeout: Duration::from_secs(5),
+            start: Instant::now(),
+            buffer: vec![],
+            transcript: Transcript::default(),
+        }
+    }
+
+    pub fn with_line_ending(self, line_ending: impl Into<String>) -> Self {
+        Expect {
+            line_ending: line_ending.into(),
+            ..self
+        }
+    }
+
+    // How long sending may wait for the sketch to make room
+    pub fn with_send_timeout(self, send_timeout: Duration) -> Self {
+        Expect {
+            send_timeout,
+            ..self
+        }
+    }
+
+    pub fn transcript(&self) -> &Transcript {
+        &self.transcript
+    }
+
+    pub fn send(&mut self, bytes: impl AsRef<[u8]>) -> Result<(), ExpectError> {
+        let bytes = bytes.as_ref();
+        self.transcript
+            .push(self.start.elapsed(), Direction::Sent, bytes);
+        Ok(self.uart.write_all_timeout(bytes, self.send_timeout)?)
+    }
+
+    pub fn send_line(&mut self, line: &str) -> Result<(), ExpectError> {
+        self.send(format!("{}{}", line, self.line_ending))
+    }
+
+    pub fn expect(&mut self, pattern: &str, timeout: Duration) -> Result<Captures, ExpectError> {
+        self.expect_any(&[pattern], timeout)
+            .map(|(_, captures)| captures)
+    }
+
+    // Waits for whichever pattern matches first, returns its index
+    pub fn expect_any(
+        &mut self,
+        patterns: &[&str],
+        timeout: Duration,
+    ) -> Result<(usize, Captures), ExpectError> {
+        let regexes = patterns
+            .iter()
+            .map(|pattern| Regex::new(pattern))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let deadline = Instant::now() + timeout;
+        loop {
+            self.receive()?;
+            if let Some((index, captures, end)) = find(&self.buffer, &regexes) {
+                self.buffer.drain(..end);
+                return Ok((index, captures));
+            }
+            if Instant::now() >= deadline {
+                return Err(ExpectError::Timeout {
+                    patterns: patterns.iter().map(|p| p.to_string()).collect(),
+                    timeout,
+                    transcript: self.transcript.clone(),
+                });
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+
+    fn receive(&mut self) -> io::Result<()> {
+        let mut chunk = [0; 256];
+        loop {
+            let read = (&mut &*self.uart).read(&mut chunk)?;
+            if read == 0 {
+                return Ok(());
+            }
+            self.transcript
+                .push(self.start.elapsed(), Direction::Received, &chunk[..read]);
+            self.buffer.extend_from_slice(&chunk[..read]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use regex::bytes::Regex;
+
+    use crate::expect::{find, Direction, Transcript};
+
+    #[test]
+    fn earliest_match_wins() {
+        let patterns = [
+            Regex::new(r"ready").unwrap(),
+            Regex::new(r"value=(?P<value>\d+)").unwrap(),
+        ];
+        let (index, captures, end) = find(b"boot\nvalue=42\nready\n", &patterns).unwrap();
+        assert_eq!(index, 1);
+        assert_eq!(captures.as_str(), "value=42");
+        assert_eq!(captures.get(1), Some("42"));
+        assert_eq!(captures.name("value"), Some("42"));
+        assert_eq!(captures.before(), "boot\n");
+        assert_eq!(end, 13);
+
+        assert!(find(b"nothing", &patterns).is_none());
+    }
+
+    #[test]
+    fn transcript_lines() {
+        let mut transcript = Transcript::default();
+        transcript.push(Duration::from_millis(5), Direction::Sent, b"menu\n");
+        transcript.push(
+            Duration::from_millis(1250),
+            Direction::Received,
+            b"1) \"go\"",
+        );
+        assert_eq!(
+            transcript.to_string(),
+            "    0.005s >> \"menu\\n\"\n    1.250s << \"1) \\\"go\\\"\"\n"
+        );
+    }
+}
@@ -0,0 +1,428 @@
+/*
+ *  fault.rs
+ *  Copyright 2021 ItJustWorksTM
+ *
+ *  Licensed under the Apache License, Version 2.0 (the "License");
+ *  you may not use this file except in compliance with the License.
+ *  You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ *  Unless required by applicable law or agreed to in writing, software
+ *  distributed under the License is distributed on an "AS IS" BASIS,
+ *  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ *  See the License for the specific language governing permissions and
+ *  limitations under the License.
+ *
+ */
+
+use std::cmp;
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::board_view::UartChannel;
+pub use crate::expect::Direction;
+use crate::rng::Rng;
+
+/// Chances of each fault, per byte unless noted otherwise.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct FaultConfig {
+    pub drop: f64,
+    // A single random bit
+    pub bit_flip: f64,
+    pub duplicate: f64,
+    // Random bytes inserted before the byte, up to garbage_length of them
+    pub garbage: f64,
+    pub garbage_length: usize,
+    // Per read or write, up to max_delay
+    pub delay: f64,
+    pub max_delay: Duration,
+    // Noise replacing burst_length bytes in a row with random ones
+    pub burst: f64,
+    pub burst_length: usize,
+    pub seed: u64,
+}
+
+impl Default for FaultConfig {
+    fn default() -> Self {
+        FaultConfig {
+            drop: 0.0,
+            bit_flip: 0.0,
+            duplicate: 0.0,
+            garbage: 0.0,
+            garbage_length: 4,
+            delay: 0.0,
+            max_delay: Duration::from_millis(50),
+            burst: 0.0,
+            burst_length: 8,
+            seed: 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Eq, Hash, PartialEq)]
+pub enum FaultKind {
+    Drop(u8),
+    BitFlip { original: u8, corrupted: u8 },
+    Duplicate(u8),
+    Garbage(Vec<u8>),
+    Delay(Duration),
+    // Length of the noise, starting at the offset
+    Burst(usize),
+}
+
+#[derive(Debug, Clone, Eq, Hash, PartialEq)]
+pub struct Fault {
+    // Since the wrapper was created
+    pub at: Duration,
+    pub direction: Direction,
+    // Position in the original stream of that direction
+    pub offset: u64,
+    pub kind: FaultKind,
+}
+
+// Faults of one direction
+struct Injector {
+    config: FaultConfig,
+    rng: Rng,
+    offset: u64,
+    // Bytes left in the current burst
+    burst_left: usize,
+}
+
+impl Injector {
+    fn new(config: FaultConfig) -> Self {
+        Injector {
+            config,
+            rng: Rng::new(config.seed),
+            offset: 0,
+            burst_left: 0,
+        }
+    }
+
+    fn chance(&mut self, probability: f64) -> bool {
+        probability > 0.0 && self.rng.next_f64() < probability
+    }
+
+    fn random_byte(&mut self) -> u8 {
+        self.rng.next_u64() as u8
+    }
+
+    fn delay(&mut self) -> Option<Duration> {
+        if self.chance(self.config.delay) {
+            Some(self.config.max_delay.mul_f64(self.rng.next_f64()))
+        } else {
+            None
+        }
+    }
+
+    // Corrupts bytes into out, reporting every fault
+    fn inject(
+        &mut self,
+        bytes: &[u8],
+        out: &mut VecDeque<u8>,
+        mut report: impl FnMut(u64, FaultKind),
+    ) {
+        for &byte in bytes {
+            let offset = self.offset;
+            self.offset += 1;
+
+            if self.burst_left == 0 && self.chance(self.config.burst) {
+                self.burst_left = self.config.burst_length;
+                report(offset, FaultKind::Burst(self.burst_left));
+            }
+            if self.burst_left > 0 {
+                let noise = self.random_byte();
+                out.push_back(noise);
+                self.burst_left -= 1;
+                continue;
+            }
+
+            if self.chance(self.config.garbage) {
+                let length = 1 + (self.rng.next_u64() % self.config.garbage_length.max(1) as u64);
+                let garbage: Vec<_> = (0..length).map(|_| self.random_byte()).collect();
+                out.extend(&garbage);
+                report(offset, FaultKind::Garbage(garbage));
+            }
+            if self.chance(self.config.drop) {
+                report(offset, FaultKind::Drop(byte));
+                continue;
+            }
+            let mut byte = byte;
+            if self.chance(self.config.bit_flip) {
+                let corrupted = byte ^ (1 << (self.rng.next_u64() % 8));
+                report(
+                    offset,
+                    FaultKind::BitFlip {
+                        original: byte,
+                        corrupted,
+                    },
+                );
+                byte = corrupted;
+            }
+            out.push_back(byte);
+            if self.chance(self.config.duplicate) {
+                out.push_back(byte);
+                report(offset, FaultKind::Duplicate(byte));
+            }
+        }
+    }
+}
+
+/// Injects faults into what passes through a uart, in place of its own `Read` and `Write`.
+///
+/// Reads corrupt what the sketch sent, writes what it receives. Delayed reads return nothing
+/// until the delay is over while delayed writes block. A write may claim bytes the sketch has
+/// no room for yet, they are sent before anything else and flushing waits for them.
+pub struct Faulty<T> {
+    inner: T,
+    read: Option<Injector>,
+    write: Option<Injector>,
+    started: Instant,
+    // Corrupted bytes not handed out yet, in each direction
+    received: VecDeque<u8>,
+    unsent: VecDeque<u8>,
+    held_until: Option<Instant>,
+    log: Vec<Fault>,
+    // Reused for reading from the inner reader
+    scratch: Vec<u8>,
+}
+
+impl<T> Faulty<T> {
+    // The same faults both ways, each from its own sequence
+    pub fn new(inner: T, config: FaultConfig) -> Self {
+        Faulty {
+            inner,
+            read: Some(Injector::new(config)),
+            write: Some(Injector::new(FaultConfig {
+                seed: config.seed.wrapping_add(1),
+                ..config
+            })),
+            started: Instant::now(),
+            received: VecDeque::new(),
+            unsent: VecDeque::new(),
+            held_until: None,
+            log: vec![],
+            scratch: vec![],
+        }
+    }
+
+    // Leaves the other direction alone
+    pub fn only(self, direction: Direction) -> Self {
+        match direction {
+            Direction::Sent => Faulty { read: None, ..self },
+            Direction::Received => Faulty {
+                write: None,
+                ..self
+            },
+        }
+    }
+
+    pub fn log(&self) -> &[Fault] {
+        &self.log
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+
+    fn record(
+        log: &mut Vec<Fault>,
+        started: Instant,
+        direction: Direction,
+    ) -> impl FnMut(u64, FaultKind) + '_ {
+        move |offset, kind| {
+            log.push(Fault {
+                at: started.elapsed(),
+                direction,
+                offset,
+                kind,
+            })
+        }
+    }
+}
+
+impl<T: Write> Faulty<T> {
+    // Hands over what the sketch has room for
+    fn send_unsent(&mut self) -> io::Result<()> {
+        while !self.unsent.is_empty() {
+            let (front, _) = self.unsent.as_slices();
+            match self.inner.write(front) {
+                Ok(written) => drop(self.unsent.drain(..written)),
+                Err(err) if err.kind() == io::ErrorKind::WriteZero => return Ok(()),
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<T: Read> Read for Faulty<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let injector = match &mut self.read {
+            Some(injector) => injector,
+            None if self.received.is_empty() => return self.inner.read(buf),
+            None => return Ok(drain_into(&mut self.received, buf)),
+        };
+
+        let now = Instant::now();
+        if matches!(self.held_until, Some(until) if until > now) {
+            return Ok(0);
+        }
+        if let Some(delay) = injector.delay() {
+            self.held_until = Some(now + delay);
+            Self::record(&mut self.log, self.started, Direction::Received)(
+                injector.offset,
+                FaultKind::Delay(delay),
+            );
+            return Ok(0);
+        }
+
+        if self.received.len() < buf.len() {
+            if self.scratch.len() < buf.len() {
+                self.scratch.resize(buf.len(), 0);
+            }
+            let read = self.inner.read(&mut self.scratch[..buf.len()])?;
+            injector.inject(
+                &self.scratch[..read],
+                &mut self.received,
+                Self::record(&mut self.log, self.started, Direction::Received),
+            );
+        }
+        Ok(drain_into(&mut self.received, buf))
+    }
+}
+
+impl<T: Write> Write for Faulty<T> {
+    // Fails with WriteZero like the uart while earlier bytes are still waiting for room
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.write.is_none() && self.unsent.is_empty() {
+            return self.inner.write(buf);
+        }
+        self.send_unsent()?;
+        if !self.unsent.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::WriteZero,
+                "Uart buffer is full, increase the max buffer size or try again",
+            ));
+        }
+
+        match &mut self.write {
+            Some(injector) => {
+                if let Some(delay) = injector.delay() {
+                    Self::record(&mut self.log, self.started, Direction::Sent)(
+                        injector.offset,
+                        FaultKind::Delay(delay),
+                    );
+                    thread::sleep(delay);
+                }
+                injector.inject(
+                    buf,
+                    &mut self.unsent,
+                    Self::record(&mut self.log, self.started, Direction::Sent),
+                );
+            }
+            None => self.unsent.extend(buf),
+        }
+        self.send_unsent()?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        while !self.unsent.is_empty() {
+            self.send_unsent()?;
+            thread::sleep(Duration::from_millis(1));
+        }
+        self.inner.flush()
+    }
+}
+
+fn drain_into(from: &mut VecDeque<u8>, buf: &mut [u8]) -> usize {
+    let read = cmp::min(from.len(), buf.len());
+    for (dst, src) in buf.iter_mut().zip(from.drain(..read)) {
+        *dst = src;
+    }
+    read
+}
+
+impl UartChannel {
+    pub fn faulty(&self, config: FaultConfig) -> Faulty<&UartChannel> {
+        Faulty::new(self, config)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Read, Write};
+
+    use crate::fault::{Direction, FaultConfig, FaultKind, Faulty};
+
+    fn corrupt(config: FaultConfig, input: &[u8]) -> (Vec<u8>, Vec<FaultKind>) {
+        let mut faulty = Faulty::new(vec![], config).only(Direction::Sent);
+        faulty.write_all(input).unwrap();
+        let log = faulty
+            .log()
+            .iter()
+            .map(|fault| fault.kind.clone())
+            .collect();
+        (faulty.into_inner(), log)
+    }
+
+    #[test]
+    fn clean_by_default() {
+        let (output, log) = corrupt(FaultConfig::default(), b"hello");
+        assert_eq!(output, b"hello");
+        assert!(log.is_empty());
+    }
+
+    #[test]
+    fn faults_are_logged_and_reproducible() {
+        let config = FaultConfig {
+            drop: 0.05,
+            bit_flip: 0.05,
+            duplicate: 0.05,
+            garbage: 0.05,
+            burst: 0.01,
+            seed: 7,
+            ..Default::default()
+        };
+        let input: Vec<u8> = (0..=255).cycle().take(2000).collect();
+        let (output, log) = corrupt(config, &input);
+        assert_eq!(corrupt(config, &input), (output.clone(), log.clone()));
+        assert_ne!(output, input);
+
+        let count = |f: fn(&FaultKind) -> bool| log.iter().filter(|kind| f(kind)).count();
+        let drops = count(|kind| matches!(kind, FaultKind::Drop(_)));
+        let duplicates = count(|kind| matches!(kind, FaultKind::Duplicate(_)));
+        let garbage: usize = log
+            .iter()
+            .map(|kind| match kind {
+                FaultKind::Garbage(garbage) => garbage.len(),
+                _ => 0,
+            })
+            .sum();
+        assert!(drops > 0 && duplicates > 0 && garbage > 0);
+        assert!(count(|kind| matches!(kind, FaultKind::BitFlip { .. })) > 0);
+        assert!(count(|kind| matches!(kind, FaultKind::Burst(_))) > 0);
+        assert_eq!(output.len(), input.len() - drops + duplicates + garbage);
+    }
+
+    #[test]
+    fn reads_are_corrupted() {
+        let config = FaultConfig {
+            drop: 1.0,
+            ..Default::default()
+        };
+        let mut faulty = Faulty::new(&b"lost"[..], config);
+        let mut buf = vec![];
+        assert_eq!(faulty.read_to_end(&mut buf).unwrap(), 0);
+        assert_eq!(faulty.log().len(), 4);
+        assert_eq!(faulty.log()[0].direction, Direction::Received);
+    }
+}
@@ -41,7 +41,7 @@ auto OpaqueBoard::attach_sketch(const std::unique_ptr<OpaqueSketch>& sketch) ->
 
 auto OpaqueBoard::tick() -> ExitInfo {
     internal.tick();
-    return {exited, exited};
+    return {exited, exit_code};
 }
 
 auto OpaqueBoard::status() const -> OpaqueBoardStatus {
@@ -68,8 +68,9 @@ auto OpaqueBoard::view() -> std::unique_ptr<OpaqueBoardView> {
     return std::make_unique<OpaqueBoardView>(OpaqueBoardView{internal.view()});
 }
 
-auto OpaqueBoard::runtime_log(rust::Slice<uint8_t> buf) -> size_t {
-    auto log = internal.runtime_log();
+// Safe to call from any thread, the log is guarded by its own mutex
+auto OpaqueBoard::runtime_log(rust::Slice<uint8_t> buf) const -> size_t {
+    auto log = const_cast<smce::Board&>(internal).runtime_log();
     const auto len = std::min(buf.size(), log.second.size());
 
     std::memcpy(buf.data(), log.second.data(), len);
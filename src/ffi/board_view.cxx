@@ -53,6 +53,7 @@ auto OpaqueVirtualPin::clone() -> std::unique_ptr<OpaqueVirtualPin> { return std
 auto OpaqueVirtualUart::readable() -> size_t { return tx().size(); }
 auto OpaqueVirtualUart::max_read() -> size_t { return tx().max_size(); }
 auto OpaqueVirtualUart::max_write() -> size_t { return rx().max_size(); }
+auto OpaqueVirtualUart::pending() -> size_t { return rx().size(); }
 auto OpaqueVirtualUart::read(rust::Slice<uint8_t> buf) -> size_t {
     return tx().read({reinterpret_cast<char*>(buf.data()), buf.size() - 1});
 }
@@ -60,16 +61,23 @@ auto OpaqueVirtualUart::write(rust::Slice<const uint8_t> buf) -> size_t {
     return rx().write({reinterpret_cast<const char*>(buf.data()), buf.size()});
 }
 auto OpaqueVirtualUart::front() -> uint8_t { return tx().front(); }
+auto OpaqueVirtualUart::take_pending(rust::Slice<uint8_t> buf) -> size_t {
+    return rx().read({reinterpret_cast<char*>(buf.data()), buf.size() - 1});
+}
 auto OpaqueVirtualUart::clone() -> std::unique_ptr<OpaqueVirtualUart> { return std::make_unique<OpaqueVirtualUart>(*this); }
 
-auto OpaqueFramebuffer::needs_horizontal_flip() -> bool { return needs_horizontal_flip(); }
-auto OpaqueFramebuffer::needs_vertical_flip() -> bool { return needs_vertical_flip(); }
-auto OpaqueFramebuffer::width() -> uint16_t { return width(); }
-auto OpaqueFramebuffer::height() -> uint16_t { return height(); }
-auto OpaqueFramebuffer::freq() -> uint8_t { return freq(); }
+auto OpaqueFramebuffer::needs_horizontal_flip() -> bool { return smce::FrameBuffer::needs_horizontal_flip(); }
+auto OpaqueFramebuffer::needs_vertical_flip() -> bool { return smce::FrameBuffer::needs_vertical_flip(); }
+auto OpaqueFramebuffer::width() -> uint16_t { return smce::FrameBuffer::get_width(); }
+auto OpaqueFramebuffer::height() -> uint16_t { return smce::FrameBuffer::get_height(); }
+auto OpaqueFramebuffer::freq() -> uint8_t { return smce::FrameBuffer::get_freq(); }
 auto OpaqueFramebuffer::write_rgb888(rust::Slice<const uint8_t> buf) -> bool {
     return smce::FrameBuffer::write_rgb888({reinterpret_cast<const std::byte*>(buf.data()), buf.size()});
 }
 auto OpaqueFramebuffer::write_rgb444(rust::Slice<const uint8_t> buf) -> bool {
     return smce::FrameBuffer::write_rgb444({reinterpret_cast<const std::byte*>(buf.data()), buf.size()});
 }
+auto OpaqueFramebuffer::read_rgb888(rust::Slice<uint8_t> buf) -> bool {
+    return smce::FrameBuffer::read_rgb888({reinterpret_cast<std::byte*>(buf.data()), buf.size()});
+}
+auto OpaqueFramebuffer::clone() -> std::unique_ptr<OpaqueFramebuffer> { return std::make_unique<OpaqueFramebuffer>(*this); }
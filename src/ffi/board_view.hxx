@@ -37,9 +37,11 @@ struct OpaqueVirtualUart : smce::VirtualUart {
     auto readable() -> size_t;
     auto max_read() -> size_t;
     auto max_write() -> size_t;
+    auto pending() -> size_t;
     auto read(rust::Slice<uint8_t> buf) -> size_t;
     auto write(rust::Slice<const uint8_t> buf) -> size_t;
     auto front() -> uint8_t;
+    auto take_pending(rust::Slice<uint8_t> buf) -> size_t;
     auto clone() -> std::unique_ptr<OpaqueVirtualUart>;
 };
 
@@ -51,6 +53,8 @@ struct OpaqueFramebuffer : smce::FrameBuffer {
     auto freq() -> uint8_t;
     auto write_rgb888(rust::Slice<const uint8_t> buf) -> bool;
     auto write_rgb444(rust::Slice<const uint8_t> buf) -> bool;
+    auto read_rgb888(rust::Slice<uint8_t> buf) -> bool;
+    auto clone() -> std::unique_ptr<OpaqueFramebuffer>;
 };
 
 struct OpaqueBoardView : smce::BoardView {
@@ -132,6 +132,7 @@ pub mod ffi {
             config: &OpaqueSketchConfig,
         ) -> UniquePtr<OpaqueSketch>;
         pub(crate) unsafe fn get_source<'a>(self: &'a OpaqueSketch) -> &'a str;
+        pub(crate) unsafe fn get_executable<'a>(self: &'a OpaqueSketch) -> &'a str;
         pub(crate) unsafe fn is_compiled(self: &OpaqueSketch) -> bool;
         pub(crate) unsafe fn get_uuid(self: &OpaqueSketch) -> Uuid;
 
@@ -192,7 +193,7 @@ pub mod ffi {
             conf: &UniquePtr<OpaqueBoardConfig>,
         ) -> bool;
         pub(crate) unsafe fn view(self: Pin<&mut OpaqueBoard>) -> UniquePtr<OpaqueBoardView>;
-        pub(crate) unsafe fn runtime_log(self: Pin<&mut OpaqueBoard>, buf: &mut [u8]) -> usize;
+        pub(crate) unsafe fn runtime_log(self: &OpaqueBoard, buf: &mut [u8]) -> usize;
 
         include!("board_view.hxx");
 
@@ -219,14 +220,21 @@ pub mod ffi {
         pub(crate) unsafe fn digital_read(self: Pin<&mut OpaqueVirtualPin>) -> bool;
         pub(crate) unsafe fn analog_write(self: Pin<&mut OpaqueVirtualPin>, val: u16);
         pub(crate) unsafe fn analog_read(self: Pin<&mut OpaqueVirtualPin>) -> u16;
+        pub(crate) unsafe fn clone(self: Pin<&mut OpaqueVirtualPin>)
+            -> UniquePtr<OpaqueVirtualPin>;
 
         pub(crate) type OpaqueVirtualUart;
         pub(crate) unsafe fn readable(self: Pin<&mut OpaqueVirtualUart>) -> usize;
         pub(crate) unsafe fn max_read(self: Pin<&mut OpaqueVirtualUart>) -> usize;
         pub(crate) unsafe fn max_write(self: Pin<&mut OpaqueVirtualUart>) -> usize;
+        pub(crate) unsafe fn pending(self: Pin<&mut OpaqueVirtualUart>) -> usize;
         pub(crate) unsafe fn write(self: Pin<&mut OpaqueVirtualUart>, buf: &[u8]) -> usize;
         pub(crate) unsafe fn read(self: Pin<&mut OpaqueVirtualUart>, buf: &mut [u8]) -> usize;
         pub(crate) unsafe fn front(self: Pin<&mut OpaqueVirtualUart>) -> u8;
+        pub(crate) unsafe fn take_pending(
+            self: Pin<&mut OpaqueVirtualUart>,
+            buf: &mut [u8],
+        ) -> usize;
         pub(crate) unsafe fn clone(
             self: Pin<&mut OpaqueVirtualUart>,
         ) -> UniquePtr<OpaqueVirtualUart>;
@@ -239,6 +247,11 @@ pub mod ffi {
         pub(crate) unsafe fn freq(self: Pin<&mut OpaqueFramebuffer>) -> u8;
         pub(crate) unsafe fn write_rgb888(self: Pin<&mut OpaqueFramebuffer>, buf: &[u8]) -> bool;
         pub(crate) unsafe fn write_rgb444(self: Pin<&mut OpaqueFramebuffer>, buf: &[u8]) -> bool;
+        pub(crate) unsafe fn read_rgb888(self: Pin<&mut OpaqueFramebuffer>, buf: &mut [u8])
+            -> bool;
+        pub(crate) unsafe fn clone(
+            self: Pin<&mut OpaqueFramebuffer>,
+        ) -> UniquePtr<OpaqueFramebuffer>;
 
     }
 }
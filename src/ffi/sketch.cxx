@@ -34,6 +34,8 @@ auto sketch_new(rust::Str source, const OpaqueSketchConfig& config) -> std::uniq
 
 auto OpaqueSketch::get_source() const -> rust::Str { return {Sketch::get_source().c_str()}; }
 
+auto OpaqueSketch::get_executable() const -> rust::Str { return {Sketch::get_executable().c_str()}; }
+
 auto OpaqueSketch::is_compiled() const -> bool { return Sketch::is_compiled(); }
 
 auto OpaqueSketch::get_uuid() const -> Uuid { return into(Sketch::get_uuid()); }
@@ -30,6 +30,8 @@ struct OpaqueSketch : public smce::Sketch {
 
     auto get_source() const -> rust::Str;
 
+    auto get_executable() const -> rust::Str;
+
     auto is_compiled() const -> bool;
 
     auto get_uuid() const -> Uuid;
@@ -0,0 +1,453 @@
+/*
+ *  input.rs
+ *  Copyright 2021 ItJustWorksTM
+ *
+ *  Licensed under the Apache License, Version 2.0 (the "License");
+ *  you may not use this file except in compliance with the License.
+ *  You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ *  Unless required by applicable law or agreed to in writing, software
+ *  distributed under the License is distributed on an "AS IS" BASIS,
+ *  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ *  See the License for the specific language governing permissions and
+ *  limitations under the License.
+ *
+ */
+
+use std::collections::{BTreeSet, VecDeque};
+use std::time::Duration;
+
+use crate::board_view::BoardView;
+use crate::rng::Rng;
+use crate::simulation::{require_input, require_output, Peripheral, PeripheralError};
+
+// Levels only change when the simulation steps, so keep its interval well below the duration
+#[derive(Debug, Copy, Clone, Eq, Hash, PartialEq)]
+pub struct Bounce {
+    // Time until the contact settles
+    pub duration: Duration,
+    // Amount of spurious level changes before settling
+    pub transitions: u32,
+    pub seed: u64,
+}
+
+/// Momentary push button, pressed pulls the pin low unless `active_high` is set.
+#[derive(Debug, Clone)]
+pub struct PushButton {
+    pin: usize,
+    active_high: bool,
+    bounce: Option<Bounce>,
+    rng: Rng,
+    pressed: bool,
+    time: Duration,
+    // Levels still to be applied, in the pressed sense
+    pending: VecDeque<(Duration, bool)>,
+    contact: bool,
+}
+
+impl PushButton {
+    pub fn new(pin: usize) -> Self {
+        PushButton {
+            pin,
+            active_high: false,
+            bounce: None,
+            rng: Rng::new(0),
+            pressed: false,
+            time: Duration::ZERO,
+            pending: VecDeque::new(),
+            contact: false,
+        }
+    }
+
+    pub fn active_high(self, active_high: bool) -> Self {
+        PushButton {
+            active_high,
+            ..self
+        }
+    }
+
+    pub fn with_bounce(self, bounce: Bounce) -> Self {
+        PushButton {
+            bounce: Some(bounce),
+            rng: Rng::new(bounce.seed),
+            ..self
+        }
+    }
+
+    pub fn is_pressed(&self) -> bool {
+        self.pressed
+    }
+
+    pub fn press(&mut self) {
+        self.set(true);
+    }
+
+    pub fn release(&mut self) {
+        self.set(false);
+    }
+
+    fn set(&mut self, pressed: bool) {
+        if pressed == self.pressed {
+            return;
+        }
+        self.pressed = pressed;
+        self.pending.clear();
+
+        if let Some(bounce) = self.bounce {
+            // Random moments within the bounce duration, alternating levels
+            let mut moments: Vec<_> = (0..bounce.transitions)
+                .map(|_| bounce.duration.mul_f64(self.rng.next_f64()))
+                .collect();
+            moments.sort();
+            for (i, at) in moments.into_iter().enumerate() {
+                self.pending
+                    .push_back((self.time + at, (i % 2 == 0) == pressed));
+            }
+            self.pending
+                .push_back((self.time + bounce.duration, pressed));
+        } else {
+            self.pending.push_back((self.time, pressed));
+        }
+    }
+
+    fn level(&self) -> bool {
+        self.contact == self.active_high
+    }
+}
+
+impl Peripheral for PushButton {
+    fn attach(&mut self, view: &BoardView) -> Result<(), PeripheralError> {
+        require_input(view, self.pin)?.digital_write(self.level());
+        Ok(())
+    }
+
+    fn step(&mut self, view: &BoardView, dt: Duration) {
+        self.time += dt;
+        while let Some(&(at, contact)) = self.pending.front() {
+            if at > self.time {
+                break;
+            }
+            self.contact = contact;
+            self.pending.pop_front();
+        }
+        view.pins[self.pin].digital_write(self.level());
+    }
+}
+
+/// Latching switch, on drives the pin high.
+#[derive(Debug, Clone, Eq, Hash, PartialEq)]
+pub struct ToggleSwitch {
+    pin: usize,
+    on: bool,
+}
+
+impl ToggleSwitch {
+    pub fn new(pin: usize, on: bool) -> Self {
+        ToggleSwitch { pin, on }
+    }
+
+    pub fn is_on(&self) -> bool {
+        self.on
+    }
+
+    pub fn set(&mut self, on: bool) {
+        self.on = on;
+    }
+
+    pub fn toggle(&mut self) {
+        self.on = !self.on;
+    }
+}
+
+impl Peripheral for ToggleSwitch {
+    fn attach(&mut self, view: &BoardView) -> Result<(), PeripheralError> {
+        require_input(view, self.pin)?.digital_write(self.on);
+        Ok(())
+    }
+
+    fn step(&mut self, view: &BoardView, _dt: Duration) {
+        view.pins[self.pin].digital_write(self.on);
+    }
+}
+
+pub const KEYPAD_LAYOUT: [[char; 4]; 4] = [
+    ['1', '2', '3', 'A'],
+    ['4', '5', '6', 'B'],
+    ['7', '8', '9', 'C'],
+    ['*', '0', '#', 'D'],
+];
+
+/// 4x4 matrix keypad, the sketch pulls one row low at a time and reads the columns.
+///
+/// Columns read high through their pull-up, unless a pressed key connects them to a low row.
+/// A row only counts as low once the sketch has driven it high before, as a pin nobody drives
+/// reads low as well. Columns are only updated when the simulation steps, so the sketch has to
+/// give it some time between driving a row and reading the columns.
+#[derive(Debug, Clone, Eq, Hash, PartialEq)]
+pub struct Keypad {
+    rows: [usize; 4],
+    columns: [usize; 4],
+    layout: [[char; 4]; 4],
+    pressed: BTreeSet<(usize, usize)>,
+    // Rows seen high, an undriven pin reads low just the same
+    driven: [bool; 4],
+}
+
+impl Keypad {
+    pub fn new(rows: [usize; 4], columns: [usize; 4]) -> Self {
+        Keypad {
+            rows,
+            columns,
+            layout: KEYPAD_LAYOUT,
+            pressed: BTreeSet::new(),
+            driven: [false; 4],
+        }
+    }
+
+    pub fn with_layout(self, layout: [[char; 4]; 4]) -> Self {
+        Keypad { layout, ..self }
+    }
+
+    fn position(&self, key: char) -> Option<(usize, usize)> {
+        (0..4)
+            .flat_map(|row| (0..4).map(move |col| (row, col)))
+            .find(|&(row, col)| self.layout[row][col] == key)
+    }
+
+    // Returns false for keys not on the layout
+    pub fn press(&mut self, key: char) -> bool {
+        self.position(key)
+            .map(|position| self.pressed.insert(position))
+            .is_some()
+    }
+
+    pub fn release(&mut self, key: char) {
+        if let Some(position) = self.position(key) {
+            self.pressed.remove(&position);
+        }
+    }
+
+    pub fn release_all(&mut self) {
+        self.pressed.clear();
+    }
+
+    pub fn pressed(&self) -> impl Iterator<Item = char> + '_ {
+        self.pressed
+            .iter()
+            .map(move |&(row, col)| self.layout[row][col])
+    }
+
+    // Column levels for the given row levels. A pressed key only pulls its column low while the
+    // sketch drives its row low, which it must have driven high before to count as driven.
+    fn columns(&mut self, rows: [bool; 4]) -> [bool; 4] {
+        for (driven, &high) in self.driven.iter_mut().zip(&rows) {
+            *driven |= high;
+        }
+        let mut columns = [true; 4];
+        for &(row, col) in &self.pressed {
+            if self.driven[row] && !rows[row] {
+                columns[col] = false;
+            }
+        }
+        columns
+    }
+}
+
+impl Peripheral for Keypad {
+    fn attach(&mut self, view: &BoardView) -> Result<(), PeripheralError> {
+        for &row in &self.rows {
+            require_output(view, row)?;
+        }
+        for &col in &self.columns {
+            require_input(view, col)?.digital_write(true);
+        }
+        Ok(())
+    }
+
+    fn step(&mut self, view: &BoardView, _dt: Duration) {
+        let mut rows = [false; 4];
+        for (level, &row) in rows.iter_mut().zip(&self.rows) {
+            *level = view.pins[row].digital_read();
+        }
+        let columns = self.columns(rows);
+        for (&pin, &level) in self.columns.iter().zip(&columns) {
+            view.pins[pin].digital_write(level);
+        }
+    }
+}
+
+// Gray code sequence of (A, B), turning clockwise A leads B
+const QUADRATURE: [(bool, bool); 4] = [(false, false), (true, false), (true, true), (false, true)];
+
+/// Incremental rotary encoder producing quadrature signals on A and B.
+///
+/// Every detent goes through all four phases, each held for `phase`.
+#[derive(Debug, Clone, Eq, Hash, PartialEq)]
+pub struct RotaryEncoder {
+    a: usize,
+    b: usize,
+    phase: Duration,
+    state: usize,
+    // Phase changes still to be made, positive is clockwise
+    pending: i64,
+    position: i64,
+    since: Duration,
+}
+
+impl RotaryEncoder {
+    pub fn new(a: usize, b: usize) -> Self {
+        RotaryEncoder {
+            a,
+            b,
+            phase: Duration::from_millis(2),
+            state: 0,
+            pending: 0,
+            position: 0,
+            since: Duration::ZERO,
+        }
+    }
+
+    pub fn with_phase(self, phase: Duration) -> Self {
+        RotaryEncoder { phase, ..self }
+    }
+
+    // Turns by `detents`, negative is counter clockwise
+    pub fn rotate(&mut self, detents: i32) {
+        self.pending += detents as i64 * 4;
+    }
+
+    // Detents fully turned so far
+    pub fn position(&self) -> i64 {
+        self.position
+    }
+
+    pub fn is_idle(&self) -> bool {
+        self.pending == 0
+    }
+
+    // Moves on to the next phase once the current one was held long enough
+    fn advance(&mut self, dt: Duration) {
+        self.since += dt;
+        if self.pending != 0 && self.since >= self.phase {
+            self.since = Duration::ZERO;
+            let clockwise = self.pending > 0;
+            self.state = if clockwise {
+                (self.state + 1) % 4
+            } else {
+                (self.state + 3) % 4
+            };
+            self.pending -= self.pending.signum();
+            if self.state == 0 {
+                self.position += if clockwise { 1 } else { -1 };
+            }
+        }
+    }
+
+    fn write(&self, view: &BoardView) {
+        let (a, b) = QUADRATURE[self.state];
+        view.pins[self.a].digital_write(a);
+        view.pins[self.b].digital_write(b);
+    }
+}
+
+impl Peripheral for RotaryEncoder {
+    fn attach(&mut self, view: &BoardView) -> Result<(), PeripheralError> {
+        require_input(view, self.a)?;
+        require_input(view, self.b)?;
+        self.write(view);
+        Ok(())
+    }
+
+    fn step(&mut self, view: &BoardView, dt: Duration) {
+        self.advance(dt);
+        self.write(view);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use crate::input::{Bounce, Keypad, PushButton, RotaryEncoder, QUADRATURE};
+
+    #[test]
+    fn bounce_settles_on_target() {
+        let bounce = Bounce {
+            duration: Duration::from_millis(5),
+            transitions: 4,
+            seed: 3,
+        };
+        let mut button = PushButton::new(0).with_bounce(bounce);
+        button.press();
+
+        let levels: Vec<_> = button.pending.iter().map(|&(_, level)| level).collect();
+        assert_eq!(levels, [true, false, true, false, true]);
+        assert!(button.pending.iter().all(|&(at, _)| at <= bounce.duration));
+        assert!(button
+            .pending
+            .iter()
+            .zip(button.pending.iter().skip(1))
+            .all(|(a, b)| a.0 <= b.0));
+
+        button.release();
+        assert_eq!(button.pending.back(), Some(&(bounce.duration, false)));
+    }
+
+    #[test]
+    fn keypad_keys() {
+        let mut keypad = Keypad::new([0, 1, 2, 3], [4, 5, 6, 7]);
+        assert!(keypad.press('5'));
+        assert!(keypad.press('#'));
+        assert!(!keypad.press('x'));
+        assert_eq!(keypad.pressed().collect::<String>(), "5#");
+        keypad.release('5');
+        assert_eq!(keypad.pressed().collect::<String>(), "#");
+    }
+
+    #[test]
+    fn keypad_needs_driven_rows() {
+        let mut keypad = Keypad::new([0, 1, 2, 3], [4, 5, 6, 7]);
+        keypad.press('5');
+        // Nothing drives the rows yet
+        assert_eq!(keypad.columns([false; 4]), [true; 4]);
+        // Scanning the first and then the second row
+        assert_eq!(keypad.columns([false, true, true, true]), [true; 4]);
+        assert_eq!(
+            keypad.columns([true, false, true, true]),
+            [true, false, true, true]
+        );
+        // Once driven, a row low is a row being scanned
+        assert_eq!(keypad.columns([false; 4]), [true, false, true, true]);
+    }
+
+    #[test]
+    fn encoder_quadrature() {
+        let phase = Duration::from_millis(2);
+        let mut encoder = RotaryEncoder::new(0, 1).with_phase(phase);
+        encoder.rotate(1);
+
+        let mut states = vec![];
+        for _ in 0..8 {
+            encoder.advance(Duration::from_millis(1));
+            states.push(QUADRATURE[encoder.state]);
+        }
+        let expected: Vec<_> = [0, 1, 1, 2, 2, 3, 3, 0]
+            .iter()
+            .map(|&i| QUADRATURE[i])
+            .collect();
+        assert_eq!(states, expected);
+        assert_eq!(encoder.position(), 1);
+        assert!(encoder.is_idle());
+
+        // Counter clockwise B leads A
+        encoder.rotate(-1);
+        encoder.advance(phase);
+        assert_eq!(QUADRATURE[encoder.state], (false, true));
+        for _ in 0..3 {
+            encoder.advance(phase);
+        }
+        assert_eq!(encoder.position(), 0);
+    }
+}
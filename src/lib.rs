@@ -16,11 +16,36 @@
  *
  */
 
+pub mod actuator;
+#[cfg(feature = "tokio")]
+pub mod asynchronous;
 pub mod board;
 pub mod board_config;
 pub mod board_view;
+pub mod bridge;
+pub mod events;
+pub mod expect;
+pub mod fault;
 pub mod ffi;
+pub mod input;
+pub mod limits;
+pub mod meter;
+pub mod pacing;
+mod process;
+pub mod replay;
+mod rng;
+pub mod runtime_log;
+pub mod sandbox;
+pub mod signal;
+pub mod simulation;
 pub mod sketch;
 pub mod sketch_config;
+pub mod stimulus;
+pub mod supervisor;
 pub mod toolchain;
 pub mod uuid;
+pub mod vcd;
+pub mod vehicle;
+pub mod wait;
+mod worker;
+pub mod world;
@@ -0,0 +1,268 @@
+/*
+ *  limits.rs
+ *  Copyright 2021 ItJustWorksTM
+ *
+ *  Licensed under the Apache License, Version 2.0 (the "License");
+ *  you may not use this file except in compliance with the License.
+ *  You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ *  Unless required by applicable law or agreed to in writing, software
+ *  distributed under the License is distributed on an "AS IS" BASIS,
+ *  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ *  See the License for the specific language governing permissions and
+ *  limitations under the License.
+ *
+ */
+
+use std::time::{Duration, SystemTime};
+
+use crate::process::{self, Pid, Rlimits, Termination, Usage};
+use crate::runtime_log::{LogStream, RuntimeLog};
+
+/// Constraints on the sketch process, Linux only.
+///
+/// The kernel limits are set in the forked sketch process before it executes the sketch, so they
+/// hold from its first instruction on. A sketch process that could not be limited exits right
+/// away instead. Open files and processes are also watched while the board is ticked.
+#[derive(Debug, Clone, Default, Eq, Hash, PartialEq)]
+pub struct ResourceLimits {
+    /// Maximum address space in bytes
+    pub memory: Option<u64>,
+    /// Maximum CPU time, rounded up to whole seconds
+    pub cpu_time: Option<Duration>,
+    /// Maximum amount of open file descriptors
+    pub open_files: Option<u64>,
+    /// Maximum amount of processes the sketch may have running, including itself.
+    /// Note that the kernel counts all processes of the user against this limit on fork.
+    pub processes: Option<u64>,
+}
+
+impl ResourceLimits {
+    pub fn is_unlimited(&self) -> bool {
+        *self == Self::default()
+    }
+
+    // What the kernel is asked to enforce
+    pub(crate) fn rlimits(&self) -> Rlimits {
+        let cpu_secs = |cpu_time: Duration| {
+            let secs = cpu_time.as_secs() + u64::from(cpu_time.subsec_nanos() > 0);
+            // SIGXCPU at the soft limit, SIGKILL a second later if it is ignored
+            (Limit::CpuTime, secs, secs + 1)
+        };
+        [
+            self.memory.map(|memory| (Limit::Memory, memory, memory)),
+            self.cpu_time.map(cpu_secs),
+            self.open_files
+                .map(|files| (Limit::OpenFiles, files, files)),
+            self.processes.map(|procs| (Limit::Processes, procs, procs)),
+        ]
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum Limit {
+    Memory,
+    CpuTime,
+    OpenFiles,
+    Processes,
+}
+
+// What libstdc++ prints when an allocation failure goes unhandled and aborts the sketch
+const OUT_OF_MEMORY: &str = "std::bad_alloc";
+
+// Limits the kernel does not enforce for the sketch alone, using the whole allowance is fine.
+// The process count includes the sketch itself.
+fn over(limits: &ResourceLimits, usage: &Usage) -> Option<Limit> {
+    let over = |limit: Option<u64>, used: u64| matches!(limit, Some(limit) if used > limit);
+    if over(limits.open_files, usage.open_files) {
+        Some(Limit::OpenFiles)
+    } else if over(limits.processes, usage.processes) {
+        Some(Limit::Processes)
+    } else {
+        None
+    }
+}
+
+// Keeps track of the resource usage of a limited sketch process, kills it when
+// it hits a limit the kernel only enforces by failing syscalls (files, processes).
+pub(crate) struct LimitMonitor {
+    pid: Pid,
+    limits: ResourceLimits,
+    // Log lines from before belong to an earlier run
+    started: SystemTime,
+    peak: Usage,
+    tripped: Option<Limit>,
+}
+
+impl LimitMonitor {
+    // The kernel limits are already in place, see ResourceLimits::rlimits
+    pub(crate) fn attach(pid: Pid, limits: ResourceLimits, started: SystemTime) -> Self {
+        LimitMonitor {
+            pid,
+            limits,
+            started,
+            peak: Usage::default(),
+            tripped: None,
+        }
+    }
+
+    pub(crate) fn sample(&mut self) {
+        if self.tripped.is_some() {
+            return;
+        }
+
+        let usage = match process::usage(self.pid) {
+            Some(usage) => usage,
+            None => return,
+        };
+
+        self.peak = Usage {
+            cpu_time: self.peak.cpu_time.max(usage.cpu_time),
+            memory: self.peak.memory.max(usage.memory),
+            open_files: self.peak.open_files.max(usage.open_files),
+            processes: self.peak.processes.max(usage.processes),
+        };
+
+        self.tripped = over(&self.limits, &usage);
+
+        if self.tripped.is_some() {
+            let _ = process::kill(self.pid, process::SIGKILL);
+        }
+    }
+
+    // Figures out whether the sketch died because of one of our limits, only a signal that
+    // killed it counts. Exiting with the same number, or an unknown end, does not.
+    pub(crate) fn exceeded(
+        &self,
+        termination: Option<Termination>,
+        log: &RuntimeLog,
+    ) -> Option<Limit> {
+        if self.tripped.is_some() {
+            return self.tripped;
+        }
+        let signal = match termination {
+            Some(Termination::Signalled(signal)) => signal,
+            _ => return None,
+        };
+
+        if let Some(cpu_time) = self.limits.cpu_time {
+            // The hard limit kills, which we only trust if we saw it getting close
+            if signal == process::SIGXCPU
+                || (signal == process::SIGKILL
+                    && self.peak.cpu_time + Duration::from_secs(1) >= cpu_time)
+            {
+                return Some(Limit::CpuTime);
+            }
+        }
+
+        // A failed allocation is no signal of its own, only the abort it leads to tells
+        let out_of_memory = || {
+            log.stream(LogStream::Stderr)
+                .filter(|record| record.timestamp >= self.started)
+                .any(|record| record.line.contains(OUT_OF_MEMORY))
+        };
+        if self.limits.memory.is_some() && signal == process::SIGABRT && out_of_memory() {
+            return Some(Limit::Memory);
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, SystemTime};
+
+    use crate::limits::{over, Limit, LimitMonitor, ResourceLimits};
+    use crate::process::{self, Termination, Usage};
+    use crate::runtime_log::{LogStream, RuntimeLog};
+
+    const SIGSEGV: i32 = 11;
+
+    fn killed(signal: i32) -> Option<Termination> {
+        Some(Termination::Signalled(signal))
+    }
+
+    fn monitor(limits: ResourceLimits) -> LimitMonitor {
+        LimitMonitor {
+            pid: 0,
+            limits,
+            started: SystemTime::now(),
+            peak: Usage::default(),
+            tripped: None,
+        }
+    }
+
+    #[test]
+    fn allowance_may_be_used_up() {
+        let limits = ResourceLimits {
+            open_files: Some(16),
+            processes: Some(1),
+            ..Default::default()
+        };
+        let mut usage = Usage {
+            open_files: 16,
+            processes: 1,
+            ..Default::default()
+        };
+        assert_eq!(over(&limits, &usage), None);
+
+        usage.processes = 2;
+        assert_eq!(over(&limits, &usage), Some(Limit::Processes));
+        usage.open_files = 17;
+        assert_eq!(over(&limits, &usage), Some(Limit::OpenFiles));
+    }
+
+    #[test]
+    fn memory_needs_failed_allocation() {
+        let mut monitor = monitor(ResourceLimits {
+            memory: Some(64 << 20),
+            ..Default::default()
+        });
+        // Peaking right at the limit is no proof on its own
+        monitor.peak.memory = 64 << 20;
+
+        let mut log = RuntimeLog::default();
+        log.feed(LogStream::Stderr, b"Segmentation fault\n");
+        assert_eq!(monitor.exceeded(killed(SIGSEGV), &log), None);
+        assert_eq!(monitor.exceeded(killed(process::SIGABRT), &log), None);
+
+        log.feed(
+            LogStream::Stderr,
+            b"terminate called after throwing an instance of 'std::bad_alloc'\n",
+        );
+        assert_eq!(monitor.exceeded(killed(SIGSEGV), &log), None);
+        assert_eq!(
+            monitor.exceeded(killed(process::SIGABRT), &log),
+            Some(Limit::Memory)
+        );
+        // Caught and exited on its own
+        assert_eq!(
+            monitor.exceeded(Some(Termination::Exited(process::SIGABRT)), &log),
+            None
+        );
+    }
+
+    #[test]
+    fn cpu_time_by_signal() {
+        let monitor = monitor(ResourceLimits {
+            cpu_time: Some(Duration::from_secs(2)),
+            ..Default::default()
+        });
+        let log = RuntimeLog::default();
+        assert_eq!(
+            monitor.exceeded(killed(process::SIGXCPU), &log),
+            Some(Limit::CpuTime)
+        );
+        // Killed long before getting close
+        assert_eq!(monitor.exceeded(killed(process::SIGKILL), &log), None);
+        assert_eq!(monitor.exceeded(Some(Termination::Exited(0)), &log), None);
+        // Exiting with the number of the signal is no sign of it
+        assert_eq!(
+            monitor.exceeded(Some(Termination::Exited(process::SIGXCPU)), &log),
+            None
+        );
+        assert_eq!(monitor.exceeded(None, &log), None);
+    }
+}
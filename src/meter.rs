@@ -0,0 +1,337 @@
+/*
+ *  meter.rs
+ *  Copyright 2021 ItJustWorksTM
+ *
+ *  Licensed under the Apache License, Version 2.0 (the "License");
+ *  you may not use this file except in compliance with the License.
+ *  You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ *  Unless required by applicable law or agreed to in writing, software
+ *  distributed under the License is distributed on an "AS IS" BASIS,
+ *  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ *  See the License for the specific language governing permissions and
+ *  limitations under the License.
+ *
+ */
+
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::board_view::GpioPin;
+use crate::worker::Workers;
+
+#[derive(Debug, Clone, Eq, Hash, PartialEq)]
+pub struct MeterConfig {
+    pub interval: Duration,
+    // Only samples this recent are taken into account
+    pub window: Duration,
+}
+
+impl Default for MeterConfig {
+    fn default() -> Self {
+        MeterConfig {
+            interval: Duration::from_millis(1),
+            window: Duration::from_secs(1),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub struct AnalogStats {
+    pub min: u16,
+    pub max: u16,
+    pub mean: f64,
+}
+
+#[derive(Debug, Copy, Clone, Default, Eq, Hash, PartialEq)]
+pub struct PulseStats {
+    pub count: usize,
+    pub min: Duration,
+    pub max: Duration,
+    pub mean: Duration,
+}
+
+/// Behaviour of a pin over the window, fields are `None` when there was too little to go on.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MeterReport {
+    // Time actually covered by samples, at most the configured window
+    pub span: Duration,
+    pub analog: Option<AnalogStats>,
+    pub rising_edges: usize,
+    pub falling_edges: usize,
+    // In Hz, from the rising edges
+    pub frequency: Option<f64>,
+    // Fraction of the time the pin was high
+    pub duty_cycle: Option<f64>,
+    // Only pulses that started and ended within the window
+    pub high_pulses: Option<PulseStats>,
+    pub low_pulses: Option<PulseStats>,
+}
+
+#[derive(Debug, Copy, Clone, Eq, Hash, PartialEq)]
+pub(crate) struct Sample {
+    pub(crate) at: Duration,
+    pub(crate) digital: bool,
+    pub(crate) analog: u16,
+}
+
+pub(crate) struct Window {
+    samples: VecDeque<Sample>,
+    length: Duration,
+}
+
+fn pulse_stats(pulses: &[Duration]) -> Option<PulseStats> {
+    Some(PulseStats {
+        count: pulses.len(),
+        min: *pulses.iter().min()?,
+        max: *pulses.iter().max()?,
+        mean: pulses.iter().sum::<Duration>() / pulses.len() as u32,
+    })
+}
+
+impl Window {
+    pub(crate) fn new(length: Duration) -> Self {
+        Window {
+            samples: VecDeque::new(),
+            length,
+        }
+    }
+
+    pub(crate) fn push(&mut self, sample: Sample) {
+        while matches!(self.samples.front(), Some(first) if first.at + self.length < sample.at) {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(sample);
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.samples.clear();
+    }
+
+    pub(crate) fn report(&self) -> MeterReport {
+        let (first, last) = match (self.samples.front(), self.samples.back()) {
+            (Some(first), Some(last)) => (first, last),
+            _ => return MeterReport::default(),
+        };
+
+        let analog = AnalogStats {
+            min: self.samples.iter().map(|s| s.analog).min().unwrap(),
+            max: self.samples.iter().map(|s| s.analog).max().unwrap(),
+            mean: self.samples.iter().map(|s| s.analog as f64).sum::<f64>()
+                / self.samples.len() as f64,
+        };
+
+        let mut rises = vec![];
+        let mut falls = 0;
+        let mut high_time = Duration::ZERO;
+        let mut last_edge = None;
+        let mut high_pulses = vec![];
+        let mut low_pulses = vec![];
+
+        for (prev, next) in self.samples.iter().zip(self.samples.iter().skip(1)) {
+            if prev.digital {
+                high_time += next.at - prev.at;
+            }
+            if prev.digital == next.digital {
+                continue;
+            }
+
+            if next.digital {
+                rises.push(next.at);
+            } else {
+                falls += 1;
+            }
+            // The level that just ended was a complete pulse if it started with an edge too
+            if let Some(since) = last_edge {
+                let pulses = if prev.digital {
+                    &mut high_pulses
+                } else {
+                    &mut low_pulses
+                };
+                pulses.push(next.at - since);
+            }
+            last_edge = Some(next.at);
+        }
+
+        let span = last.at - first.at;
+        let frequency = match (rises.first(), rises.last()) {
+            (Some(first), Some(last)) if rises.len() > 1 => {
+                Some((rises.len() - 1) as f64 / (*last - *first).as_secs_f64())
+            }
+            _ => None,
+        };
+
+        MeterReport {
+            span,
+            analog: Some(analog),
+            rising_edges: rises.len(),
+            falling_edges: falls,
+            frequency,
+            duty_cycle: (!span.is_zero()).then(|| high_time.as_secs_f64() / span.as_secs_f64()),
+            high_pulses: pulse_stats(&high_pulses),
+            low_pulses: pulse_stats(&low_pulses),
+        }
+    }
+}
+
+/// Samples a pin on a background thread and reports on it over a sliding window.
+///
+/// Keeps sampling until dropped, the sketch exits or the board is stopped.
+pub struct PinMeter {
+    window: Arc<Mutex<Window>>,
+    stop: Arc<AtomicBool>,
+}
+
+impl PinMeter {
+    pub(crate) fn spawn(workers: &Workers, pin: &GpioPin, config: MeterConfig) -> Self {
+        let window = Arc::new(Mutex::new(Window::new(config.window)));
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let pin = pin.duplicate();
+        let samples = window.clone();
+        let stopped = stop.clone();
+        workers.spawn(move |liveness| {
+            let start = Instant::now();
+            while liveness.alive() && !stopped.load(Ordering::SeqCst) {
+                let sample = Sample {
+                    at: start.elapsed(),
+                    digital: pin.digital_read(),
+                    analog: pin.analog_read(),
+                };
+                samples.lock().unwrap().push(sample);
+                thread::sleep(config.interval);
+            }
+        });
+
+        PinMeter { window, stop }
+    }
+
+    pub fn report(&self) -> MeterReport {
+        self.window.lock().unwrap().report()
+    }
+
+    // Forgets everything sampled so far
+    pub fn reset(&self) {
+        self.window.lock().unwrap().clear();
+    }
+}
+
+impl Drop for PinMeter {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+    }
+}
+
+// When the levels changed, and what they became
+pub(crate) type LevelChange = (Duration, Vec<bool>);
+
+// Level changes of a few pins, timed by a worker sampling them every `interval` rather than
+// once per simulation step. Each change comes with the levels of all the pins at that moment.
+#[derive(Debug)]
+pub(crate) struct EdgeLog {
+    changes: Arc<Mutex<VecDeque<LevelChange>>>,
+    stop: Arc<AtomicBool>,
+}
+
+impl EdgeLog {
+    pub(crate) fn spawn(workers: &Workers, pins: &[&GpioPin], interval: Duration) -> Self {
+        let changes = Arc::new(Mutex::new(VecDeque::new()));
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let pins: Vec<GpioPin> = pins.iter().map(|pin| pin.duplicate()).collect();
+        let log = changes.clone();
+        let stopped = stop.clone();
+        workers.spawn(move |liveness| {
+            let start = Instant::now();
+            let mut levels: Vec<bool> = pins.iter().map(|pin| pin.digital_read()).collect();
+            while liveness.alive() && !stopped.load(Ordering::SeqCst) {
+                thread::sleep(interval);
+                let now: Vec<bool> = pins.iter().map(|pin| pin.digital_read()).collect();
+                if now != levels {
+                    log.lock()
+                        .unwrap()
+                        .push_back((start.elapsed(), now.clone()));
+                    levels = now;
+                }
+            }
+        });
+
+        EdgeLog { changes, stop }
+    }
+
+    // Changes since the previous call, oldest first
+    pub(crate) fn take(&self) -> Vec<LevelChange> {
+        self.changes.lock().unwrap().drain(..).collect()
+    }
+}
+
+impl Drop for EdgeLog {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use crate::meter::{PulseStats, Sample, Window};
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn square_wave() {
+        let mut window = Window::new(ms(1000));
+        // 10 Hz with 30% duty cycle, sampled every millisecond for two seconds
+        for t in 0..2000 {
+            window.push(Sample {
+                at: ms(t),
+                digital: t % 100 < 30,
+                analog: if t % 100 < 30 { 255 } else { 0 },
+            });
+        }
+
+        let report = window.report();
+        assert_eq!(report.span, ms(1000));
+        assert_eq!(report.rising_edges, 10);
+        assert_eq!(report.falling_edges, 10);
+        assert!((report.frequency.unwrap() - 10.0).abs() < 1e-9);
+        assert!((report.duty_cycle.unwrap() - 0.3).abs() < 0.01);
+
+        let analog = report.analog.unwrap();
+        assert_eq!((analog.min, analog.max), (0, 255));
+        assert!((analog.mean - 76.5).abs() < 1.0);
+
+        let high = report.high_pulses.unwrap();
+        assert_eq!((high.min, high.max, high.mean), (ms(30), ms(30), ms(30)));
+        assert_eq!(report.low_pulses.unwrap().mean, ms(70));
+    }
+
+    #[test]
+    fn constant_level() {
+        let mut window = Window::new(ms(100));
+        assert_eq!(window.report().analog, None);
+
+        for t in 0..50 {
+            window.push(Sample {
+                at: ms(t),
+                digital: true,
+                analog: 153,
+            });
+        }
+
+        let report = window.report();
+        assert_eq!(report.rising_edges, 0);
+        assert_eq!(report.frequency, None);
+        assert_eq!(report.duty_cycle, Some(1.0));
+        assert_eq!(report.high_pulses, None::<PulseStats>);
+        assert_eq!(report.analog.unwrap().mean, 153.0);
+    }
+}
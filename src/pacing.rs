@@ -0,0 +1,297 @@
+/*
+ *  pacing.rs
+ *  Copyright 2021 ItJustWorksTM
+ *
+ *  Licensed under the Apache License, Version 2.0 (the "License");
+ *  you may not use this file except in compliance with the License.
+ *  You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ *  Unless required by applicable law or agreed to in writing, software
+ *  distributed under the License is distributed on an "AS IS" BASIS,
+ *  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ *  See the License for the specific language governing permissions and
+ *  limitations under the License.
+ *
+ */
+
+use std::cmp;
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::board_view::UartChannel;
+
+// How far a line may fall behind before the time is lost, like an idle wire
+const BACKLOG: Duration = Duration::from_millis(10);
+
+#[derive(Debug, Copy, Clone, Eq, Hash, PartialEq)]
+pub enum Parity {
+    None,
+    Even,
+    Odd,
+}
+
+#[derive(Debug, Copy, Clone, Eq, Hash, PartialEq)]
+pub enum StopBits {
+    One,
+    Two,
+}
+
+/// Shape of a single character on the wire, 8N1 by default.
+#[derive(Debug, Copy, Clone, Eq, Hash, PartialEq)]
+pub struct FrameFormat {
+    // 5 to 8
+    pub data_bits: u8,
+    pub parity: Parity,
+    pub stop_bits: StopBits,
+}
+
+impl Default for FrameFormat {
+    fn default() -> Self {
+        FrameFormat {
+            data_bits: 8,
+            parity: Parity::None,
+            stop_bits: StopBits::One,
+        }
+    }
+}
+
+impl FrameFormat {
+    // Including the start bit
+    pub fn bits(&self) -> u32 {
+        let parity = match self.parity {
+            Parity::None => 0,
+            Parity::Even | Parity::Odd => 1,
+        };
+        let stop = match self.stop_bits {
+            StopBits::One => 1,
+            StopBits::Two => 2,
+        };
+        1 + self.data_bits as u32 + parity + stop
+    }
+
+    pub fn byte_time(&self, baud_rate: u32) -> Duration {
+        Duration::from_secs_f64(self.bits() as f64 / baud_rate.max(1) as f64)
+    }
+}
+
+/// Bytes moved through a [`Paced`] uart since it was created.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct UartStats {
+    pub sent: u64,
+    pub received: u64,
+    pub elapsed: Duration,
+    // Bytes per second the configured line can carry in each direction
+    pub line_rate: f64,
+}
+
+impl UartStats {
+    pub fn sent_rate(&self) -> f64 {
+        self.sent as f64 / self.elapsed.as_secs_f64()
+    }
+
+    pub fn received_rate(&self) -> f64 {
+        self.received as f64 / self.elapsed.as_secs_f64()
+    }
+
+    // Fraction of the line capacity in use, busiest direction
+    pub fn utilization(&self) -> f64 {
+        self.sent_rate().max(self.received_rate()) / self.line_rate
+    }
+}
+
+// When the line is free again, one direction
+struct Throttle {
+    byte_time: Duration,
+    free: Option<Instant>,
+}
+
+impl Throttle {
+    fn new(byte_time: Duration) -> Self {
+        Throttle {
+            byte_time,
+            free: None,
+        }
+    }
+
+    fn base(&self, now: Instant) -> Instant {
+        let earliest = now.checked_sub(BACKLOG).unwrap_or(now);
+        cmp::max(self.free.unwrap_or(now), earliest)
+    }
+
+    // Bytes that could have been on the wire by now
+    fn credit(&self, now: Instant) -> usize {
+        let base = self.base(now);
+        if base > now {
+            return 0;
+        }
+        1 + ((now - base).as_nanos() / self.byte_time.as_nanos().max(1)) as usize
+    }
+
+    fn consume(&mut self, bytes: usize, now: Instant) {
+        self.free = Some(self.base(now) + self.byte_time * bytes as u32);
+    }
+
+    fn wait(&self, now: Instant) {
+        if let Some(free) = self.free {
+            if free > now {
+                thread::sleep(free - now);
+            }
+        }
+    }
+}
+
+/// Throttles a uart to its baud rate and frame format.
+///
+/// Only the host side is paced, the sketch still reads and writes its own buffers at once.
+/// Received bytes are taken from the sketch as the line would carry them, writes block until
+/// the line has room for at least one more byte.
+pub struct Paced<T> {
+    inner: T,
+    rx: Throttle,
+    tx: Throttle,
+    // Taken from the sketch, not yet on the wire
+    staged: VecDeque<u8>,
+    started: Instant,
+    sent: u64,
+    received: u64,
+    line_rate: f64,
+}
+
+impl<T> Paced<T> {
+    pub fn new(inner: T, baud_rate: u32, format: FrameFormat) -> Self {
+        let byte_time = format.byte_time(baud_rate);
+        Paced {
+            inner,
+            rx: Throttle::new(byte_time),
+            tx: Throttle::new(byte_time),
+            staged: VecDeque::new(),
+            started: Instant::now(),
+            sent: 0,
+            received: 0,
+            line_rate: baud_rate as f64 / format.bits() as f64,
+        }
+    }
+
+    pub fn stats(&self) -> UartStats {
+        UartStats {
+            sent: self.sent,
+            received: self.received,
+            elapsed: self.started.elapsed(),
+            line_rate: self.line_rate,
+        }
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+impl<T: Read> Read for Paced<T> {
+    // Like the uart itself expect 0 size reads, also when the line is still busy.
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let now = Instant::now();
+        let credit = cmp::min(self.rx.credit(now), buf.len());
+        if credit == 0 {
+            return Ok(0);
+        }
+        if self.staged.len() < credit {
+            // One extra byte as the native read never fills the last one
+            let mut chunk = vec![0; credit - self.staged.len() + 1];
+            let read = self.inner.read(&mut chunk)?;
+            self.staged.extend(&chunk[..read]);
+        }
+
+        let read = cmp::min(credit, self.staged.len());
+        for (dst, src) in buf.iter_mut().zip(self.staged.drain(..read)) {
+            *dst = src;
+        }
+        if read > 0 {
+            self.rx.consume(read, now);
+            self.received += read as u64;
+        }
+        Ok(read)
+    }
+}
+
+impl<T: Write> Write for Paced<T> {
+    // Blocks until the line can take a byte, then writes as many as it could have carried.
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return self.inner.write(buf);
+        }
+        self.tx.wait(Instant::now());
+        let now = Instant::now();
+        let credit = cmp::min(self.tx.credit(now), buf.len());
+        let written = self.inner.write(&buf[..credit])?;
+        self.tx.consume(written, now);
+        self.sent += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl UartChannel {
+    // Paced at the baud rate of the channel
+    pub fn paced(&self, format: FrameFormat) -> Paced<&UartChannel> {
+        Paced::new(self, self.info.baud_rate as u32, format)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Read, Write};
+    use std::time::{Duration, Instant};
+
+    use crate::pacing::{FrameFormat, Paced, Parity, StopBits};
+
+    #[test]
+    fn frame_bits() {
+        assert_eq!(FrameFormat::default().bits(), 10);
+        let format = FrameFormat {
+            data_bits: 7,
+            parity: Parity::Even,
+            stop_bits: StopBits::Two,
+        };
+        assert_eq!(format.bits(), 11);
+        assert_eq!(format.byte_time(1100), Duration::from_millis(10));
+    }
+
+    #[test]
+    fn writes_at_baud_rate() {
+        // 2 ms per byte
+        let mut paced = Paced::new(vec![], 5000, FrameFormat::default());
+        let start = Instant::now();
+        paced.write_all(&[0; 20]).unwrap();
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(36), "{:?}", elapsed);
+        assert_eq!(paced.get_ref().len(), 20);
+        assert_eq!(paced.stats().sent, 20);
+    }
+
+    #[test]
+    fn reads_at_baud_rate() {
+        let mut paced = Paced::new(&[7u8; 20][..], 5000, FrameFormat::default());
+        let mut buf = [0; 32];
+        assert_eq!(paced.read(&mut buf).unwrap(), 1);
+        assert_eq!(paced.read(&mut buf).unwrap(), 0);
+
+        let start = Instant::now();
+        let mut total = 1;
+        while total < 20 {
+            total += paced.read(&mut buf).unwrap();
+        }
+        assert!(start.elapsed() >= Duration::from_millis(36));
+        assert_eq!(paced.stats().received, 20);
+    }
+}
@@ -0,0 +1,275 @@
+/*
+ *  process.rs
+ *  Copyright 2021 ItJustWorksTM
+ *
+ *  Licensed under the Apache License, Version 2.0 (the "License");
+ *  you may not use this file except in compliance with the License.
+ *  You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ *  Unless required by applicable law or agreed to in writing, software
+ *  distributed under the License is distributed on an "AS IS" BASIS,
+ *  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ *  See the License for the specific language governing permissions and
+ *  limitations under the License.
+ *
+ */
+
+// Helpers to find and inspect the sketch process libSMCE spawns for us,
+// only implemented for Linux as they rely on procfs.
+
+#[cfg(target_os = "linux")]
+use std::cell::Cell;
+use std::fs::File;
+use std::io;
+use std::time::Duration;
+
+use crate::limits::Limit;
+
+pub(crate) type Pid = i32;
+
+#[cfg(target_os = "linux")]
+pub(crate) use libc::{SIGABRT, SIGKILL, SIGTERM, SIGXCPU};
+#[cfg(not(target_os = "linux"))]
+pub(crate) const SIGABRT: i32 = 6;
+#[cfg(not(target_os = "linux"))]
+pub(crate) const SIGKILL: i32 = 9;
+#[cfg(not(target_os = "linux"))]
+pub(crate) const SIGTERM: i32 = 15;
+#[cfg(not(target_os = "linux"))]
+pub(crate) const SIGXCPU: i32 = 24;
+
+// How a process ended, libSMCE only passes on a number that may be either
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub(crate) enum Termination {
+    Exited(i32),
+    Signalled(i32),
+}
+
+// Kernel resource limits as (limit, soft, hard), one for each kind at most
+pub(crate) type Rlimits = [Option<(Limit, u64, u64)>; 4];
+
+// Exit code of a forked child that could not be limited
+pub(crate) const LIMITS_FAILED: i32 = 126;
+
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq)]
+pub(crate) struct Usage {
+    pub(crate) cpu_time: Duration,
+    pub(crate) memory: u64,
+    pub(crate) open_files: u64,
+    pub(crate) processes: u64,
+}
+
+// Runs `f` and returns the processes it forked from the calling thread,
+// libSMCE spawns the sketch on the thread that calls start().
+pub(crate) fn spawned_by<R>(f: impl FnOnce() -> R) -> (R, Vec<Pid>) {
+    let before = thread_children();
+    let ret = f();
+    let spawned = thread_children()
+        .into_iter()
+        .filter(|pid| !before.contains(pid))
+        .collect();
+    (ret, spawned)
+}
+
+// Runs `f` with the stdout of this process pointed into a pipe, for a process spawned by `f`
+// to inherit. Returns the read end, which does not block. Whatever other threads print in the
+// meantime ends up in there too, Rust's own stdout is held locked to keep that to C code.
+#[cfg(target_os = "linux")]
+pub(crate) fn stdout_captured<R>(f: impl FnOnce() -> R) -> (R, Option<File>) {
+    use std::io::Write;
+    use std::os::unix::io::FromRawFd;
+
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    let _ = stdout.flush();
+
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } != 0 {
+        return (f(), None);
+    }
+    let (read, write) = (fds[0], fds[1]);
+    // The sketch should block on a full pipe rather than lose output
+    unsafe { libc::fcntl(read, libc::F_SETFL, libc::O_NONBLOCK) };
+    let saved = unsafe { libc::fcntl(libc::STDOUT_FILENO, libc::F_DUPFD_CLOEXEC, 0) };
+    if saved < 0 || unsafe { libc::dup2(write, libc::STDOUT_FILENO) } < 0 {
+        unsafe {
+            libc::close(read);
+            libc::close(write);
+            if saved >= 0 {
+                libc::close(saved);
+            }
+        }
+        return (f(), None);
+    }
+    // Only the child keeps the write end, so reading ends once it exits
+    unsafe { libc::close(write) };
+
+    let ret = f();
+    unsafe {
+        libc::dup2(saved, libc::STDOUT_FILENO);
+        libc::close(saved);
+    }
+    (ret, Some(unsafe { File::from_raw_fd(read) }))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn stdout_captured<R>(f: impl FnOnce() -> R) -> (R, Option<File>) {
+    (f(), None)
+}
+
+#[cfg(target_os = "linux")]
+fn thread_children() -> Vec<Pid> {
+    read_pids("/proc/thread-self/children")
+}
+
+#[cfg(not(target_os = "linux"))]
+fn thread_children() -> Vec<Pid> {
+    vec![]
+}
+
+#[cfg(target_os = "linux")]
+fn read_pids(path: &str) -> Vec<Pid> {
+    std::fs::read_to_string(path)
+        .map(|s| {
+            s.split_whitespace()
+                .filter_map(|p| p.parse().ok())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+// Counts every process below `pid`, excluding itself
+#[cfg(target_os = "linux")]
+fn descendants(pid: Pid) -> u64 {
+    let tasks = match std::fs::read_dir(format!("/proc/{}/task", pid)) {
+        Ok(tasks) => tasks,
+        Err(_) => return 0,
+    };
+
+    tasks
+        .filter_map(|task| task.ok())
+        .flat_map(|task| read_pids(&format!("{}/children", task.path().display())))
+        .map(|child| 1 + descendants(child))
+        .sum()
+}
+
+#[cfg(target_os = "linux")]
+pub(crate) fn usage(pid: Pid) -> Option<Usage> {
+    let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    // Skip past the executable name as it may contain spaces,
+    // the first field after it is field 3 (state) in proc(5)
+    let fields: Vec<&str> = stat[stat.rfind(')')? + 1..].split_whitespace().collect();
+    let field = |n: usize| -> Option<u64> { fields.get(n - 3)?.parse().ok() };
+
+    let ticks = unsafe { libc::sysconf(libc::_SC_CLK_TCK) }.max(1) as u64;
+    let cpu_ticks = field(14)? + field(15)?;
+
+    let open_files = std::fs::read_dir(format!("/proc/{}/fd", pid))
+        .map(|fds| fds.count() as u64)
+        .unwrap_or(0);
+
+    Some(Usage {
+        cpu_time: Duration::from_millis(cpu_ticks * 1000 / ticks),
+        memory: field(23)?,
+        open_files,
+        processes: 1 + descendants(pid),
+    })
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn usage(_pid: Pid) -> Option<Usage> {
+    None
+}
+
+#[cfg(target_os = "linux")]
+thread_local! {
+    // Limits for the processes forked from this thread, see limited_forks()
+    static FORK_LIMITS: Cell<Rlimits> = const { Cell::new([None; 4]) };
+}
+
+// Runs `f` with the limits set in every process it forks from the calling thread. They are set
+// in the child right after the fork, so they hold before it executes anything. A child that
+// could not be limited exits with LIMITS_FAILED instead.
+#[cfg(target_os = "linux")]
+pub(crate) fn limited_forks<R>(limits: Rlimits, f: impl FnOnce() -> R) -> io::Result<R> {
+    use std::sync::OnceLock;
+
+    static REGISTERED: OnceLock<bool> = OnceLock::new();
+    let registered = REGISTERED
+        .get_or_init(|| unsafe { libc::pthread_atfork(None, None, Some(apply_fork_limits)) } == 0);
+    if !registered {
+        return Err(io::Error::other("failed to register fork handler"));
+    }
+
+    FORK_LIMITS.with(|pending| pending.set(limits));
+    let ret = f();
+    FORK_LIMITS.with(|pending| pending.set([None; 4]));
+    Ok(ret)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn limited_forks<R>(_limits: Rlimits, _f: impl FnOnce() -> R) -> io::Result<R> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+// Runs in the child of every fork, which may be a copy of a multithreaded process,
+// so only async-signal-safe calls in here
+#[cfg(target_os = "linux")]
+extern "C" fn apply_fork_limits() {
+    for (limit, soft, hard) in FORK_LIMITS.with(Cell::get).iter().flatten() {
+        let resource = match limit {
+            Limit::Memory => libc::RLIMIT_AS,
+            Limit::CpuTime => libc::RLIMIT_CPU,
+            Limit::OpenFiles => libc::RLIMIT_NOFILE,
+            Limit::Processes => libc::RLIMIT_NPROC,
+        };
+        let rlim = libc::rlimit {
+            rlim_cur: *soft as libc::rlim_t,
+            rlim_max: *hard as libc::rlim_t,
+        };
+        if unsafe { libc::setrlimit(resource, &rlim) } != 0 {
+            unsafe { libc::_exit(LIMITS_FAILED) };
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+pub(crate) fn kill(pid: Pid, signal: i32) -> io::Result<()> {
+    match unsafe { libc::kill(pid, signal) } {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn kill(_pid: Pid, _signal: i32) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+// Tells how a child process ended without reaping it, so libSMCE still gets to,
+// None while it is still running
+#[cfg(target_os = "linux")]
+pub(crate) fn peek_exit(pid: Pid) -> io::Result<Option<Termination>> {
+    let mut info: libc::siginfo_t = unsafe { std::mem::zeroed() };
+    let flags = libc::WEXITED | libc::WNOHANG | libc::WNOWAIT;
+    if unsafe { libc::waitid(libc::P_PID, pid as libc::id_t, &mut info, flags) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    // Left zeroed when there is nothing to wait for yet
+    if unsafe { info.si_pid() } == 0 {
+        return Ok(None);
+    }
+    let status = unsafe { info.si_status() };
+    Ok(Some(match info.si_code {
+        libc::CLD_EXITED => Termination::Exited(status),
+        _ => Termination::Signalled(status),
+    }))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn peek_exit(_pid: Pid) -> io::Result<Option<Termination>> {
+    Err(io::ErrorKind::Unsupported.into())
+}
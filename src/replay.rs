@@ -0,0 +1,438 @@
+/*
+ *  replay.rs
+ *  Copyright 2021 ItJustWorksTM
+ *
+ *  Licensed under the Apache License, Version 2.0 (the "License");
+ *  you may not use this file except in compliance with the License.
+ *  You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ *  Unless required by applicable law or agreed to in writing, software
+ *  distributed under the License is distributed on an "AS IS" BASIS,
+ *  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ *  See the License for the specific language governing permissions and
+ *  limitations under the License.
+ *
+ */
+
+use std::collections::BTreeMap;
+use std::io::{self, Read, Write};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use thiserror::Error;
+
+use crate::board_view::BoardView;
+pub use crate::expect::Direction;
+
+// How often the uarts are read while replaying
+const POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+#[derive(Error, Debug)]
+pub enum ReplayError {
+    #[error("Failed to read or replay the recording")]
+    Io(#[from] io::Error),
+    #[error("Invalid recording on line {line}: {reason}")]
+    Csv { line: usize, reason: String },
+    #[error("Recording uses uart channel {0} which the board does not have")]
+    Channel(usize),
+}
+
+/// Bytes that went through a uart at once, from the host's point of view.
+#[derive(Debug, Clone, Eq, Hash, PartialEq)]
+pub struct UartEvent {
+    // Since recording started
+    pub at: Duration,
+    pub channel: usize,
+    pub direction: Direction,
+    pub bytes: Vec<u8>,
+}
+
+/// A uart session, saved as csv with the bytes in hex.
+///
+/// ```text
+/// # time_us, channel, direction, bytes
+/// 1200, 0, sent, 48454c4c4f0a
+/// 1003250, 0, received, 48454c4c4f0a
+/// ```
+#[derive(Debug, Clone, Default, Eq, Hash, PartialEq)]
+pub struct Recording {
+    pub events: Vec<UartEvent>,
+}
+
+impl Recording {
+    pub fn write_csv<W: Write>(&self, mut out: W) -> io::Result<()> {
+        writeln!(out, "# time_us, channel, direction, bytes")?;
+        for event in &self.events {
+            let direction = match event.direction {
+                Direction::Sent => "sent",
+                Direction::Received => "received",
+            };
+            let bytes: String = event.bytes.iter().map(|b| format!("{:02x}", b)).collect();
+            writeln!(
+                out,
+                "{}, {}, {}, {}",
+                event.at.as_micros(),
+                event.channel,
+                direction,
+                bytes
+            )?;
+        }
+        Ok(())
+    }
+
+    pub fn from_csv<R: Read>(mut reader: R) -> Result<Self, ReplayError> {
+        let mut input = String::new();
+        reader.read_to_string(&mut input)?;
+
+        let mut events = vec![];
+        for (i, line) in input.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let err = |reason: &str| ReplayError::Csv {
+                line: i + 1,
+                reason: reason.into(),
+            };
+
+            let fields: Vec<_> = line.split(',').map(str::trim).collect();
+            let (at, channel, direction, bytes) = match fields.as_slice() {
+                &[at, channel, direction, bytes] => (at, channel, direction, bytes),
+                _ => return Err(err("expected 4 fields")),
+            };
+            let at = at.parse().map_err(|_| err("invalid time"))?;
+            let channel = channel.parse().map_err(|_| err("invalid channel"))?;
+            let direction = match direction {
+                "sent" => Direction::Sent,
+                "received" => Direction::Received,
+                _ => return Err(err("expected sent or received")),
+            };
+            if bytes.len() % 2 != 0 || !bytes.is_ascii() {
+                return Err(err("invalid bytes"));
+            }
+            let bytes = (0..bytes.len())
+                .step_by(2)
+                .map(|i| u8::from_str_radix(&bytes[i..i + 2], 16))
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|_| err("invalid bytes"))?;
+
+            events.push(UartEvent {
+                at: Duration::from_micros(at),
+                channel,
+                direction,
+                bytes,
+            });
+        }
+        events.sort_by_key(|event| event.at);
+        Ok(Recording { events })
+    }
+
+    /// Sends what the host sent at the recorded times and compares what the sketch answers.
+    ///
+    /// Start the board right before, as timestamps count from the call. Received bytes may
+    /// arrive up to `tolerance` before or after their recorded time, which is also how long
+    /// the sketch gets to make room for sent bytes. A channel the sketch did not make room on
+    /// in time is reported as [`Mismatch::Unsent`] and gets nothing more sent to it.
+    pub fn replay(
+        &self,
+        view: &BoardView,
+        tolerance: Duration,
+    ) -> Result<ReplayReport, ReplayError> {
+        let mut channels = BTreeMap::new();
+        for event in &self.events {
+            if event.channel >= view.uart_channels.len() {
+                return Err(ReplayError::Channel(event.channel));
+            }
+            channels.entry(event.channel).or_insert_with(Vec::new);
+        }
+        let end = match self.events.last() {
+            Some(event) => event.at + tolerance,
+            None => Duration::ZERO,
+        };
+
+        let start = Instant::now();
+        let mut sends = self
+            .events
+            .iter()
+            .filter(|event| event.direction == Direction::Sent)
+            .peekable();
+        let mut unsent = BTreeMap::new();
+        let mut buf = [0; 256];
+        loop {
+            let now = start.elapsed();
+            while let Some(event) = sends.next_if(|event| event.at <= now) {
+                if unsent.contains_key(&event.channel) {
+                    continue;
+                }
+                let uart = &view.uart_channels[event.channel];
+                match uart.write_all_timeout(&event.bytes, tolerance) {
+                    Err(err) if err.kind() == io::ErrorKind::TimedOut => {
+                        unsent.insert(
+                            event.channel,
+                            Mismatch::Unsent {
+                                channel: event.channel,
+                                expected: event.at,
+                            },
+                        );
+                    }
+                    result => result?,
+                }
+            }
+            for (&channel, received) in channels.iter_mut() {
+                let mut uart = &view.uart_channels[channel];
+                let read = uart.read(&mut buf)?;
+                // Sends above may have blocked for a while
+                let at = start.elapsed();
+                received.extend(buf[..read].iter().map(|&byte| (at, byte)));
+            }
+            if sends.peek().is_none() && start.elapsed() >= end {
+                break;
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+
+        let mismatches = channels
+            .iter()
+            .filter_map(|(&channel, received)| {
+                if let Some(mismatch) = unsent.remove(&channel) {
+                    return Some(mismatch);
+                }
+                let expected: Vec<_> = self
+                    .events
+                    .iter()
+                    .filter(|event| event.channel == channel)
+                    .filter(|event| event.direction == Direction::Received)
+                    .flat_map(|event| event.bytes.iter().map(move |&byte| (event.at, byte)))
+                    .collect();
+                compare(channel, &expected, received, tolerance)
+            })
+            .collect();
+        Ok(ReplayReport { mismatches })
+    }
+}
+
+// First difference on a channel, in content or timing
+fn compare(
+    channel: usize,
+    expected: &[(Duration, u8)],
+    actual: &[(Duration, u8)],
+    tolerance: Duration,
+) -> Option<Mismatch> {
+    for offset in 0..expected.len().max(actual.len()) {
+        match (expected.get(offset), actual.get(offset)) {
+            (Some(&(expected_at, expected)), Some(&(actual_at, actual))) if expected == actual => {
+                if actual_at.abs_diff(expected_at) > tolerance {
+                    return Some(Mismatch::Timing {
+                        channel,
+                        offset,
+                        expected: expected_at,
+                        actual: actual_at,
+                    });
+                }
+            }
+            (expected, actual) => {
+                return Some(Mismatch::Bytes {
+                    channel,
+                    offset,
+                    expected: expected.map(|&(_, byte)| byte),
+                    actual: actual.map(|&(_, byte)| byte),
+                })
+            }
+        }
+    }
+    None
+}
+
+#[derive(Debug, Clone, Eq, Hash, PartialEq)]
+pub enum Mismatch {
+    // None when one side ended early
+    Bytes {
+        channel: usize,
+        offset: usize,
+        expected: Option<u8>,
+        actual: Option<u8>,
+    },
+    Timing {
+        channel: usize,
+        offset: usize,
+        expected: Duration,
+        actual: Duration,
+    },
+    // The sketch did not make room for what was sent at `expected` in time, what it answered
+    // on the channel is not compared then
+    Unsent {
+        channel: usize,
+        expected: Duration,
+    },
+}
+
+/// The first mismatch of each channel the sketch answered on differently.
+#[derive(Debug, Clone, Default, Eq, Hash, PartialEq)]
+pub struct ReplayReport {
+    pub mismatches: Vec<Mismatch>,
+}
+
+impl ReplayReport {
+    pub fn matches(&self) -> bool {
+        self.mismatches.is_empty()
+    }
+}
+
+/// Records uart sessions, shared by the channels taking part.
+#[derive(Clone)]
+pub struct Recorder {
+    start: Instant,
+    events: Arc<Mutex<Vec<UartEvent>>>,
+}
+
+impl Default for Recorder {
+    fn default() -> Self {
+        Recorder {
+            start: Instant::now(),
+            events: Arc::default(),
+        }
+    }
+}
+
+impl Recorder {
+    // Records everything read from and written to inner as the given channel
+    pub fn wrap<T>(&self, channel: usize, inner: T) -> Recorded<T> {
+        Recorded {
+            inner,
+            channel,
+            recorder: self.clone(),
+        }
+    }
+
+    pub fn recording(&self) -> Recording {
+        Recording {
+            events: self.events.lock().unwrap().clone(),
+        }
+    }
+
+    fn push(&self, channel: usize, direction: Direction, bytes: &[u8]) {
+        if !bytes.is_empty() {
+            self.events.lock().unwrap().push(UartEvent {
+                at: self.start.elapsed(),
+                channel,
+                direction,
+                bytes: bytes.to_vec(),
+            });
+        }
+    }
+}
+
+pub struct Recorded<T> {
+    inner: T,
+    channel: usize,
+    recorder: Recorder,
+}
+
+impl<T> Recorded<T> {
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+impl<T: Read> Read for Recorded<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.recorder
+            .push(self.channel, Direction::Received, &buf[..read]);
+        Ok(read)
+    }
+}
+
+impl<T: Write> Write for Recorded<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.recorder
+            .push(self.channel, Direction::Sent, &buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Read, Write};
+    use std::time::Duration;
+
+    use crate::replay::{compare, Direction, Mismatch, Recorder, Recording, ReplayError};
+
+    #[test]
+    fn csv_round_trip() {
+        let recorder = Recorder::default();
+        let mut uart0 = recorder.wrap(0, &b"pong\n"[..]);
+        let mut uart1 = recorder.wrap(1, vec![]);
+        uart1.write_all(b"ping\n").unwrap();
+        let mut buf = String::new();
+        uart0.read_to_string(&mut buf).unwrap();
+
+        let recording = recorder.recording();
+        assert_eq!(recording.events.len(), 2);
+        assert_eq!(recording.events[0].channel, 1);
+        assert_eq!(recording.events[0].direction, Direction::Sent);
+        assert_eq!(recording.events[1].bytes, b"pong\n");
+
+        let mut csv = vec![];
+        recording.write_csv(&mut csv).unwrap();
+        // Timestamps lose their nanoseconds
+        let loaded = Recording::from_csv(csv.as_slice()).unwrap();
+        for (loaded, event) in loaded.events.iter().zip(&recording.events) {
+            assert_eq!(loaded.at.as_micros(), event.at.as_micros());
+            assert_eq!(
+                (loaded.channel, loaded.direction),
+                (event.channel, event.direction)
+            );
+            assert_eq!(loaded.bytes, event.bytes);
+        }
+
+        assert!(matches!(
+            Recording::from_csv("0, 0, sent, 4".as_bytes()),
+            Err(ReplayError::Csv { line: 1, .. })
+        ));
+    }
+
+    #[test]
+    fn mismatches() {
+        let ms = Duration::from_millis;
+        let tolerance = ms(50);
+        let expected = [(ms(100), b'o'), (ms(100), b'k')];
+
+        let on_time = [(ms(120), b'o'), (ms(140), b'k')];
+        assert_eq!(compare(0, &expected, &on_time, tolerance), None);
+
+        let late = [(ms(120), b'o'), (ms(200), b'k')];
+        assert_eq!(
+            compare(0, &expected, &late, tolerance),
+            Some(Mismatch::Timing {
+                channel: 0,
+                offset: 1,
+                expected: ms(100),
+                actual: ms(200)
+            })
+        );
+
+        let short = [(ms(100), b'o')];
+        assert_eq!(
+            compare(2, &expected, &short, tolerance),
+            Some(Mismatch::Bytes {
+                channel: 2,
+                offset: 1,
+                expected: Some(b'k'),
+                actual: None
+            })
+        );
+    }
+}
@@ -0,0 +1,378 @@
+/*
+ *  runtime_log.rs
+ *  Copyright 2021 ItJustWorksTM
+ *
+ *  Licensed under the Apache License, Version 2.0 (the "License");
+ *  you may not use this file except in compliance with the License.
+ *  You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ *  Unless required by applicable law or agreed to in writing, software
+ *  distributed under the License is distributed on an "AS IS" BASIS,
+ *  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ *  See the License for the specific language governing permissions and
+ *  limitations under the License.
+ *
+ */
+
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, Copy, Clone, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum LogStream {
+    // Captured through a pipe the sketch process inherits as its stdout
+    Stdout,
+    // The runtime log libSMCE captures from the sketch process
+    Stderr,
+    // Notices from our side, such as the sketch starting and exiting
+    Runtime,
+}
+
+impl fmt::Display for LogStream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            LogStream::Stdout => "stdout",
+            LogStream::Stderr => "stderr",
+            LogStream::Runtime => "runtime",
+        })
+    }
+}
+
+#[derive(Debug, Clone, Eq, Hash, PartialEq)]
+pub struct LogRecord {
+    pub timestamp: SystemTime,
+    pub stream: LogStream,
+    pub line: String,
+}
+
+impl fmt::Display for LogRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let since_epoch = self
+            .timestamp
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        write!(
+            f,
+            "{}.{:03} [{}] {}",
+            since_epoch.as_secs(),
+            since_epoch.subsec_millis(),
+            self.stream,
+            self.line
+        )
+    }
+}
+
+/// Appends log records to a file, rotating it once it grows past `max_bytes`.
+///
+/// Rotated files get a numeric suffix, `log.1` being the most recent,
+/// at most `max_files` of them are kept around.
+pub struct FileSink {
+    path: PathBuf,
+    max_bytes: u64,
+    max_files: usize,
+    file: File,
+    written: u64,
+}
+
+impl FileSink {
+    pub fn new<P: Into<PathBuf>>(path: P, max_bytes: u64, max_files: usize) -> io::Result<Self> {
+        let path = path.into();
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let written = file.metadata()?.len();
+        Ok(FileSink {
+            path,
+            max_bytes,
+            max_files,
+            file,
+            written,
+        })
+    }
+
+    fn rotated(&self, n: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", n));
+        path.into()
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        if self.max_files == 0 {
+            self.file = File::create(&self.path)?;
+        } else {
+            let _ = fs::remove_file(self.rotated(self.max_files));
+            for n in (1..self.max_files).rev() {
+                let _ = fs::rename(self.rotated(n), self.rotated(n + 1));
+            }
+            fs::rename(&self.path, self.rotated(1))?;
+            self.file = File::create(&self.path)?;
+        }
+        self.written = 0;
+        Ok(())
+    }
+
+    pub fn write(&mut self, record: &LogRecord) -> io::Result<()> {
+        let line = format!("{}\n", record);
+        if self.written > 0 && self.written + line.len() as u64 > self.max_bytes {
+            self.rotate()?;
+        }
+        self.file.write_all(line.as_bytes())?;
+        self.written += line.len() as u64;
+        Ok(())
+    }
+}
+
+// Raw bytes kept around for BoardLogReader
+const RAW_CAPACITY: usize = 64 * 1024;
+
+/// Records the runtime log held at one point, see [`RuntimeLog::history`].
+#[derive(Debug, Clone, Default, Eq, Hash, PartialEq)]
+pub struct LogHistory {
+    records: VecDeque<LogRecord>,
+}
+
+impl LogHistory {
+    pub fn records(&self) -> impl Iterator<Item = &LogRecord> {
+        self.records.iter()
+    }
+
+    pub fn stream(&self, stream: LogStream) -> impl Iterator<Item = &LogRecord> {
+        self.records
+            .iter()
+            .filter(move |record| record.stream == stream)
+    }
+
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+}
+
+/// Runtime log of the board, split into timestamped lines.
+///
+/// Keeps the last `capacity` records in memory, which stay available after the sketch exited.
+/// Lines are timestamped as they are read from the sketch, which happens in the background
+/// while it runs.
+pub struct RuntimeLog {
+    history: LogHistory,
+    capacity: usize,
+    // Unterminated line of each stream
+    partial: BTreeMap<LogStream, Vec<u8>>,
+    raw: VecDeque<u8>,
+    sink: Option<FileSink>,
+    subscribers: Vec<Subscriber>,
+}
+
+// Gets every new record, dropped once it returns false
+pub(crate) type Subscriber = Box<dyn FnMut(&LogRecord) -> bool + Send>;
+
+impl Default for RuntimeLog {
+    fn default() -> Self {
+        RuntimeLog {
+            history: LogHistory::default(),
+            capacity: 1024,
+            partial: BTreeMap::new(),
+            raw: VecDeque::new(),
+            sink: None,
+            subscribers: vec![],
+        }
+    }
+}
+
+impl RuntimeLog {
+    pub fn records(&self) -> impl Iterator<Item = &LogRecord> {
+        self.history.records()
+    }
+
+    pub fn stream(&self, stream: LogStream) -> impl Iterator<Item = &LogRecord> {
+        self.history.stream(stream)
+    }
+
+    // A copy of the records in memory
+    pub fn history(&self) -> LogHistory {
+        self.history.clone()
+    }
+
+    pub fn len(&self) -> usize {
+        self.history.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.history.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.history.records.clear();
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        while self.history.len() > capacity {
+            self.history.records.pop_front();
+        }
+    }
+
+    pub fn set_sink(&mut self, sink: Option<FileSink>) {
+        self.sink = sink;
+    }
+
+    pub(crate) fn subscribe(&mut self, subscriber: Subscriber) {
+        self.subscribers.push(subscriber);
+    }
+
+    pub(crate) fn unsubscribe_all(&mut self) {
+        self.subscribers.clear();
+    }
+
+    // Splits incoming bytes into lines, the last unterminated line of each stream is held back.
+    // Only stderr makes it into the raw bytes, as libSMCE's runtime log always did.
+    pub(crate) fn feed(&mut self, stream: LogStream, bytes: &[u8]) {
+        if stream == LogStream::Stderr {
+            self.raw.extend(bytes);
+            let overflow = self.raw.len().saturating_sub(RAW_CAPACITY);
+            self.raw.drain(..overflow);
+        }
+
+        for &byte in bytes {
+            let partial = self.partial.entry(stream).or_default();
+            if byte == b'\n' {
+                let line = String::from_utf8_lossy(partial).into_owned();
+                partial.clear();
+                self.record(stream, line);
+            } else {
+                partial.push(byte);
+            }
+        }
+    }
+
+    // Emits a held back line, used once the stream ended
+    pub(crate) fn flush(&mut self, stream: LogStream) {
+        if let Some(partial) = self.partial.remove(&stream) {
+            if !partial.is_empty() {
+                self.record(stream, String::from_utf8_lossy(&partial).into_owned());
+            }
+        }
+    }
+
+    pub(crate) fn record(&mut self, stream: LogStream, line: String) {
+        let record = LogRecord {
+            timestamp: SystemTime::now(),
+            stream,
+            line: line.trim_end_matches('\r').to_owned(),
+        };
+
+        if let Some(sink) = &mut self.sink {
+            // A failing sink should not take the log down with it
+            if sink.write(&record).is_err() {
+                self.sink = None;
+            }
+        }
+
+        self.subscribers
+            .retain_mut(|subscriber| subscriber(&record));
+
+        if self.capacity == 0 {
+            return;
+        }
+        if self.history.len() == self.capacity {
+            self.history.records.pop_front();
+        }
+        self.history.records.push_back(record);
+    }
+
+    pub(crate) fn read_raw(&mut self, buf: &mut [u8]) -> usize {
+        let len = buf.len().min(self.raw.len());
+        for (dst, src) in buf.iter_mut().zip(self.raw.drain(..len)) {
+            *dst = src;
+        }
+        len
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use crate::runtime_log::{FileSink, LogStream, RuntimeLog};
+
+    #[test]
+    fn splits_lines() {
+        let mut log = RuntimeLog::default();
+        log.feed(LogStream::Stderr, b"hello\nwor");
+        log.feed(LogStream::Stderr, b"ld\r\npartial");
+
+        let lines: Vec<_> = log.records().map(|r| r.line.as_str()).collect();
+        assert_eq!(lines, ["hello", "world"]);
+
+        log.flush(LogStream::Stderr);
+        assert_eq!(log.records().last().unwrap().line, "partial");
+
+        let mut raw = [0; 64];
+        let read = log.read_raw(&mut raw);
+        assert_eq!(&raw[..read], b"hello\nworld\r\npartial");
+    }
+
+    #[test]
+    fn streams_kept_apart() {
+        let mut log = RuntimeLog::default();
+        log.feed(LogStream::Stdout, b"hel");
+        log.feed(LogStream::Stderr, b"oops\n");
+        log.feed(LogStream::Stdout, b"lo\n");
+
+        let lines: Vec<_> = log.records().map(|r| (r.stream, r.line.as_str())).collect();
+        assert_eq!(
+            lines,
+            [(LogStream::Stderr, "oops"), (LogStream::Stdout, "hello")]
+        );
+
+        let mut raw = [0; 64];
+        let read = log.read_raw(&mut raw);
+        assert_eq!(&raw[..read], b"oops\n");
+    }
+
+    #[test]
+    fn bounded_history() {
+        let mut log = RuntimeLog::default();
+        log.set_capacity(2);
+        log.feed(LogStream::Stderr, b"a\nb\n");
+        log.record(LogStream::Runtime, "c".into());
+
+        let lines: Vec<_> = log.records().map(|r| r.line.as_str()).collect();
+        assert_eq!(lines, ["b", "c"]);
+        assert_eq!(log.stream(LogStream::Runtime).count(), 1);
+
+        let history = log.history();
+        log.clear();
+        assert_eq!(history.len(), 2);
+    }
+
+    #[test]
+    fn sink_rotates() -> std::io::Result<()> {
+        let dir = std::env::temp_dir().join(format!("smce-rs-log-{}", std::process::id()));
+        fs::create_dir_all(&dir)?;
+        let path = dir.join("runtime.log");
+
+        let mut log = RuntimeLog::default();
+        log.set_sink(Some(FileSink::new(&path, 40, 2)?));
+        for i in 0..5 {
+            log.record(LogStream::Runtime, format!("line {}", i));
+        }
+
+        assert!(fs::read_to_string(&path)?.ends_with("line 4\n"));
+        assert!(fs::read_to_string(dir.join("runtime.log.1"))?.contains("line 3"));
+        assert!(dir.join("runtime.log.2").exists());
+        assert!(!dir.join("runtime.log.3").exists());
+
+        fs::remove_dir_all(&dir)
+    }
+}
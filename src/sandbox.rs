@@ -0,0 +1,262 @@
+/*
+ *  sandbox.rs
+ *  Copyright 2021 ItJustWorksTM
+ *
+ *  Licensed under the Apache License, Version 2.0 (the "License");
+ *  you may not use this file except in compliance with the License.
+ *  You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ *  Unless required by applicable law or agreed to in writing, software
+ *  distributed under the License is distributed on an "AS IS" BASIS,
+ *  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ *  See the License for the specific language governing permissions and
+ *  limitations under the License.
+ *
+ */
+
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::thread;
+
+use thiserror::Error;
+
+use crate::board_config::BoardConfig;
+use crate::sketch::Sketch;
+
+/// Opt-in confinement of the sketch process, Linux only.
+///
+/// File access is restricted with Landlock to the system libraries, the sketch, its own build
+/// directory and the runtime libraries of the SMCE home, the configured SD card root directories
+/// and the shared memory of its own board. Other sketches built in the same home are off limits.
+/// `/proc` is not readable unless added to `read_paths`. Unless `allow_network` is set, a
+/// seccomp filter blocks creating sockets and socket pairs of any kind and setting up io_uring,
+/// which could open them on the sketch's behalf. On x86_64 every x32 system call is refused.
+///
+/// The confinement is applied to the thread that asks libSMCE to spawn the sketch, so libSMCE's
+/// own start up code and any thread it spawns from there run confined as well. These only use
+/// the pipes and shared memory that are already open, but keep it in mind when extending them.
+#[derive(Debug, Clone, Default, Eq, Hash, PartialEq)]
+pub struct Sandbox {
+    pub allow_network: bool,
+    /// Extra paths the sketch may read from
+    pub read_paths: Vec<PathBuf>,
+    /// Extra paths the sketch may read from and write to
+    pub write_paths: Vec<PathBuf>,
+}
+
+#[derive(Error, Debug)]
+#[non_exhaustive]
+pub enum SandboxError {
+    #[error("Sandboxing is not supported on this platform")]
+    Unsupported,
+    #[error("Landlock is not supported by the running kernel")]
+    NotEnforced,
+    #[error("Failed to set up Landlock ruleset: {0}")]
+    Landlock(String),
+    #[error("Failed to install seccomp filter: {0}")]
+    Seccomp(String),
+}
+
+// Needed to load and run the sketch binary at all, missing ones are skipped
+const SYSTEM_READ_PATHS: &[&str] = &[
+    "/bin",
+    "/lib",
+    "/lib32",
+    "/lib64",
+    "/usr",
+    "/etc/ld.so.cache",
+    "/etc/localtime",
+    "/dev/random",
+    "/dev/urandom",
+    "/dev/zero",
+];
+
+const SYSTEM_WRITE_PATHS: &[&str] = &["/dev/null"];
+
+// Runtime libraries in the SMCE home that sketches are linked against. Every sketch is built
+// in the same home, so of the rest only the sketch's own build directory is readable.
+const RUNTIME_DIR: &str = "RtResources";
+
+// Where libSMCE keeps the POSIX shared memory of the boards
+const SHM_DIR: &str = "/dev/shm";
+
+// Shared memory segments of all boards, comparing before and after preparing a board
+// tells which one is its own
+pub(crate) fn shm_segments() -> BTreeSet<PathBuf> {
+    fs::read_dir(SHM_DIR)
+        .map(|entries| {
+            entries
+                .filter_map(|entry| Some(entry.ok()?.path()))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+// Sandbox resolved against a board config and sketch
+#[derive(Debug, Clone)]
+pub(crate) struct Confinement {
+    read: Vec<PathBuf>,
+    write: Vec<PathBuf>,
+    allow_network: bool,
+}
+
+impl Sandbox {
+    // The segments are the shared memory of this board, the only part of /dev/shm it may use
+    pub(crate) fn confinement(
+        &self,
+        config: &BoardConfig,
+        sketch: &Sketch,
+        segments: Vec<PathBuf>,
+    ) -> Confinement {
+        let source = sketch.source();
+        let sketch_dir = if source.is_dir() {
+            source
+        } else {
+            source.parent().unwrap_or(source)
+        };
+
+        let read = SYSTEM_READ_PATHS
+            .iter()
+            .map(PathBuf::from)
+            .chain(Some(sketch_dir.to_path_buf()))
+            .chain(
+                sketch
+                    .toolchain_home
+                    .as_ref()
+                    .map(|home| home.join(RUNTIME_DIR)),
+            )
+            .chain(
+                sketch
+                    .executable()
+                    .and_then(Path::parent)
+                    .map(Path::to_path_buf),
+            )
+            .chain(self.read_paths.iter().cloned())
+            .collect();
+
+        let write = SYSTEM_WRITE_PATHS
+            .iter()
+            .map(PathBuf::from)
+            .chain(segments)
+            .chain(config.sd_cards.iter().map(|sd| PathBuf::from(&sd.root_dir)))
+            .chain(self.write_paths.iter().cloned())
+            .collect();
+
+        Confinement {
+            read,
+            write,
+            allow_network: self.allow_network,
+        }
+    }
+}
+
+impl Confinement {
+    // Runs `f` on a fresh thread that is confined first, landlock and seccomp only apply to
+    // the calling thread and whatever it spawns, leaving the rest of the host untouched.
+    pub(crate) fn run<R: Send>(&self, f: impl FnOnce() -> R + Send) -> Result<R, SandboxError> {
+        thread::scope(|s| {
+            s.spawn(|| {
+                self.restrict_thread()?;
+                Ok(f())
+            })
+            .join()
+            .unwrap()
+        })
+    }
+
+    #[cfg(target_os = "linux")]
+    fn restrict_thread(&self) -> Result<(), SandboxError> {
+        use landlock::{
+            path_beneath_rules, Access, AccessFs, Ruleset, RulesetAttr, RulesetCreatedAttr,
+            RulesetStatus, ABI,
+        };
+
+        let abi = ABI::V5;
+        let landlock_err = |err: landlock::RulesetError| SandboxError::Landlock(err.to_string());
+
+        let status = Ruleset::default()
+            .handle_access(AccessFs::from_all(abi))
+            .map_err(landlock_err)?
+            .create()
+            .map_err(landlock_err)?
+            .add_rules(path_beneath_rules(&self.read, AccessFs::from_read(abi)))
+            .map_err(landlock_err)?
+            .add_rules(path_beneath_rules(&self.write, AccessFs::from_all(abi)))
+            .map_err(landlock_err)?
+            .restrict_self()
+            .map_err(landlock_err)?;
+
+        if status.ruleset == RulesetStatus::NotEnforced {
+            return Err(SandboxError::NotEnforced);
+        }
+
+        if !self.allow_network {
+            deny_network()?;
+        }
+
+        Ok(())
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn restrict_thread(&self) -> Result<(), SandboxError> {
+        Err(SandboxError::Unsupported)
+    }
+}
+
+// Set in the numbers of x32 system calls, which share the architecture of x86_64
+#[cfg(target_os = "linux")]
+const X32_SYSCALL_BIT: u32 = 0x4000_0000;
+
+// Fails creation of any socket with EACCES, and io_uring which could create them without going
+// through socket(2). Calls of other architectures, like socketcall(2) through the 32-bit entry
+// point, kill the process as the filter only allows the native one.
+#[cfg(target_os = "linux")]
+fn deny_network() -> Result<(), SandboxError> {
+    use std::collections::BTreeMap;
+    use std::convert::TryInto;
+
+    use seccompiler::{sock_filter, BpfProgram, SeccompAction, SeccompFilter};
+
+    let seccomp_err = |err: seccompiler::Error| SandboxError::Seccomp(err.to_string());
+    let backend_err = |err: seccompiler::BackendError| SandboxError::Seccomp(err.to_string());
+    let denied = libc::SECCOMP_RET_ERRNO | libc::EACCES as u32;
+
+    let filter = SeccompFilter::new(
+        // No rules match every call
+        BTreeMap::from([
+            (libc::SYS_socket, vec![]),
+            (libc::SYS_socketpair, vec![]),
+            (libc::SYS_io_uring_setup, vec![]),
+        ]),
+        SeccompAction::Allow,
+        SeccompAction::Errno(libc::EACCES as u32),
+        std::env::consts::ARCH.try_into().map_err(backend_err)?,
+    )
+    .map_err(backend_err)?;
+
+    let mut program: BpfProgram = filter.try_into().map_err(backend_err)?;
+    // The filter only compares whole system call numbers, so x32 ones would slip past it
+    if cfg!(target_arch = "x86_64") {
+        let instruction = |code: u32, jf: u8, k: u32| sock_filter {
+            code: code as u16,
+            jt: 0,
+            jf,
+            k,
+        };
+        let x32 = [
+            // Load the number, refuse the call when the bit is set and carry on otherwise
+            instruction(libc::BPF_LD | libc::BPF_W | libc::BPF_ABS, 0, 0),
+            instruction(
+                libc::BPF_JMP | libc::BPF_JGE | libc::BPF_K,
+                1,
+                X32_SYSCALL_BIT,
+            ),
+            instruction(libc::BPF_RET | libc::BPF_K, 0, denied),
+        ];
+        program.splice(0..0, x32);
+    }
+    seccompiler::apply_filter(&program).map_err(seccomp_err)
+}
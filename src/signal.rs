@@ -0,0 +1,349 @@
+/*
+ *  signal.rs
+ *  Copyright 2021 ItJustWorksTM
+ *
+ *  Licensed under the Apache License, Version 2.0 (the "License");
+ *  you may not use this file except in compliance with the License.
+ *  You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ *  Unless required by applicable law or agreed to in writing, software
+ *  distributed under the License is distributed on an "AS IS" BASIS,
+ *  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ *  See the License for the specific language governing permissions and
+ *  limitations under the License.
+ *
+ */
+
+use std::f64::consts::TAU;
+use std::ops::Add;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use thiserror::Error;
+
+use crate::board_view::BoardView;
+use crate::rng::Rng;
+use crate::worker::Workers;
+
+#[derive(Debug, Copy, Clone, Eq, Hash, PartialEq)]
+pub enum Waveform {
+    Sine,
+    Triangle,
+    Sawtooth,
+    // Jumps between both extremes every half period
+    Step,
+    // Wanders around within the amplitude, `frequency` sets how fast
+    RandomWalk,
+    // Gaussian with the amplitude as standard deviation, held for a period unless `frequency` is 0
+    Noise,
+}
+
+/// Single waveform around `offset`, values are in ADC counts.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Source {
+    pub waveform: Waveform,
+    pub amplitude: f64,
+    pub offset: f64,
+    // In Hz
+    pub frequency: f64,
+    // Only used by the random waveforms
+    pub seed: u64,
+}
+
+impl Source {
+    pub fn new(waveform: Waveform, amplitude: f64, frequency: f64) -> Self {
+        Source {
+            waveform,
+            amplitude,
+            offset: 0.0,
+            frequency,
+            seed: 0,
+        }
+    }
+
+    pub fn sine(amplitude: f64, frequency: f64) -> Self {
+        Self::new(Waveform::Sine, amplitude, frequency)
+    }
+
+    pub fn triangle(amplitude: f64, frequency: f64) -> Self {
+        Self::new(Waveform::Triangle, amplitude, frequency)
+    }
+
+    pub fn sawtooth(amplitude: f64, frequency: f64) -> Self {
+        Self::new(Waveform::Sawtooth, amplitude, frequency)
+    }
+
+    pub fn step(amplitude: f64, frequency: f64) -> Self {
+        Self::new(Waveform::Step, amplitude, frequency)
+    }
+
+    pub fn random_walk(amplitude: f64, frequency: f64) -> Self {
+        Self::new(Waveform::RandomWalk, amplitude, frequency)
+    }
+
+    pub fn noise(amplitude: f64) -> Self {
+        Self::new(Waveform::Noise, amplitude, 0.0)
+    }
+
+    pub fn with_offset(self, offset: f64) -> Self {
+        Source { offset, ..self }
+    }
+
+    pub fn with_frequency(self, frequency: f64) -> Self {
+        Source { frequency, ..self }
+    }
+
+    pub fn with_seed(self, seed: u64) -> Self {
+        Source { seed, ..self }
+    }
+}
+
+/// Sum of sources, such as a sine plus noise.
+///
+/// ```ignore
+/// let signal = Source::sine(200.0, 1.0).with_offset(512.0) + Source::noise(5.0).with_seed(42);
+/// ```
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Signal {
+    pub sources: Vec<Source>,
+}
+
+impl From<Source> for Signal {
+    fn from(source: Source) -> Self {
+        Signal {
+            sources: vec![source],
+        }
+    }
+}
+
+impl<S: Into<Signal>> Add<S> for Signal {
+    type Output = Signal;
+
+    fn add(mut self, rhs: S) -> Signal {
+        self.sources.extend(rhs.into().sources);
+        self
+    }
+}
+
+impl<S: Into<Signal>> Add<S> for Source {
+    type Output = Signal;
+
+    fn add(self, rhs: S) -> Signal {
+        Signal::from(self) + rhs
+    }
+}
+
+impl Signal {
+    // Evaluates the signal over time, clamped to 0..=max
+    pub fn sampler(&self, max: u16) -> Sampler {
+        Sampler {
+            states: self
+                .sources
+                .iter()
+                .map(|&source| SourceState {
+                    source,
+                    rng: Rng::new(source.seed),
+                    value: 0.0,
+                    last: None,
+                })
+                .collect(),
+            max,
+        }
+    }
+}
+
+struct SourceState {
+    source: Source,
+    rng: Rng,
+    // Position of the random walk or the held noise value
+    value: f64,
+    last: Option<Duration>,
+}
+
+impl SourceState {
+    fn sample(&mut self, t: Duration) -> f64 {
+        let Source {
+            waveform,
+            amplitude,
+            offset,
+            frequency,
+            ..
+        } = self.source;
+        let phase = (t.as_secs_f64() * frequency).fract();
+
+        let wave = match waveform {
+            Waveform::Sine => (TAU * phase).sin(),
+            Waveform::Triangle => 1.0 - 4.0 * (phase - 0.5).abs(),
+            Waveform::Sawtooth => 2.0 * phase - 1.0,
+            Waveform::Step => {
+                if phase < 0.5 {
+                    1.0
+                } else {
+                    -1.0
+                }
+            }
+            Waveform::RandomWalk => {
+                let dt = self
+                    .last
+                    .map_or(0.0, |last| t.saturating_sub(last).as_secs_f64());
+                let step = self.rng.next_gaussian() * (dt * frequency).sqrt();
+                self.value = (self.value + step).clamp(-1.0, 1.0);
+                self.value
+            }
+            Waveform::Noise => {
+                let period = (t.as_secs_f64() * frequency).floor();
+                let held = self
+                    .last
+                    .map(|last| (last.as_secs_f64() * frequency).floor())
+                    == Some(period);
+                if frequency <= 0.0 || !held {
+                    self.value = self.rng.next_gaussian();
+                }
+                self.value
+            }
+        };
+
+        self.last = Some(t);
+        offset + amplitude * wave
+    }
+}
+
+pub struct Sampler {
+    states: Vec<SourceState>,
+    max: u16,
+}
+
+impl Sampler {
+    // `t` is the time since the signal started, expected to only go forward
+    pub fn sample(&mut self, t: Duration) -> u16 {
+        let value: f64 = self.states.iter_mut().map(|state| state.sample(t)).sum();
+        value.round().clamp(0.0, self.max as f64) as u16
+    }
+}
+
+#[derive(Debug, Clone, Eq, Hash, PartialEq)]
+pub struct SignalConfig {
+    // Time between two writes to the pin
+    pub interval: Duration,
+    // Highest value of the ADC, 1023 for the default 10 bit resolution
+    pub max: u16,
+}
+
+impl Default for SignalConfig {
+    fn default() -> Self {
+        SignalConfig {
+            interval: Duration::from_millis(1),
+            max: 1023,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Error, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum SignalError {
+    #[error("Pin {0} is not part of the board")]
+    UnknownPin(usize),
+    #[error("Pin {0} can not be read by the sketch")]
+    NotAnInput(usize),
+}
+
+/// Signal being written to a pin on a background thread.
+///
+/// Ends once the sketch exits or the board is stopped.
+pub struct SignalDriver {
+    stop: Arc<AtomicBool>,
+    done: Receiver<()>,
+}
+
+impl SignalDriver {
+    pub fn stop(self) {
+        self.stop.store(true, Ordering::SeqCst);
+        let _ = self.done.recv();
+    }
+}
+
+pub(crate) fn spawn_driver(
+    workers: &Workers,
+    view: &BoardView,
+    pin: usize,
+    signal: &Signal,
+    config: SignalConfig,
+) -> Result<SignalDriver, SignalError> {
+    let gpio = view.pins.get(pin).ok_or(SignalError::UnknownPin(pin))?;
+    if !gpio.info().allow_read {
+        return Err(SignalError::NotAnInput(pin));
+    }
+    let gpio = gpio.duplicate();
+    let mut sampler = signal.sampler(config.max);
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let stopped = stop.clone();
+    let (done_tx, done) = mpsc::channel();
+
+    workers.spawn(move |liveness| {
+        let start = Instant::now();
+        while liveness.alive() && !stopped.load(Ordering::SeqCst) {
+            gpio.analog_write(sampler.sample(start.elapsed()));
+            thread::sleep(config.interval);
+        }
+        let _ = done_tx.send(());
+    });
+
+    Ok(SignalDriver { stop, done })
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use crate::signal::{Signal, Source};
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn periodic_waveforms() {
+        let mut sine = Signal::from(Source::sine(100.0, 1.0).with_offset(500.0)).sampler(1023);
+        assert_eq!(sine.sample(ms(0)), 500);
+        assert_eq!(sine.sample(ms(250)), 600);
+        assert_eq!(sine.sample(ms(750)), 400);
+
+        let mut triangle =
+            Signal::from(Source::triangle(100.0, 10.0).with_offset(100.0)).sampler(1023);
+        assert_eq!(triangle.sample(ms(0)), 0);
+        assert_eq!(triangle.sample(ms(25)), 100);
+        assert_eq!(triangle.sample(ms(50)), 200);
+
+        let mut saw = Signal::from(Source::sawtooth(10.0, 1.0).with_offset(10.0)).sampler(1023);
+        assert_eq!(saw.sample(ms(500)), 10);
+        assert_eq!(saw.sample(ms(999)), 20);
+
+        let mut step = Signal::from(Source::step(50.0, 1.0).with_offset(50.0)).sampler(1023);
+        assert_eq!(step.sample(ms(100)), 100);
+        assert_eq!(step.sample(ms(600)), 0);
+    }
+
+    #[test]
+    fn combined_and_clamped() {
+        let signal = Source::sine(1000.0, 1.0).with_offset(512.0) + Source::noise(3.0).with_seed(7);
+        let mut a = signal.sampler(1023);
+        let mut b = signal.sampler(1023);
+
+        let samples: Vec<_> = (0..1000).map(|i| a.sample(ms(i))).collect();
+        assert_eq!(
+            samples,
+            (0..1000).map(|i| b.sample(ms(i))).collect::<Vec<_>>()
+        );
+        assert_eq!(*samples.iter().max().unwrap(), 1023);
+        assert_eq!(*samples.iter().min().unwrap(), 0);
+
+        let mut walk =
+            Signal::from(Source::random_walk(100.0, 50.0).with_offset(300.0)).sampler(1023);
+        assert!((0..1000).all(|i| (200..=400).contains(&walk.sample(ms(i)))));
+    }
+}
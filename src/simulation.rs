@@ -0,0 +1,255 @@
+/*
+ *  simulation.rs
+ *  Copyright 2021 ItJustWorksTM
+ *
+ *  Licensed under the Apache License, Version 2.0 (the "License");
+ *  you may not use this file except in compliance with the License.
+ *  You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ *  Unless required by applicable law or agreed to in writing, software
+ *  distributed under the License is distributed on an "AS IS" BASIS,
+ *  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ *  See the License for the specific language governing permissions and
+ *  limitations under the License.
+ *
+ */
+
+use std::any::Any;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use thiserror::Error;
+
+use crate::board::{BoardHandle, ExitStatus};
+use crate::board_view::{BoardView, GpioPin};
+
+#[derive(Error, Debug, Clone, Eq, Hash, PartialEq)]
+pub enum PeripheralError {
+    #[error("Pin {0} is not part of the board")]
+    MissingPin(usize),
+    #[error("Pin {0} can not be read by the sketch")]
+    NotAnInput(usize),
+    #[error("Pin {0} can not be written by the sketch")]
+    NotAnOutput(usize),
+    #[error("Uart channel {0} is not part of the board")]
+    MissingUartChannel(usize),
+    #[error("{0}")]
+    Custom(String),
+}
+
+// Looks up a pin peripherals depend on
+pub fn require_pin(view: &BoardView, pin: usize) -> Result<&GpioPin, PeripheralError> {
+    view.pins.get(pin).ok_or(PeripheralError::MissingPin(pin))
+}
+
+// Looks up a pin the peripheral drives and the sketch reads
+pub fn require_input(view: &BoardView, pin: usize) -> Result<&GpioPin, PeripheralError> {
+    let gpio = require_pin(view, pin)?;
+    if gpio.info().allow_read {
+        Ok(gpio)
+    } else {
+        Err(PeripheralError::NotAnInput(pin))
+    }
+}
+
+// Looks up a pin the sketch drives and the peripheral reads
+pub fn require_output(view: &BoardView, pin: usize) -> Result<&GpioPin, PeripheralError> {
+    let gpio = require_pin(view, pin)?;
+    if gpio.info().allow_write {
+        Ok(gpio)
+    } else {
+        Err(PeripheralError::NotAnOutput(pin))
+    }
+}
+
+/// Simulated hardware connected to the board, driven by a [`Simulation`].
+pub trait Peripheral {
+    // Called once when added, should check the board has everything it needs
+    fn attach(&mut self, view: &BoardView) -> Result<(), PeripheralError>;
+
+    // Advances the peripheral by `dt`, the time since its previous step
+    fn step(&mut self, view: &BoardView, dt: Duration);
+}
+
+// Lets us hand out added peripherals by their concrete type
+trait AnyPeripheral: Peripheral {
+    fn as_any(&self) -> &dyn Any;
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+}
+
+impl<T: Peripheral + 'static> AnyPeripheral for T {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct PeripheralId(usize);
+
+/// Steps a set of peripherals at a fixed rate while the sketch runs.
+pub struct Simulation<'a> {
+    handle: BoardHandle<'a>,
+    peripherals: Vec<Box<dyn AnyPeripheral>>,
+    interval: Duration,
+    last_step: Option<Instant>,
+}
+
+impl<'a> Simulation<'a> {
+    pub fn new(handle: BoardHandle<'a>, interval: Duration) -> Self {
+        Simulation {
+            handle,
+            peripherals: vec![],
+            interval,
+            last_step: None,
+        }
+    }
+
+    pub fn add<P: Peripheral + 'static>(
+        &mut self,
+        mut peripheral: P,
+    ) -> Result<PeripheralId, PeripheralError> {
+        peripheral.attach(self.handle.view())?;
+        self.peripherals.push(Box::new(peripheral));
+        Ok(PeripheralId(self.peripherals.len() - 1))
+    }
+
+    pub fn peripheral<P: Peripheral + 'static>(&self, id: PeripheralId) -> Option<&P> {
+        self.peripherals.get(id.0)?.as_any().downcast_ref()
+    }
+
+    pub fn peripheral_mut<P: Peripheral + 'static>(&mut self, id: PeripheralId) -> Option<&mut P> {
+        self.peripherals.get_mut(id.0)?.as_any_mut().downcast_mut()
+    }
+
+    pub fn handle(&self) -> &BoardHandle<'a> {
+        &self.handle
+    }
+
+    pub fn view(&self) -> &BoardView {
+        self.handle.view()
+    }
+
+    pub fn into_handle(self) -> BoardHandle<'a> {
+        self.handle
+    }
+
+    // Waits for the next step to be due, then ticks the board and steps every peripheral.
+    // Fails with the exit status once the sketch has exited.
+    pub fn step(&mut self) -> Result<(), ExitStatus> {
+        let now = match self.last_step {
+            Some(last) => {
+                let due = last + self.interval;
+                let now = Instant::now();
+                if now < due {
+                    thread::sleep(due - now);
+                }
+                Instant::now()
+            }
+            None => Instant::now(),
+        };
+        let dt = self.last_step.map_or(Duration::ZERO, |last| now - last);
+        self.last_step = Some(now);
+
+        if let Err(exit_code) = self.handle.tick() {
+            return Err(self
+                .handle
+                .exit_status()
+                .unwrap_or(ExitStatus::Exited(exit_code)));
+        }
+
+        let view = self.handle.view();
+        for peripheral in &mut self.peripherals {
+            peripheral.step(view, dt);
+        }
+        Ok(())
+    }
+
+    pub fn run_for(&mut self, duration: Duration) -> Result<(), ExitStatus> {
+        let deadline = Instant::now() + duration;
+        while Instant::now() < deadline {
+            self.step()?;
+        }
+        Ok(())
+    }
+
+    // Steps until the predicate holds, returns whether it did before the timeout
+    pub fn run_until(
+        &mut self,
+        mut predicate: impl FnMut(&Self) -> bool,
+        timeout: Duration,
+    ) -> Result<bool, ExitStatus> {
+        let deadline = Instant::now() + timeout;
+        while Instant::now() < deadline {
+            self.step()?;
+            if predicate(self) {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+}
+
+/// LED on an output pin of the sketch, brightness follows `analogWrite`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Led {
+    pin: usize,
+    on: bool,
+    brightness: u16,
+    toggles: usize,
+    on_time: Duration,
+}
+
+impl Led {
+    pub fn new(pin: usize) -> Self {
+        Led {
+            pin,
+            ..Default::default()
+        }
+    }
+
+    pub fn is_on(&self) -> bool {
+        self.on
+    }
+
+    // Last analog value written to the pin
+    pub fn brightness(&self) -> u16 {
+        self.brightness
+    }
+
+    pub fn toggles(&self) -> usize {
+        self.toggles
+    }
+
+    // Total time the LED has been on
+    pub fn on_time(&self) -> Duration {
+        self.on_time
+    }
+}
+
+impl Peripheral for Led {
+    fn attach(&mut self, view: &BoardView) -> Result<(), PeripheralError> {
+        let pin = require_output(view, self.pin)?;
+        self.on = pin.digital_read();
+        self.brightness = pin.analog_read();
+        Ok(())
+    }
+
+    fn step(&mut self, view: &BoardView, dt: Duration) {
+        if self.on {
+            self.on_time += dt;
+        }
+        let pin = &view.pins[self.pin];
+        let on = pin.digital_read();
+        if on != self.on {
+            self.toggles += 1;
+            self.on = on;
+        }
+        self.brightness = pin.analog_read();
+    }
+}
@@ -17,7 +17,7 @@
  */
 
 use std::fmt::{Debug, Formatter};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::{ffi::OsStr, fmt};
 
 use cxx::UniquePtr;
@@ -29,6 +29,8 @@ use crate::sketch_config::SketchConfig;
 pub struct Sketch {
     pub(crate) internal: UniquePtr<OpaqueSketch>,
     pub(crate) config: SketchConfig,
+    // Set once compiled, the build artifacts live in here
+    pub(crate) toolchain_home: Option<PathBuf>,
 }
 
 impl Sketch {
@@ -37,6 +39,7 @@ impl Sketch {
         source.as_ref().to_str().map(|source| Sketch {
             internal: unsafe { sketch_new(source, config.as_opaque().as_ref().unwrap()) },
             config,
+            toolchain_home: None,
         })
     }
 
@@ -44,6 +47,14 @@ impl Sketch {
         Path::new(unsafe { self.internal.get_source() })
     }
 
+    // Binary the toolchain built, in a build directory of its own
+    pub(crate) fn executable(&self) -> Option<&Path> {
+        if !self.compiled() {
+            return None;
+        }
+        Some(Path::new(unsafe { self.internal.get_executable() }))
+    }
+
     pub fn compiled(&self) -> bool {
         unsafe { self.internal.is_compiled() }
     }
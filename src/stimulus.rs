@@ -0,0 +1,374 @@
+/*
+ *  stimulus.rs
+ *  Copyright 2021 ItJustWorksTM
+ *
+ *  Licensed under the Apache License, Version 2.0 (the "License");
+ *  you may not use this file except in compliance with the License.
+ *  You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ *  Unless required by applicable law or agreed to in writing, software
+ *  distributed under the License is distributed on an "AS IS" BASIS,
+ *  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ *  See the License for the specific language governing permissions and
+ *  limitations under the License.
+ *
+ */
+
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::io::{self, Read};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use thiserror::Error;
+
+use crate::board_view::{BoardView, GpioPin};
+use crate::vcd::{self, VcdValue};
+use crate::worker::Workers;
+
+#[derive(Debug, Copy, Clone, Eq, Hash, PartialEq)]
+pub enum Level {
+    Digital(bool),
+    Analog(u16),
+}
+
+#[derive(Debug, Copy, Clone, Eq, Hash, PartialEq)]
+pub struct Step {
+    // Relative to the start of the playback
+    pub at: Duration,
+    pub pin: usize,
+    pub level: Level,
+}
+
+#[derive(Error, Debug)]
+pub enum StimulusError {
+    #[error("Failed to read schedule")]
+    Io(#[from] io::Error),
+    #[error("Invalid schedule on line {line}: {reason}")]
+    Csv { line: usize, reason: String },
+    #[error("Invalid schedule: {0}")]
+    Vcd(String),
+    #[error("Pin {0} is not part of the board")]
+    UnknownPin(usize),
+    #[error("Pin {0} can not be read by the sketch")]
+    NotAnInput(usize),
+}
+
+/// Time-stamped levels to drive pins with, kept in chronological order.
+///
+/// ```text
+/// # time_ms, pin, digital|analog, value
+/// 0, 0, digital, 1
+/// 250.5, 0, digital, 0
+/// 300, 1, analog, 512
+/// ```
+#[derive(Debug, Clone, Default, Eq, Hash, PartialEq)]
+pub struct Schedule {
+    steps: Vec<Step>,
+}
+
+impl Schedule {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(mut self, at: Duration, pin: usize, level: Level) -> Self {
+        let index = self.steps.partition_point(|step| step.at <= at);
+        self.steps.insert(index, Step { at, pin, level });
+        self
+    }
+
+    pub fn steps(&self) -> &[Step] {
+        &self.steps
+    }
+
+    pub fn duration(&self) -> Duration {
+        self.steps.last().map_or(Duration::ZERO, |step| step.at)
+    }
+
+    // Steps of both schedules interleaved, on equal times ours go first
+    pub fn merge(self, other: Schedule) -> Self {
+        other.steps.into_iter().fold(self, |schedule, step| {
+            schedule.push(step.at, step.pin, step.level)
+        })
+    }
+
+    pub fn delay(mut self, by: Duration) -> Self {
+        for step in &mut self.steps {
+            step.at += by;
+        }
+        self
+    }
+
+    // Plays the schedule `times` times in a row, each repetition starting after `period`
+    pub fn repeat(self, times: u32, period: Duration) -> Self {
+        (1..times).fold(self.clone(), |schedule, i| {
+            schedule.merge(self.clone().delay(period * i))
+        })
+    }
+
+    // `count` pulses going high for `width` every `period`, starting high
+    pub fn pulses(pin: usize, width: Duration, period: Duration, count: u32) -> Self {
+        (0..count).fold(Schedule::new(), |schedule, i| {
+            schedule.push(period * i, pin, Level::Digital(true)).push(
+                period * i + width,
+                pin,
+                Level::Digital(false),
+            )
+        })
+    }
+
+    // Square wave with a 50% duty cycle
+    pub fn square(pin: usize, period: Duration, cycles: u32) -> Self {
+        Self::pulses(pin, period / 2, period, cycles)
+    }
+
+    // Analog values going from `from` to `to` in `steps` equal steps
+    pub fn ramp(pin: usize, from: u16, to: u16, duration: Duration, steps: u32) -> Self {
+        let steps = steps.max(1);
+        (0..=steps).fold(Schedule::new(), |schedule, i| {
+            let value = from as i64 + (to as i64 - from as i64) * i as i64 / steps as i64;
+            schedule.push(duration * i / steps, pin, Level::Analog(value as u16))
+        })
+    }
+
+    // Lines of `time_ms, pin, digital|analog, value`, empty lines and # comments are skipped
+    pub fn from_csv<R: Read>(mut reader: R) -> Result<Self, StimulusError> {
+        let mut input = String::new();
+        reader.read_to_string(&mut input)?;
+
+        let mut schedule = Schedule::new();
+        for (i, line) in input.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let err = |reason: &str| StimulusError::Csv {
+                line: i + 1,
+                reason: reason.into(),
+            };
+
+            let fields: Vec<_> = line.split(',').map(str::trim).collect();
+            let (time, pin, kind, value) = match fields.as_slice() {
+                [time, pin, kind, value] => (time, pin, kind, value),
+                _ => return Err(err("expected 4 fields")),
+            };
+
+            let at = time
+                .parse::<f64>()
+                .ok()
+                .and_then(|time| Duration::try_from_secs_f64(time / 1000.0).ok())
+                .ok_or_else(|| err("invalid time"))?;
+            let pin = pin.parse().map_err(|_| err("invalid pin"))?;
+            let level = match *kind {
+                "digital" | "d" => match *value {
+                    "1" | "high" => Level::Digital(true),
+                    "0" | "low" => Level::Digital(false),
+                    _ => return Err(err("invalid digital value")),
+                },
+                "analog" | "a" => {
+                    Level::Analog(value.parse().map_err(|_| err("invalid analog value"))?)
+                }
+                _ => return Err(err("expected digital or analog")),
+            };
+            schedule = schedule.push(at, pin, level);
+        }
+        Ok(schedule)
+    }
+
+    // Wires named like the ones VCD recordings write, `pin<id>` and `pin<id>_analog`
+    pub fn from_vcd<R: Read>(mut reader: R) -> Result<Self, StimulusError> {
+        let mut input = String::new();
+        reader.read_to_string(&mut input)?;
+
+        let mut schedule = Schedule::new();
+        for change in vcd::parse(&input).map_err(StimulusError::Vcd)? {
+            let name = change.name.strip_prefix("pin");
+            let (pin, analog) = match name.map(|name| name.strip_suffix("_analog").ok_or(name)) {
+                Some(Ok(pin)) => (pin, true),
+                Some(Err(pin)) => (pin, false),
+                None => continue,
+            };
+            let pin = match pin.parse() {
+                Ok(pin) => pin,
+                Err(_) => continue,
+            };
+            let level = match (change.value, analog) {
+                (VcdValue::Bit(bit), false) => Level::Digital(bit),
+                (VcdValue::Vector(vec), true) => Level::Analog(vec),
+                _ => continue,
+            };
+            schedule = schedule.push(change.time, pin, level);
+        }
+        Ok(schedule)
+    }
+}
+
+/// Schedule being played onto the pins on a background thread.
+///
+/// Ends on its own after the last step, or once the sketch exits or the board is stopped.
+pub struct Playback {
+    stop: Arc<AtomicBool>,
+    done: Receiver<()>,
+}
+
+impl Playback {
+    // Blocks until the last step has been played
+    pub fn wait(self) {
+        let _ = self.done.recv();
+    }
+
+    pub fn stop(self) {
+        self.stop.store(true, Ordering::SeqCst);
+        self.wait();
+    }
+}
+
+pub(crate) fn spawn_playback(
+    workers: &Workers,
+    view: &BoardView,
+    schedule: Schedule,
+) -> Result<Playback, StimulusError> {
+    let mut pins = HashMap::new();
+    for step in &schedule.steps {
+        if let Entry::Vacant(entry) = pins.entry(step.pin) {
+            let pin = view
+                .pins
+                .get(step.pin)
+                .ok_or(StimulusError::UnknownPin(step.pin))?;
+            // Whatever the sketch drives would overwrite the schedule, or the other way around
+            if !pin.info().allow_read {
+                return Err(StimulusError::NotAnInput(step.pin));
+            }
+            entry.insert(pin.duplicate());
+        }
+    }
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let stopped = stop.clone();
+    let (done_tx, done) = mpsc::channel();
+
+    workers.spawn(move |liveness| {
+        let start = Instant::now();
+        let running = || liveness.alive() && !stopped.load(Ordering::SeqCst);
+
+        for step in schedule.steps {
+            // Sleep in slices so we notice when to bail out
+            loop {
+                if !running() {
+                    let _ = done_tx.send(());
+                    return;
+                }
+                let elapsed = start.elapsed();
+                if elapsed >= step.at {
+                    break;
+                }
+                thread::sleep((step.at - elapsed).min(Duration::from_millis(10)));
+            }
+
+            let pin: &GpioPin = &pins[&step.pin];
+            match step.level {
+                Level::Digital(val) => pin.digital_write(val),
+                Level::Analog(val) => pin.analog_write(val),
+            }
+        }
+        let _ = done_tx.send(());
+    });
+
+    Ok(Playback { stop, done })
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use crate::stimulus::{Level, Schedule, Step, StimulusError};
+
+    #[test]
+    fn builders() {
+        let ms = Duration::from_millis;
+        let square = Schedule::square(0, ms(100), 2);
+        let levels: Vec<_> = square.steps().iter().map(|s| (s.at, s.level)).collect();
+        assert_eq!(
+            levels,
+            [
+                (ms(0), Level::Digital(true)),
+                (ms(50), Level::Digital(false)),
+                (ms(100), Level::Digital(true)),
+                (ms(150), Level::Digital(false)),
+            ]
+        );
+
+        let ramp = Schedule::ramp(1, 1000, 0, ms(40), 4);
+        let values: Vec<_> = ramp.steps().iter().map(|s| s.level).collect();
+        assert_eq!(values[0], Level::Analog(1000));
+        assert_eq!(values[2], Level::Analog(500));
+        assert_eq!(ramp.duration(), ms(40));
+
+        let merged = square.merge(ramp).repeat(2, ms(200));
+        assert_eq!(merged.steps().len(), 18);
+        assert!(merged.steps().windows(2).all(|w| w[0].at <= w[1].at));
+    }
+
+    #[test]
+    fn loads_csv() {
+        let csv =
+            "# time_ms, pin, kind, value\n0, 0, digital, 1\n\n2.5,3,analog,512\n1, 0, d, low\n";
+        let schedule = Schedule::from_csv(csv.as_bytes()).unwrap();
+        assert_eq!(
+            schedule.steps(),
+            [
+                Step {
+                    at: Duration::ZERO,
+                    pin: 0,
+                    level: Level::Digital(true)
+                },
+                Step {
+                    at: Duration::from_millis(1),
+                    pin: 0,
+                    level: Level::Digital(false)
+                },
+                Step {
+                    at: Duration::from_micros(2500),
+                    pin: 3,
+                    level: Level::Analog(512)
+                },
+            ]
+        );
+
+        assert!(Schedule::from_csv("0, 0, digital, 2".as_bytes()).is_err());
+        // Too far out for a Duration, or before the start
+        for time in ["1e300", "-1", "inf", "NaN"] {
+            let csv = format!("{}, 0, digital, 1", time);
+            assert!(matches!(
+                Schedule::from_csv(csv.as_bytes()),
+                Err(StimulusError::Csv { line: 1, .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn loads_vcd() {
+        let vcd = "$timescale 1ms $end $var wire 1 ! pin4 $end $var wire 16 \" pin4_analog $end \
+                   $var wire 1 # clk $end $enddefinitions $end #0 0! 1# #10 1! b11 \"";
+        let schedule = Schedule::from_vcd(vcd.as_bytes()).unwrap();
+        let levels: Vec<_> = schedule
+            .steps()
+            .iter()
+            .map(|s| (s.at, s.pin, s.level))
+            .collect();
+        assert_eq!(
+            levels,
+            [
+                (Duration::ZERO, 4, Level::Digital(false)),
+                (Duration::from_millis(10), 4, Level::Digital(true)),
+                (Duration::from_millis(10), 4, Level::Analog(3)),
+            ]
+        );
+    }
+}
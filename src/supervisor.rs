@@ -0,0 +1,273 @@
+/*
+ *  supervisor.rs
+ *  Copyright 2021 ItJustWorksTM
+ *
+ *  Licensed under the Apache License, Version 2.0 (the "License");
+ *  you may not use this file except in compliance with the License.
+ *  You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ *  Unless required by applicable law or agreed to in writing, software
+ *  distributed under the License is distributed on an "AS IS" BASIS,
+ *  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ *  See the License for the specific language governing permissions and
+ *  limitations under the License.
+ *
+ */
+
+use std::io::Read;
+use std::mem;
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::board::{Board, BoardError, BoardHandle, ExitStatus};
+use crate::board_config::BoardConfig;
+use crate::board_view::BoardView;
+use crate::sketch::Sketch;
+
+// Shortest wait before a restart, keeps a sketch that exits right away from spinning
+pub const MIN_RESTART_DELAY: Duration = Duration::from_millis(100);
+
+// How long a sketch has to run before its earlier failures are forgiven, by default
+pub const HEALTHY_UPTIME: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Eq, Hash, PartialEq)]
+pub enum RestartPolicy {
+    Never,
+    // Restarts after MIN_RESTART_DELAY, whatever the exit status
+    Always,
+    OnFailure(Backoff),
+}
+
+// Waits `initial * 2^retry` before restarting, capped at `max`. Retries only count failures
+// in a row, see Supervisor::set_healthy_uptime
+#[derive(Debug, Clone, Eq, Hash, PartialEq)]
+pub struct Backoff {
+    pub initial: Duration,
+    pub max: Duration,
+    pub max_retries: u32,
+}
+
+impl Backoff {
+    pub fn delay(&self, retry: u32) -> Duration {
+        1u32.checked_shl(retry)
+            .and_then(|factor| self.initial.checked_mul(factor))
+            .map_or(self.max, |delay| delay.min(self.max))
+    }
+}
+
+#[derive(Debug, Clone, Eq, Hash, PartialEq)]
+pub struct Restart {
+    // Of the restarts in a row without a healthy run in between
+    pub attempt: u32,
+    pub exit_status: ExitStatus,
+    pub exited_at: SystemTime,
+    // How long the sketch ran before it exited
+    pub uptime: Duration,
+}
+
+#[derive(Debug, Copy, Clone, Eq, Hash, PartialEq)]
+pub enum Supervision {
+    Running,
+    // The sketch exited and will be restarted once the backoff has passed
+    BackingOff(Duration),
+    Restarted,
+    // The sketch exited and the policy does not allow another restart
+    GaveUp(ExitStatus),
+}
+
+enum State {
+    Running(Instant),
+    BackingOff(Instant),
+    GaveUp(ExitStatus),
+}
+
+/// Keeps a sketch running by restarting it according to a [`RestartPolicy`].
+///
+/// Every restart prepares the board again with the same config. The [`BoardView`] stays the same
+/// and points at the restarted sketch, host bytes the old sketch did not read yet are passed on.
+/// Event streams carry on with the restarted sketch. Meters, recordings, playbacks, signal
+/// drivers and bridges end with the sketch they were started for.
+pub struct Supervisor<'a> {
+    board: &'a mut Board,
+    config: BoardConfig,
+    sketch: &'a Sketch,
+    policy: RestartPolicy,
+    preserve_uart: bool,
+    uart_backlog: Vec<Vec<u8>>,
+    healthy_uptime: Duration,
+    // Exits since the sketch last ran for at least healthy_uptime
+    failures: u32,
+    history: Vec<Restart>,
+    state: State,
+}
+
+impl<'a> Supervisor<'a> {
+    pub fn start(
+        board: &'a mut Board,
+        config: BoardConfig,
+        sketch: &'a Sketch,
+        policy: RestartPolicy,
+    ) -> Result<Self, BoardError> {
+        if !board.prepare(&config, sketch)?.start() {
+            board.handle().unwrap().stop();
+            return Err(BoardError::StartFailed);
+        }
+
+        Ok(Supervisor {
+            board,
+            uart_backlog: vec![vec![]; config.uart_channels.len()],
+            config,
+            sketch,
+            policy,
+            preserve_uart: false,
+            healthy_uptime: HEALTHY_UPTIME,
+            failures: 0,
+            history: vec![],
+            state: State::Running(Instant::now()),
+        })
+    }
+
+    // Keep sketch output that has not been read yet across restarts, see uart_backlog()
+    pub fn set_preserve_uart(&mut self, preserve: bool) {
+        self.preserve_uart = preserve;
+    }
+
+    // A sketch running at least this long starts over with a fresh backoff and retries
+    pub fn set_healthy_uptime(&mut self, uptime: Duration) {
+        self.healthy_uptime = uptime;
+    }
+
+    // Sketch output of a channel that was left unread when the sketch got restarted, None if
+    // the board has no such channel
+    pub fn uart_backlog(&mut self, channel: usize) -> Option<Vec<u8>> {
+        self.uart_backlog.get_mut(channel).map(mem::take)
+    }
+
+    pub fn view(&self) -> &BoardView {
+        self.board.view().unwrap()
+    }
+
+    pub fn handle(&mut self) -> BoardHandle<'_> {
+        self.board.handle().unwrap()
+    }
+
+    pub fn history(&self) -> &[Restart] {
+        &self.history
+    }
+
+    // Checks on the sketch, restarting it when the policy says so and the backoff has passed
+    pub fn tick(&mut self) -> Result<Supervision, BoardError> {
+        match self.state {
+            State::Running(since) => {
+                let handle = self.board.handle().unwrap();
+                let exit_status = match handle.tick() {
+                    Ok(()) => return Ok(Supervision::Running),
+                    Err(exit_code) => handle
+                        .exit_status()
+                        .unwrap_or(ExitStatus::Exited(exit_code)),
+                };
+
+                let uptime = since.elapsed();
+                if uptime >= self.healthy_uptime {
+                    self.failures = 0;
+                }
+                let attempt = self.failures;
+                let delay = match &self.policy {
+                    RestartPolicy::Always => Some(MIN_RESTART_DELAY),
+                    RestartPolicy::OnFailure(backoff)
+                        if exit_status != ExitStatus::Exited(0)
+                            && attempt < backoff.max_retries =>
+                    {
+                        Some(backoff.delay(attempt))
+                    }
+                    _ => None,
+                };
+
+                match delay {
+                    Some(delay) => {
+                        self.failures += 1;
+                        self.history.push(Restart {
+                            attempt: self.failures,
+                            exit_status,
+                            exited_at: SystemTime::now(),
+                            uptime,
+                        });
+                        self.state = State::BackingOff(Instant::now() + delay);
+                        self.tick()
+                    }
+                    None => {
+                        self.state = State::GaveUp(exit_status);
+                        Ok(Supervision::GaveUp(exit_status))
+                    }
+                }
+            }
+            State::BackingOff(until) => {
+                let now = Instant::now();
+                if now < until {
+                    return Ok(Supervision::BackingOff(until - now));
+                }
+                self.restart()?;
+                Ok(Supervision::Restarted)
+            }
+            State::GaveUp(exit_status) => Ok(Supervision::GaveUp(exit_status)),
+        }
+    }
+
+    fn restart(&mut self) -> Result<(), BoardError> {
+        if self.preserve_uart {
+            let mut buf = [0; 256];
+            let channels = &self.board.view().unwrap().uart_channels;
+            for (mut channel, backlog) in channels.iter().zip(self.uart_backlog.iter_mut()) {
+                loop {
+                    match channel.read(&mut buf) {
+                        Ok(read) if read > 0 => backlog.extend_from_slice(&buf[..read]),
+                        _ => break,
+                    }
+                }
+            }
+        }
+
+        // Should preparing or starting fail there is nothing left to restart
+        self.state = State::GaveUp(self.history.last().unwrap().exit_status);
+        self.board.prepare_again(&self.config, self.sketch)?;
+        if !self.board.handle().unwrap().start() {
+            return Err(BoardError::StartFailed);
+        }
+        self.state = State::Running(Instant::now());
+        Ok(())
+    }
+
+    pub fn stop(self) -> ExitStatus {
+        let exit_code = match self.board.handle() {
+            Some(handle) => handle.stop(),
+            None => 0,
+        };
+        match self.state {
+            State::GaveUp(exit_status) => exit_status,
+            _ => ExitStatus::Exited(exit_code),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use crate::supervisor::Backoff;
+
+    #[test]
+    fn backoff_doubles_until_max() {
+        let backoff = Backoff {
+            initial: Duration::from_millis(100),
+            max: Duration::from_secs(1),
+            max_retries: 10,
+        };
+
+        assert_eq!(backoff.delay(0), Duration::from_millis(100));
+        assert_eq!(backoff.delay(1), Duration::from_millis(200));
+        assert_eq!(backoff.delay(3), Duration::from_millis(800));
+        assert_eq!(backoff.delay(4), Duration::from_secs(1));
+        assert_eq!(backoff.delay(64), Duration::from_secs(1));
+    }
+}
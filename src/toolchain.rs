@@ -179,6 +179,7 @@ impl Toolchain {
         .into();
 
         self.internal.finished.store(true, Ordering::SeqCst);
+        sketch.toolchain_home = Some(self.home_dir);
 
         ret
     }
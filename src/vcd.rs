@@ -0,0 +1,440 @@
+/*
+ *  vcd.rs
+ *  Copyright 2021 ItJustWorksTM
+ *
+ *  Licensed under the Apache License, Version 2.0 (the "License");
+ *  you may not use this file except in compliance with the License.
+ *  You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ *  Unless required by applicable law or agreed to in writing, software
+ *  distributed under the License is distributed on an "AS IS" BASIS,
+ *  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ *  See the License for the specific language governing permissions and
+ *  limitations under the License.
+ *
+ */
+
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::board_view::{BoardView, GpioPin, HostWrite};
+use crate::worker::Workers;
+
+#[derive(Debug, Clone, Eq, Hash, PartialEq)]
+pub struct VcdConfig {
+    // Time between two samples of the pins, host writes are recorded as they happen
+    pub interval: Duration,
+    // Also record the analog value of every pin as a 16 bit vector
+    pub analog: bool,
+}
+
+impl Default for VcdConfig {
+    fn default() -> Self {
+        VcdConfig {
+            interval: Duration::from_millis(1),
+            analog: true,
+        }
+    }
+}
+
+/// Pin timeline being written to a Value Change Dump file.
+///
+/// Recording ends on its own once the sketch exits or the board is stopped.
+pub struct VcdRecording {
+    stop: Arc<AtomicBool>,
+    done: Receiver<io::Result<()>>,
+}
+
+impl VcdRecording {
+    // Stops recording and waits for the file to be completed
+    pub fn stop(self) -> io::Result<()> {
+        self.stop.store(true, Ordering::SeqCst);
+        self.done.recv().unwrap_or(Ok(()))
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, Hash, PartialEq)]
+pub(crate) enum VcdValue {
+    Bit(bool),
+    Vector(u16),
+}
+
+impl From<HostWrite> for VcdValue {
+    fn from(write: HostWrite) -> Self {
+        match write {
+            HostWrite::Digital(val) => VcdValue::Bit(val),
+            HostWrite::Analog(val) => VcdValue::Vector(val),
+        }
+    }
+}
+
+// Short printable identifier VCD uses to refer to a variable
+pub(crate) fn identifier(mut n: usize) -> String {
+    let mut id = String::new();
+    loop {
+        id.push((b'!' + (n % 94) as u8) as char);
+        n /= 94;
+        if n == 0 {
+            return id;
+        }
+        n -= 1;
+    }
+}
+
+pub(crate) struct VcdVar {
+    pub(crate) id: String,
+    pub(crate) width: u32,
+    pub(crate) name: String,
+}
+
+// Writes VCD with a timescale of 1us, times have to be passed in increasing order
+pub(crate) struct VcdWriter<W: Write> {
+    out: W,
+    time: Option<u64>,
+}
+
+impl<W: Write> VcdWriter<W> {
+    pub(crate) fn new(mut out: W, vars: &[VcdVar]) -> io::Result<Self> {
+        writeln!(out, "$version smce-rs {} $end", env!("CARGO_PKG_VERSION"))?;
+        writeln!(out, "$timescale 1us $end")?;
+        writeln!(out, "$scope module board $end")?;
+        for var in vars {
+            writeln!(out, "$var wire {} {} {} $end", var.width, var.id, var.name)?;
+        }
+        writeln!(out, "$upscope $end")?;
+        writeln!(out, "$enddefinitions $end")?;
+        Ok(VcdWriter { out, time: None })
+    }
+
+    // Earlier times are clamped to the last one written
+    pub(crate) fn change(&mut self, time: u64, id: &str, value: VcdValue) -> io::Result<()> {
+        self.advance(time)?;
+        match value {
+            VcdValue::Bit(bit) => writeln!(self.out, "{}{}", u8::from(bit), id),
+            VcdValue::Vector(vec) => writeln!(self.out, "b{:b} {}", vec, id),
+        }
+    }
+
+    fn advance(&mut self, time: u64) -> io::Result<()> {
+        match self.time {
+            Some(last) if time <= last => Ok(()),
+            _ => {
+                self.time = Some(time);
+                writeln!(self.out, "#{}", time)
+            }
+        }
+    }
+
+    pub(crate) fn finish(mut self, time: u64) -> io::Result<W> {
+        self.advance(time)?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
+
+struct Trace {
+    pin: GpioPin,
+    digital_id: String,
+    analog_id: Option<String>,
+    digital: bool,
+    analog: u16,
+}
+
+impl Trace {
+    fn record<W: Write>(
+        &mut self,
+        writer: &mut VcdWriter<W>,
+        time: u64,
+        value: VcdValue,
+    ) -> io::Result<()> {
+        match value {
+            VcdValue::Bit(bit) if bit != self.digital => {
+                self.digital = bit;
+                writer.change(time, &self.digital_id, value)
+            }
+            VcdValue::Vector(vec) if vec != self.analog => {
+                self.analog = vec;
+                match &self.analog_id {
+                    Some(id) => writer.change(time, id, value),
+                    None => Ok(()),
+                }
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+pub(crate) fn spawn_recorder<W: Write + Send + 'static>(
+    workers: &Workers,
+    view: &BoardView,
+    out: W,
+    config: VcdConfig,
+) -> io::Result<VcdRecording> {
+    let mut pins: Vec<_> = view.pins.iter().collect();
+    pins.sort_by_key(|(&id, _)| id);
+
+    let mut vars = vec![];
+    let mut traces = vec![];
+    for (&id, pin) in pins {
+        let digital_id = identifier(vars.len());
+        vars.push(VcdVar {
+            id: digital_id.clone(),
+            width: 1,
+            name: format!("pin{}", id),
+        });
+        let analog_id = if config.analog {
+            let analog_id = identifier(vars.len());
+            vars.push(VcdVar {
+                id: analog_id.clone(),
+                width: 16,
+                name: format!("pin{}_analog", id),
+            });
+            Some(analog_id)
+        } else {
+            None
+        };
+        traces.push((
+            id,
+            Trace {
+                pin: pin.duplicate(),
+                digital_id,
+                analog_id,
+                digital: false,
+                analog: 0,
+            },
+        ));
+    }
+
+    let mut writer = VcdWriter::new(out, &vars)?;
+    let (tap, host_writes) = mpsc::channel();
+    for (_, pin) in view.pins.iter() {
+        pin.taps.lock().unwrap().push(tap.clone());
+    }
+
+    // Initial values, so viewers do not show the pins as undefined
+    writer.advance(0)?;
+    for (_, trace) in &mut traces {
+        let digital = trace.pin.digital_read();
+        let analog = trace.pin.analog_read();
+        trace.digital = !digital;
+        trace.analog = !analog;
+        trace.record(&mut writer, 0, VcdValue::Bit(digital))?;
+        trace.record(&mut writer, 0, VcdValue::Vector(analog))?;
+    }
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let (done_tx, done) = mpsc::channel();
+    let stopped = stop.clone();
+    let start = Instant::now();
+    let micros = move |at: Instant| at.saturating_duration_since(start).as_micros() as u64;
+
+    workers.spawn(move |liveness| {
+        let mut record = || -> io::Result<()> {
+            loop {
+                for (at, pin, write) in host_writes.try_iter() {
+                    if let Some((_, trace)) = traces.iter_mut().find(|(id, _)| *id == pin) {
+                        trace.record(&mut writer, micros(at), write.into())?;
+                    }
+                }
+
+                let now = micros(Instant::now());
+                for (_, trace) in &mut traces {
+                    let digital = trace.pin.digital_read();
+                    trace.record(&mut writer, now, VcdValue::Bit(digital))?;
+                    let analog = trace.pin.analog_read();
+                    trace.record(&mut writer, now, VcdValue::Vector(analog))?;
+                }
+
+                // Sampled once more after the exit to catch the final state
+                if !liveness.alive() || stopped.load(Ordering::SeqCst) {
+                    return Ok(());
+                }
+                thread::sleep(config.interval);
+            }
+        };
+
+        let result = record();
+        let end = micros(Instant::now());
+        let result = result.and_then(|_| writer.finish(end).map(drop));
+        let _ = done_tx.send(result);
+    });
+
+    Ok(VcdRecording { stop, done })
+}
+
+pub(crate) struct VcdChange {
+    pub(crate) time: Duration,
+    pub(crate) name: String,
+    pub(crate) value: VcdValue,
+}
+
+fn timescale(spec: &str) -> Result<Duration, String> {
+    let split = spec
+        .find(|c: char| !c.is_ascii_digit())
+        .ok_or_else(|| format!("Invalid timescale {}", spec))?;
+    let (amount, unit) = spec.split_at(split);
+    let amount: u64 = amount
+        .parse()
+        .map_err(|_| format!("Invalid timescale {}", spec))?;
+    let nanos = match unit {
+        "s" => amount * 1_000_000_000,
+        "ms" => amount * 1_000_000,
+        "us" => amount * 1_000,
+        "ns" => amount,
+        _ => return Err(format!("Unsupported timescale unit {}", unit)),
+    };
+    Ok(Duration::from_nanos(nanos))
+}
+
+fn push(
+    changes: &mut Vec<VcdChange>,
+    vars: &HashMap<String, String>,
+    unit: Duration,
+    time: u64,
+    id: &str,
+    value: VcdValue,
+) {
+    if let Some(name) = vars.get(id) {
+        changes.push(VcdChange {
+            time: Duration::from_nanos(unit.as_nanos() as u64 * time),
+            name: name.clone(),
+            value,
+        });
+    }
+}
+
+// Reads back the value changes of every wire, unknown (x/z) and real values are skipped
+pub(crate) fn parse(input: &str) -> Result<Vec<VcdChange>, String> {
+    let mut tokens = input.split_whitespace();
+    let mut vars = HashMap::new();
+    let mut unit = Duration::from_micros(1);
+    let mut time: u64 = 0;
+    let mut changes = vec![];
+
+    while let Some(token) = tokens.next() {
+        match token {
+            "$timescale" => {
+                let spec: String = tokens.by_ref().take_while(|&t| t != "$end").collect();
+                unit = timescale(&spec)?;
+            }
+            "$var" => {
+                let decl: Vec<_> = tokens.by_ref().take_while(|&t| t != "$end").collect();
+                match decl.as_slice() {
+                    [_, _, id, name, ..] => {
+                        vars.insert(id.to_string(), name.to_string());
+                    }
+                    _ => return Err(format!("Invalid variable declaration {:?}", decl)),
+                }
+            }
+            // Markers around value changes
+            "$dumpvars" | "$dumpall" | "$dumpon" | "$dumpoff" | "$end" => {}
+            _ if token.starts_with('$') => {
+                tokens.by_ref().find(|&t| t == "$end");
+            }
+            _ if token.starts_with('#') => {
+                time = token[1..]
+                    .parse()
+                    .map_err(|_| format!("Invalid time {}", token))?;
+            }
+            _ if token.starts_with(['b', 'B']) => {
+                let id = tokens.next().ok_or("Missing identifier")?;
+                if let Ok(vec) = u16::from_str_radix(&token[1..], 2) {
+                    push(&mut changes, &vars, unit, time, id, VcdValue::Vector(vec));
+                }
+            }
+            _ if token.starts_with(['r', 'R']) => {
+                tokens.next();
+            }
+            _ => match (token.get(..1), token.get(1..)) {
+                (Some("0"), Some(id)) => {
+                    push(&mut changes, &vars, unit, time, id, VcdValue::Bit(false))
+                }
+                (Some("1"), Some(id)) => {
+                    push(&mut changes, &vars, unit, time, id, VcdValue::Bit(true))
+                }
+                (Some("x" | "X" | "z" | "Z"), _) => {}
+                _ => return Err(format!("Unexpected token {}", token)),
+            },
+        }
+    }
+
+    Ok(changes)
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use crate::vcd::{identifier, parse, VcdValue, VcdVar, VcdWriter};
+
+    #[test]
+    fn identifiers_are_unique() {
+        assert_eq!(identifier(0), "!");
+        assert_eq!(identifier(93), "~");
+        assert_eq!(identifier(94), "!!");
+        let ids: std::collections::HashSet<_> = (0..10000).map(identifier).collect();
+        assert_eq!(ids.len(), 10000);
+    }
+
+    #[test]
+    fn writes_changes() -> std::io::Result<()> {
+        let vars = [
+            VcdVar {
+                id: identifier(0),
+                width: 1,
+                name: "pin0".into(),
+            },
+            VcdVar {
+                id: identifier(1),
+                width: 16,
+                name: "pin0_analog".into(),
+            },
+        ];
+        let mut writer = VcdWriter::new(vec![], &vars)?;
+        writer.change(0, "!", VcdValue::Bit(false))?;
+        writer.change(0, "\"", VcdValue::Vector(5))?;
+        writer.change(20, "!", VcdValue::Bit(true))?;
+        // Out of order changes end up at the last time
+        writer.change(10, "\"", VcdValue::Vector(0))?;
+        let out = String::from_utf8(writer.finish(30)?).unwrap();
+
+        assert!(out.contains("$var wire 1 ! pin0 $end\n"));
+        assert!(out.contains("$var wire 16 \" pin0_analog $end\n"));
+        assert!(out.ends_with("$enddefinitions $end\n#0\n0!\nb101 \"\n#20\n1!\nb0 \"\n#30\n"));
+        Ok(())
+    }
+
+    #[test]
+    fn parses_changes() {
+        let input = "$timescale 10 ms $end\n$scope module top $end\n\
+                     $var wire 1 ! pin3 $end\n$var wire 16 # pin3_analog [15:0] $end\n\
+                     $upscope $end $enddefinitions $end\n\
+                     #0 $dumpvars x! b0 # $end #5 1! #7 b1010 # 0!";
+        let changes = parse(input).unwrap();
+
+        let flat: Vec<_> = changes
+            .iter()
+            .map(|c| (c.time, c.name.as_str(), c.value))
+            .collect();
+        assert_eq!(
+            flat,
+            [
+                (Duration::ZERO, "pin3_analog", VcdValue::Vector(0)),
+                (Duration::from_millis(50), "pin3", VcdValue::Bit(true)),
+                (
+                    Duration::from_millis(70),
+                    "pin3_analog",
+                    VcdValue::Vector(10)
+                ),
+                (Duration::from_millis(70), "pin3", VcdValue::Bit(false)),
+            ]
+        );
+    }
+}
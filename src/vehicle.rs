@@ -0,0 +1,391 @@
+/*
+ *  vehicle.rs
+ *  Copyright 2021 ItJustWorksTM
+ *
+ *  Licensed under the Apache License, Version 2.0 (the "License");
+ *  you may not use this file except in compliance with the License.
+ *  You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ *  Unless required by applicable law or agreed to in writing, software
+ *  distributed under the License is distributed on an "AS IS" BASIS,
+ *  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ *  See the License for the specific language governing permissions and
+ *  limitations under the License.
+ *
+ */
+
+use std::cell::Cell;
+use std::collections::VecDeque;
+use std::io::{self, Write};
+use std::rc::Rc;
+use std::time::Duration;
+
+use crate::board_view::BoardView;
+use crate::simulation::{require_input, require_output, Peripheral, PeripheralError};
+
+// Sketch outputs controlling one side of the car
+#[derive(Debug, Copy, Clone, Eq, Hash, PartialEq)]
+pub struct Motor {
+    // Speed through analogWrite
+    pub pwm: usize,
+    // High drives forward, unless `reversed` is set
+    pub direction: usize,
+    pub reversed: bool,
+}
+
+// Sketch inputs getting a pulse for every bit of distance a wheel travels
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Odometers {
+    pub left: usize,
+    pub right: usize,
+    pub pulses_per_meter: f64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct VehicleConfig {
+    pub left: Motor,
+    pub right: Motor,
+    pub odometers: Option<Odometers>,
+    // Distance between the wheels in meters
+    pub wheel_base: f64,
+    // Wheel speed in m/s at full PWM
+    pub max_speed: f64,
+    // PWM value meaning full speed
+    pub pwm_max: u16,
+    // Time constant of the motors, they reach ~63% of a new speed after this long
+    pub motor_response: Duration,
+}
+
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub struct Pose {
+    // Meters
+    pub x: f64,
+    pub y: f64,
+    // Radians, counter clockwise from the x axis
+    pub heading: f64,
+}
+
+impl Pose {
+    // Places a pose given relative to this one, like a sensor mounted on the car
+    pub fn compose(&self, local: Pose) -> Pose {
+        let (sin, cos) = self.heading.sin_cos();
+        Pose {
+            x: self.x + local.x * cos - local.y * sin,
+            y: self.y + local.x * sin + local.y * cos,
+            heading: self.heading + local.heading,
+        }
+    }
+}
+
+/// Shared view of a pose, lets sensors follow a [`Vehicle`] within the same simulation.
+#[derive(Debug, Clone, Default)]
+pub struct PoseTracker(Rc<Cell<Pose>>);
+
+impl PoseTracker {
+    // Pose that only changes when set, for sensors that are not on a vehicle
+    pub fn fixed(pose: Pose) -> Self {
+        PoseTracker(Rc::new(Cell::new(pose)))
+    }
+
+    pub fn pose(&self) -> Pose {
+        self.0.get()
+    }
+
+    pub fn set(&self, pose: Pose) {
+        self.0.set(pose);
+    }
+}
+
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub struct PoseSample {
+    pub time: Duration,
+    pub pose: Pose,
+    // Wheel speeds in m/s
+    pub left_speed: f64,
+    pub right_speed: f64,
+}
+
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+struct Wheel {
+    speed: f64,
+    // Distance since the last odometer level change
+    travelled: f64,
+    level: bool,
+    // Level changes the wheel is owed, a step can only show one of them
+    owed: u64,
+}
+
+// Samples kept by default, about 10 seconds at a 1 ms interval
+const HISTORY_CAPACITY: usize = 10_000;
+
+/// Differential-drive car driven by the sketch's motor pins, starting at the origin facing +x.
+///
+/// Odometer pins change level at most once per simulation step. A wheel that travels further
+/// than half a pulse within one step has the missing level changes made up in the steps after,
+/// so no pulse is lost, but keep the interval below half a pulse for them to arrive on time.
+/// Only the last `history_capacity` pose samples are kept.
+#[derive(Debug, Clone)]
+pub struct Vehicle {
+    config: VehicleConfig,
+    time: Duration,
+    pose: Pose,
+    left: Wheel,
+    right: Wheel,
+    distance: f64,
+    history: VecDeque<PoseSample>,
+    history_capacity: usize,
+    tracker: PoseTracker,
+}
+
+impl Vehicle {
+    pub fn new(config: VehicleConfig) -> Self {
+        Vehicle {
+            config,
+            time: Duration::ZERO,
+            pose: Pose::default(),
+            left: Wheel::default(),
+            right: Wheel::default(),
+            distance: 0.0,
+            history: VecDeque::from(vec![PoseSample::default()]),
+            history_capacity: HISTORY_CAPACITY,
+            tracker: PoseTracker::default(),
+        }
+    }
+
+    pub fn with_pose(mut self, pose: Pose) -> Self {
+        self.pose = pose;
+        self.tracker.set(pose);
+        self.history = VecDeque::from(vec![PoseSample {
+            pose,
+            ..Default::default()
+        }]);
+        self
+    }
+
+    pub fn with_history_capacity(mut self, capacity: usize) -> Self {
+        self.history_capacity = capacity;
+        self.trim_history();
+        self
+    }
+
+    pub fn pose(&self) -> Pose {
+        self.pose
+    }
+
+    // Follows the pose of the car as the simulation steps it
+    pub fn tracker(&self) -> PoseTracker {
+        self.tracker.clone()
+    }
+
+    // Distance travelled by the center of the car, in meters
+    pub fn distance(&self) -> f64 {
+        self.distance
+    }
+
+    // Oldest first, at most history_capacity of them
+    pub fn history(&self) -> &VecDeque<PoseSample> {
+        &self.history
+    }
+
+    fn trim_history(&mut self) {
+        let overflow = self.history.len().saturating_sub(self.history_capacity);
+        self.history.drain(..overflow);
+    }
+
+    // `time_s,x_m,y_m,heading_deg,left_mps,right_mps`, one line per step still in the history
+    pub fn write_csv<W: Write>(&self, mut out: W) -> io::Result<()> {
+        writeln!(out, "time_s,x_m,y_m,heading_deg,left_mps,right_mps")?;
+        for sample in &self.history {
+            writeln!(
+                out,
+                "{:.3},{:.4},{:.4},{:.2},{:.4},{:.4}",
+                sample.time.as_secs_f64(),
+                sample.pose.x,
+                sample.pose.y,
+                sample.pose.heading.to_degrees(),
+                sample.left_speed,
+                sample.right_speed
+            )?;
+        }
+        Ok(())
+    }
+
+    fn target_speed(&self, view: &BoardView, motor: &Motor) -> f64 {
+        let pwm = view.pins[motor.pwm].analog_read().min(self.config.pwm_max);
+        let forward = view.pins[motor.direction].digital_read() != motor.reversed;
+        let speed = self.config.max_speed * pwm as f64 / self.config.pwm_max as f64;
+        if forward {
+            speed
+        } else {
+            -speed
+        }
+    }
+
+    // Moves the car with the motors heading towards the given wheel speeds
+    fn integrate(&mut self, left_target: f64, right_target: f64, dt: Duration) {
+        let secs = dt.as_secs_f64();
+        let tau = self.config.motor_response.as_secs_f64();
+        let response = if tau > 0.0 {
+            1.0 - (-secs / tau).exp()
+        } else {
+            1.0
+        };
+        self.left.speed += (left_target - self.left.speed) * response;
+        self.right.speed += (right_target - self.right.speed) * response;
+
+        let speed = (self.left.speed + self.right.speed) / 2.0;
+        let turn_rate = (self.right.speed - self.left.speed) / self.config.wheel_base;
+        // Midpoint heading keeps arcs accurate for larger steps
+        let heading = self.pose.heading + turn_rate * secs / 2.0;
+        self.pose.x += speed * heading.cos() * secs;
+        self.pose.y += speed * heading.sin() * secs;
+        self.pose.heading += turn_rate * secs;
+        self.tracker.set(self.pose);
+
+        self.left.travelled += self.left.speed.abs() * secs;
+        self.right.travelled += self.right.speed.abs() * secs;
+        self.distance += speed.abs() * secs;
+        self.time += dt;
+
+        self.history.push_back(PoseSample {
+            time: self.time,
+            pose: self.pose,
+            left_speed: self.left.speed,
+            right_speed: self.right.speed,
+        });
+        self.trim_history();
+    }
+}
+
+// A full pulse is two level changes, of which one is made per step
+fn advance_odometer(wheel: &mut Wheel, pulses_per_meter: f64) {
+    let half_pulse = 0.5 / pulses_per_meter;
+    if wheel.travelled >= half_pulse {
+        let changes = (wheel.travelled / half_pulse).floor();
+        wheel.travelled -= changes * half_pulse;
+        wheel.owed += changes as u64;
+    }
+    if wheel.owed > 0 {
+        wheel.owed -= 1;
+        wheel.level = !wheel.level;
+    }
+}
+
+impl Peripheral for Vehicle {
+    fn attach(&mut self, view: &BoardView) -> Result<(), PeripheralError> {
+        for motor in [self.config.left, self.config.right] {
+            require_output(view, motor.pwm)?;
+            require_output(view, motor.direction)?;
+        }
+        if let Some(odometers) = self.config.odometers {
+            require_input(view, odometers.left)?.digital_write(false);
+            require_input(view, odometers.right)?.digital_write(false);
+        }
+        Ok(())
+    }
+
+    fn step(&mut self, view: &BoardView, dt: Duration) {
+        let left = self.target_speed(view, &self.config.left);
+        let right = self.target_speed(view, &self.config.right);
+        self.integrate(left, right, dt);
+
+        if let Some(odometers) = self.config.odometers {
+            advance_odometer(&mut self.left, odometers.pulses_per_meter);
+            advance_odometer(&mut self.right, odometers.pulses_per_meter);
+            view.pins[odometers.left].digital_write(self.left.level);
+            view.pins[odometers.right].digital_write(self.right.level);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use crate::vehicle::{advance_odometer, Motor, Vehicle, VehicleConfig, Wheel};
+
+    fn vehicle() -> Vehicle {
+        let motor = Motor {
+            pwm: 0,
+            direction: 1,
+            reversed: false,
+        };
+        Vehicle::new(VehicleConfig {
+            left: motor,
+            right: motor,
+            odometers: None,
+            wheel_base: 0.2,
+            max_speed: 1.0,
+            pwm_max: 255,
+            motor_response: Duration::ZERO,
+        })
+    }
+
+    #[test]
+    fn drives_straight() {
+        let mut car = vehicle();
+        for _ in 0..1000 {
+            car.integrate(0.5, 0.5, Duration::from_millis(2));
+        }
+        let pose = car.pose();
+        assert!((pose.x - 1.0).abs() < 1e-9);
+        assert!(pose.y.abs() < 1e-9);
+        assert!((car.distance() - 1.0).abs() < 1e-9);
+        assert_eq!(car.history().len(), 1001);
+
+        let mut car = vehicle().with_history_capacity(10);
+        for _ in 0..100 {
+            car.integrate(0.5, 0.5, Duration::from_millis(2));
+        }
+        assert_eq!(car.history().len(), 10);
+        assert_eq!(car.history()[9].time, Duration::from_millis(200));
+    }
+
+    #[test]
+    fn odometer_catches_up() {
+        // Two and a half level changes in the first step
+        let mut wheel = Wheel {
+            travelled: 0.125,
+            ..Default::default()
+        };
+        let mut changes = 0;
+        for _ in 0..5 {
+            let level = wheel.level;
+            advance_odometer(&mut wheel, 10.0);
+            if wheel.level != level {
+                changes += 1;
+            }
+        }
+        assert_eq!(changes, 2);
+        assert!((wheel.travelled - 0.025).abs() < 1e-9);
+    }
+
+    #[test]
+    fn turns_in_place() {
+        let mut car = vehicle();
+        // Quarter of the turning circle of the wheels at 0.1 m/s
+        let quarter = std::f64::consts::PI * 0.2 / 4.0 / 0.1;
+        let steps = 1000;
+        for _ in 0..steps {
+            car.integrate(-0.1, 0.1, Duration::from_secs_f64(quarter / steps as f64));
+        }
+        let pose = car.pose();
+        assert!((pose.heading.to_degrees() - 90.0).abs() < 1e-3);
+        assert!(pose.x.abs() < 1e-9 && pose.y.abs() < 1e-9);
+
+        let mut csv = vec![];
+        car.write_csv(&mut csv).unwrap();
+        let csv = String::from_utf8(csv).unwrap();
+        assert!(csv.lines().last().unwrap().contains(",90.00,"));
+    }
+
+    #[test]
+    fn motor_response_lags() {
+        let mut car = vehicle();
+        car.config.motor_response = Duration::from_millis(100);
+        car.integrate(1.0, 1.0, Duration::from_millis(100));
+        assert!((car.left.speed - (1.0 - (-1.0f64).exp())).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,245 @@
+/*
+ *  wait.rs
+ *  Copyright 2021 ItJustWorksTM
+ *
+ *  Licensed under the Apache License, Version 2.0 (the "License");
+ *  you may not use this file except in compliance with the License.
+ *  You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ *  Unless required by applicable law or agreed to in writing, software
+ *  distributed under the License is distributed on an "AS IS" BASIS,
+ *  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ *  See the License for the specific language governing permissions and
+ *  limitations under the License.
+ *
+ */
+
+#[cfg(feature = "tokio")]
+use std::future::Future;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use thiserror::Error;
+
+use crate::board_view::GpioPin;
+
+// How often waiting pins are read
+const POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+#[derive(Debug, Copy, Clone, Eq, Hash, PartialEq)]
+pub enum Edge {
+    Rising,
+    Falling,
+    Any,
+}
+
+#[derive(Debug, Copy, Clone, Eq, Hash, PartialEq)]
+pub struct Observed<T> {
+    pub value: T,
+    pub elapsed: Duration,
+}
+
+#[derive(Error, Debug, Clone, Eq, Hash, PartialEq)]
+#[error("Timed out after {timeout:?} waiting for pin {pin} {condition}, last read {last}")]
+pub struct WaitTimeout {
+    pub pin: usize,
+    pub condition: String,
+    // Last value read from the pin, formatted
+    pub last: String,
+    pub timeout: Duration,
+}
+
+// What is being waited for, checked against every read of the pin
+pub(crate) enum Condition<F> {
+    Level(bool),
+    Edge(Edge, Option<bool>),
+    Analog(F),
+}
+
+impl<F: FnMut(u16) -> bool> Condition<F> {
+    fn describe(&self) -> String {
+        match self {
+            Condition::Level(true) => "to be high".into(),
+            Condition::Level(false) => "to be low".into(),
+            Condition::Edge(Edge::Rising, _) => "to rise".into(),
+            Condition::Edge(Edge::Falling, _) => "to fall".into(),
+            Condition::Edge(Edge::Any, _) => "to change level".into(),
+            Condition::Analog(_) => "to reach the expected analog value".into(),
+        }
+    }
+
+    // Reads the pin once, returning the value that satisfied the condition
+    fn check(&mut self, pin: &GpioPin) -> Result<u16, String> {
+        match self {
+            Condition::Level(level) => {
+                let value = pin.digital_read();
+                if value == *level {
+                    Ok(value.into())
+                } else {
+                    Err(value.to_string())
+                }
+            }
+            Condition::Edge(edge, previous) => {
+                let value = pin.digital_read();
+                let edged = match (previous.replace(value), *edge) {
+                    (Some(old), Edge::Rising) => !old && value,
+                    (Some(old), Edge::Falling) => old && !value,
+                    (Some(old), Edge::Any) => old != value,
+                    (None, _) => false,
+                };
+                if edged {
+                    Ok(value.into())
+                } else {
+                    Err(value.to_string())
+                }
+            }
+            Condition::Analog(predicate) => {
+                let value = pin.analog_read();
+                if predicate(value) {
+                    Ok(value)
+                } else {
+                    Err(value.to_string())
+                }
+            }
+        }
+    }
+}
+
+pub(crate) struct Waiter<F> {
+    condition: Condition<F>,
+    start: Instant,
+    timeout: Duration,
+    // Formatted, for the timeout
+    last: String,
+}
+
+impl<F: FnMut(u16) -> bool> Waiter<F> {
+    pub(crate) fn new(condition: Condition<F>, timeout: Duration) -> Self {
+        Waiter {
+            condition,
+            start: Instant::now(),
+            timeout,
+            last: "nothing".into(),
+        }
+    }
+
+    // None while still waiting. Once the board is gone there is nothing left to read,
+    // which leaves the wait to time out.
+    pub(crate) fn poll(&mut self, pin: &GpioPin) -> Option<Result<Observed<u16>, WaitTimeout>> {
+        let elapsed = self.start.elapsed();
+        let condition = &mut self.condition;
+        match pin.workers.liveness().present(|| condition.check(pin)) {
+            Some(Ok(value)) => return Some(Ok(Observed { value, elapsed })),
+            Some(Err(last)) => self.last = last,
+            None => {}
+        }
+        if elapsed < self.timeout {
+            return None;
+        }
+        Some(Err(WaitTimeout {
+            pin: pin.info().pin_id as usize,
+            condition: self.condition.describe(),
+            last: self.last.clone(),
+            timeout: self.timeout,
+        }))
+    }
+
+    pub(crate) fn wait(mut self, pin: &GpioPin) -> Result<Observed<u16>, WaitTimeout> {
+        loop {
+            if let Some(result) = self.poll(pin) {
+                return result;
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+
+    // Owns the pin so the future does not borrow it across awaits, which keeps it Send
+    #[cfg(feature = "tokio")]
+    pub(crate) async fn wait_async(mut self, pin: GpioPin) -> Result<Observed<u16>, WaitTimeout> {
+        loop {
+            if let Some(result) = self.poll(&pin) {
+                return result;
+            }
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+    }
+}
+
+fn digital(observed: Observed<u16>) -> Observed<bool> {
+    Observed {
+        value: observed.value != 0,
+        elapsed: observed.elapsed,
+    }
+}
+
+type NoPredicate = fn(u16) -> bool;
+
+impl GpioPin {
+    // Blocks until the pin reads `level`, which may be right away
+    pub fn wait_for_level(
+        &self,
+        level: bool,
+        timeout: Duration,
+    ) -> Result<Observed<bool>, WaitTimeout> {
+        Waiter::<NoPredicate>::new(Condition::Level(level), timeout)
+            .wait(self)
+            .map(digital)
+    }
+
+    // Blocks until the pin changes level in the given direction, returns the new level
+    pub fn wait_for_edge(
+        &self,
+        edge: Edge,
+        timeout: Duration,
+    ) -> Result<Observed<bool>, WaitTimeout> {
+        Waiter::<NoPredicate>::new(Condition::Edge(edge, None), timeout)
+            .wait(self)
+            .map(digital)
+    }
+
+    // Blocks until the analog value satisfies the predicate
+    pub fn wait_for_analog(
+        &self,
+        predicate: impl FnMut(u16) -> bool,
+        timeout: Duration,
+    ) -> Result<Observed<u16>, WaitTimeout> {
+        Waiter::new(Condition::Analog(predicate), timeout).wait(self)
+    }
+
+    // The futures own a duplicate of the pin, so they can be spawned onto a runtime. Should
+    // the board go away first they end in a timeout.
+    #[cfg(feature = "tokio")]
+    pub fn wait_for_level_async(
+        &self,
+        level: bool,
+        timeout: Duration,
+    ) -> impl Future<Output = Result<Observed<bool>, WaitTimeout>> + Send + 'static {
+        let waiter = Waiter::<NoPredicate>::new(Condition::Level(level), timeout);
+        let pin = self.duplicate();
+        async move { waiter.wait_async(pin).await.map(digital) }
+    }
+
+    #[cfg(feature = "tokio")]
+    pub fn wait_for_edge_async(
+        &self,
+        edge: Edge,
+        timeout: Duration,
+    ) -> impl Future<Output = Result<Observed<bool>, WaitTimeout>> + Send + 'static {
+        let waiter = Waiter::<NoPredicate>::new(Condition::Edge(edge, None), timeout);
+        let pin = self.duplicate();
+        async move { waiter.wait_async(pin).await.map(digital) }
+    }
+
+    #[cfg(feature = "tokio")]
+    pub fn wait_for_analog_async(
+        &self,
+        predicate: impl FnMut(u16) -> bool + Send + 'static,
+        timeout: Duration,
+    ) -> impl Future<Output = Result<Observed<u16>, WaitTimeout>> + Send + 'static {
+        let waiter = Waiter::new(Condition::Analog(predicate), timeout);
+        let pin = self.duplicate();
+        waiter.wait_async(pin)
+    }
+}
@@ -0,0 +1,95 @@
+/*
+ *  worker.rs
+ *  Copyright 2021 ItJustWorksTM
+ *
+ *  Licensed under the Apache License, Version 2.0 (the "License");
+ *  you may not use this file except in compliance with the License.
+ *  You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ *  Unless required by applicable law or agreed to in writing, software
+ *  distributed under the License is distributed on an "AS IS" BASIS,
+ *  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ *  See the License for the specific language governing permissions and
+ *  limitations under the License.
+ *
+ */
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread::{self, JoinHandle};
+
+// Background threads working on duplicated board handles, these point into the board's
+// shared memory so every one of them has to be joined before the board goes away.
+// Clones share the same set of threads.
+#[derive(Clone, Default)]
+pub(crate) struct Workers {
+    state: Arc<State>,
+    handles: Arc<Mutex<Vec<JoinHandle<()>>>>,
+}
+
+#[derive(Default)]
+struct State {
+    shutdown: AtomicBool,
+    exited: AtomicBool,
+    // Held while a handle outside of the workers uses the board, see Liveness::present
+    present: RwLock<()>,
+}
+
+// Handed to every worker to find out when it should wrap up
+#[derive(Clone)]
+pub(crate) struct Liveness {
+    state: Arc<State>,
+}
+
+impl Liveness {
+    // False once the board is going away or the sketch has exited
+    pub(crate) fn alive(&self) -> bool {
+        !self.state.shutdown.load(Ordering::SeqCst) && !self.state.exited.load(Ordering::SeqCst)
+    }
+
+    // Runs `f` unless the board is going away, which waits for it to finish. For handles that
+    // are not on a worker thread, like those in futures, as they can not be joined.
+    pub(crate) fn present<R>(&self, f: impl FnOnce() -> R) -> Option<R> {
+        let _present = self.state.present.read().unwrap();
+        if self.state.shutdown.load(Ordering::SeqCst) {
+            return None;
+        }
+        Some(f())
+    }
+}
+
+impl Workers {
+    pub(crate) fn spawn(&self, f: impl FnOnce(Liveness) + Send + 'static) {
+        let liveness = Liveness {
+            state: self.state.clone(),
+        };
+        let handle = thread::spawn(move || f(liveness));
+
+        let mut handles = self.handles.lock().unwrap();
+        handles.retain(|handle| !handle.is_finished());
+        handles.push(handle);
+    }
+
+    // For waiting on the sketch outside of a worker
+    pub(crate) fn liveness(&self) -> Liveness {
+        Liveness {
+            state: self.state.clone(),
+        }
+    }
+
+    pub(crate) fn sketch_exited(&self) {
+        self.state.exited.store(true, Ordering::SeqCst);
+    }
+
+    pub(crate) fn shutdown(&self) {
+        {
+            let _gone = self.state.present.write().unwrap();
+            self.state.shutdown.store(true, Ordering::SeqCst);
+        }
+        for handle in self.handles.lock().unwrap().drain(..) {
+            let _ = handle.join();
+        }
+    }
+}
@@ -0,0 +1,393 @@
+/*
+ *  world.rs
+ *  Copyright 2021 ItJustWorksTM
+ *
+ *  Licensed under the Apache License, Version 2.0 (the "License");
+ *  you may not use this file except in compliance with the License.
+ *  You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ *  Unless required by applicable law or agreed to in writing, software
+ *  distributed under the License is distributed on an "AS IS" BASIS,
+ *  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ *  See the License for the specific language governing permissions and
+ *  limitations under the License.
+ *
+ */
+
+use std::io::{self, Read};
+use std::rc::Rc;
+use std::time::Duration;
+
+use thiserror::Error;
+
+use crate::board_view::BoardView;
+use crate::simulation::{require_input, require_output, Peripheral, PeripheralError};
+use crate::vehicle::{Pose, PoseTracker};
+
+// In m/s, at room temperature
+const SPEED_OF_SOUND: f64 = 343.0;
+
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub struct Segment {
+    // Meters
+    pub start: (f64, f64),
+    pub end: (f64, f64),
+}
+
+#[derive(Error, Debug)]
+pub enum WorldError {
+    #[error("Failed to read world")]
+    Io(#[from] io::Error),
+    #[error("Invalid world on line {line}: {reason}")]
+    Csv { line: usize, reason: String },
+}
+
+/// Walls and obstacles as line segments, in meters.
+///
+/// ```text
+/// # x1, y1, x2, y2
+/// -1, -1, 3, -1
+/// 2, -0.5, 2, 0.5
+/// ```
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct World {
+    segments: Vec<Segment>,
+}
+
+impl World {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(mut self, start: (f64, f64), end: (f64, f64)) -> Self {
+        self.segments.push(Segment { start, end });
+        self
+    }
+
+    // Axis aligned rectangle between two opposite corners
+    pub fn push_box(self, corner: (f64, f64), opposite: (f64, f64)) -> Self {
+        let (x1, y1) = corner;
+        let (x2, y2) = opposite;
+        self.push((x1, y1), (x2, y1))
+            .push((x2, y1), (x2, y2))
+            .push((x2, y2), (x1, y2))
+            .push((x1, y2), (x1, y1))
+    }
+
+    pub fn segments(&self) -> &[Segment] {
+        &self.segments
+    }
+
+    pub fn from_csv<R: Read>(mut reader: R) -> Result<Self, WorldError> {
+        let mut input = String::new();
+        reader.read_to_string(&mut input)?;
+
+        let mut world = World::new();
+        for (i, line) in input.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let err = |reason: &str| WorldError::Csv {
+                line: i + 1,
+                reason: reason.into(),
+            };
+
+            let coords = line
+                .split(',')
+                .map(|field| field.trim().parse::<f64>().ok().filter(|v| v.is_finite()))
+                .collect::<Option<Vec<_>>>()
+                .ok_or_else(|| err("invalid coordinate"))?;
+            world = match coords.as_slice() {
+                &[x1, y1, x2, y2] => world.push((x1, y1), (x2, y2)),
+                _ => return Err(err("expected 4 fields")),
+            };
+        }
+        Ok(world)
+    }
+
+    // Distance to the nearest segment along the heading of `from`, if within `max_range`
+    pub fn ray_cast(&self, from: Pose, max_range: f64) -> Option<f64> {
+        let (dy, dx) = from.heading.sin_cos();
+        self.segments
+            .iter()
+            .filter_map(|segment| {
+                let (ex, ey) = (
+                    segment.end.0 - segment.start.0,
+                    segment.end.1 - segment.start.1,
+                );
+                let denominator = dx * ey - dy * ex;
+                if denominator.abs() < f64::EPSILON {
+                    return None;
+                }
+                let (wx, wy) = (segment.start.0 - from.x, segment.start.1 - from.y);
+                // Along the ray, and along the segment from 0 to 1
+                let t = (wx * ey - wy * ex) / denominator;
+                let u = (wx * dy - wy * dx) / denominator;
+                (t >= 0.0 && (0.0..=1.0).contains(&u)).then_some(t)
+            })
+            .filter(|&distance| distance <= max_range)
+            .min_by(|a, b| a.total_cmp(b))
+    }
+}
+
+/// HC-SR04 style ultrasonic sensor, answers triggers with an echo pulse as long as the sound
+/// takes to come back.
+///
+/// Pins are only sampled when the simulation steps, so the echo width is rounded to its interval.
+/// That makes the distance the sketch measures only as precise as
+/// [`UltrasonicSensor::resolution`], about 17 cm at a 1 ms interval, so step much faster for
+/// anything finer. Trigger pulses shorter than the interval are usually missed, in that case use
+/// [`UltrasonicSensor::free_running`] to echo periodically instead.
+#[derive(Debug, Clone)]
+pub struct UltrasonicSensor {
+    world: Rc<World>,
+    tracker: PoseTracker,
+    mount: Pose,
+    trigger: usize,
+    echo: usize,
+    max_range: f64,
+    // Full width of the cone, in radians
+    beam: f64,
+    period: Option<Duration>,
+    triggered: bool,
+    // Time since the current echo started and how long it lasts
+    echoing: Option<(Duration, Duration)>,
+    since_echo: Duration,
+    distance: Option<f64>,
+}
+
+impl UltrasonicSensor {
+    // `mount` is relative to the tracked pose, x forward and y to the left
+    pub fn new(
+        world: Rc<World>,
+        tracker: PoseTracker,
+        mount: Pose,
+        trigger: usize,
+        echo: usize,
+    ) -> Self {
+        UltrasonicSensor {
+            world,
+            tracker,
+            mount,
+            trigger,
+            echo,
+            max_range: 4.0,
+            beam: 15f64.to_radians(),
+            period: None,
+            triggered: false,
+            echoing: None,
+            since_echo: Duration::ZERO,
+            distance: None,
+        }
+    }
+
+    pub fn with_max_range(self, max_range: f64) -> Self {
+        UltrasonicSensor { max_range, ..self }
+    }
+
+    pub fn with_beam(self, beam: f64) -> Self {
+        UltrasonicSensor { beam, ..self }
+    }
+
+    // Starts an echo every `period` whether triggered or not
+    pub fn free_running(self, period: Duration) -> Self {
+        UltrasonicSensor {
+            period: Some(period),
+            ..self
+        }
+    }
+
+    // Distance in meters one simulation interval of echo width stands for
+    pub fn resolution(interval: Duration) -> f64 {
+        SPEED_OF_SOUND * interval.as_secs_f64() / 2.0
+    }
+
+    // Result of the latest measurement, None when nothing was in range
+    pub fn distance(&self) -> Option<f64> {
+        self.distance
+    }
+
+    // Nearest hit of a few rays spread over the cone
+    fn measure(&self) -> Option<f64> {
+        let origin = self.tracker.pose().compose(self.mount);
+        (0..5)
+            .filter_map(|i| {
+                let heading = origin.heading + self.beam * (i as f64 / 4.0 - 0.5);
+                self.world
+                    .ray_cast(Pose { heading, ..origin }, self.max_range)
+            })
+            .min_by(|a, b| a.total_cmp(b))
+    }
+
+    // Advances by `dt` given the trigger level, returns the echo level
+    fn update(&mut self, trigger: bool, dt: Duration) -> bool {
+        self.since_echo += dt;
+        let rising = trigger && !self.triggered;
+        self.triggered = trigger;
+
+        if let Some((elapsed, width)) = &mut self.echoing {
+            *elapsed += dt;
+            // Ends on the step closest to the actual width
+            if *elapsed + dt / 2 >= *width {
+                self.echoing = None;
+            }
+            return self.echoing.is_some();
+        }
+
+        let due = matches!(self.period, Some(period) if self.since_echo >= period);
+        if rising || due {
+            self.since_echo = Duration::ZERO;
+            self.distance = self.measure();
+            // The real sensor gives up after 38 ms
+            let width = self.distance.map_or(Duration::from_millis(38), |distance| {
+                Duration::from_secs_f64(2.0 * distance / SPEED_OF_SOUND)
+            });
+            self.echoing = Some((Duration::ZERO, width));
+        }
+        self.echoing.is_some()
+    }
+}
+
+impl Peripheral for UltrasonicSensor {
+    fn attach(&mut self, view: &BoardView) -> Result<(), PeripheralError> {
+        require_output(view, self.trigger)?;
+        require_input(view, self.echo)?.digital_write(false);
+        Ok(())
+    }
+
+    fn step(&mut self, view: &BoardView, dt: Duration) {
+        let trigger = view.pins[self.trigger].digital_read();
+        let echo = self.update(trigger, dt);
+        view.pins[self.echo].digital_write(echo);
+    }
+}
+
+/// Sharp GP2Y0A21 style infrared sensor, outputs a voltage falling with distance.
+///
+/// Distances outside of its 10 to 80 cm range read as the nearest end of it.
+#[derive(Debug, Clone)]
+pub struct IrSensor {
+    world: Rc<World>,
+    tracker: PoseTracker,
+    mount: Pose,
+    pin: usize,
+    // Analog value matching 5 V
+    max: u16,
+    distance: Option<f64>,
+}
+
+impl IrSensor {
+    // `mount` is relative to the tracked pose, x forward and y to the left
+    pub fn new(world: Rc<World>, tracker: PoseTracker, mount: Pose, pin: usize) -> Self {
+        IrSensor {
+            world,
+            tracker,
+            mount,
+            pin,
+            max: 1023,
+            distance: None,
+        }
+    }
+
+    pub fn with_max(self, max: u16) -> Self {
+        IrSensor { max, ..self }
+    }
+
+    // Result of the latest measurement, None when nothing was in range
+    pub fn distance(&self) -> Option<f64> {
+        self.distance
+    }
+
+    // Inverse of the usual `27.728 * V^-1.2045` cm fit of the sensor
+    fn voltage(distance: Option<f64>) -> f64 {
+        let cm = distance.map_or(80.0, |distance| (distance * 100.0).clamp(10.0, 80.0));
+        (27.728 / cm).powf(1.0 / 1.2045)
+    }
+
+    fn value(&self) -> u16 {
+        let value = Self::voltage(self.distance) / 5.0 * self.max as f64;
+        value.round().min(self.max as f64) as u16
+    }
+}
+
+impl Peripheral for IrSensor {
+    fn attach(&mut self, view: &BoardView) -> Result<(), PeripheralError> {
+        require_input(view, self.pin)?.analog_write(self.value());
+        Ok(())
+    }
+
+    fn step(&mut self, view: &BoardView, _dt: Duration) {
+        let origin = self.tracker.pose().compose(self.mount);
+        self.distance = self.world.ray_cast(origin, 0.8);
+        view.pins[self.pin].analog_write(self.value());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::f64::consts::FRAC_PI_2;
+    use std::rc::Rc;
+    use std::time::Duration;
+
+    use crate::vehicle::{Pose, PoseTracker};
+    use crate::world::{IrSensor, UltrasonicSensor, World};
+
+    #[test]
+    fn parse_and_cast() {
+        let csv = "# x1, y1, x2, y2\n\n2, -1, 2, 1\n-1, 3, 1, 3\n";
+        let world = World::from_csv(csv.as_bytes()).unwrap();
+        assert_eq!(world.segments().len(), 2);
+        assert!(World::from_csv("1, 2, 3".as_bytes()).is_err());
+        assert!(World::from_csv("1, 2, x, 4".as_bytes()).is_err());
+
+        let origin = Pose::default();
+        assert_eq!(world.ray_cast(origin, 10.0), Some(2.0));
+        assert_eq!(world.ray_cast(origin, 1.5), None);
+        let up = Pose {
+            heading: FRAC_PI_2,
+            ..origin
+        };
+        assert!((world.ray_cast(up, 10.0).unwrap() - 3.0).abs() < 1e-9);
+        let back = Pose {
+            heading: 2.0 * FRAC_PI_2,
+            ..origin
+        };
+        assert_eq!(world.ray_cast(back, 10.0), None);
+    }
+
+    #[test]
+    fn ultrasonic_echo() {
+        let world = Rc::new(World::new().push((1.0, -1.0), (1.0, 1.0)));
+        let tracker = PoseTracker::fixed(Pose::default());
+        let mount = Pose {
+            x: 0.0145,
+            ..Pose::default()
+        };
+        let mut sensor = UltrasonicSensor::new(world, tracker, mount, 0, 1).with_beam(0.0);
+        let dt = Duration::from_micros(100);
+
+        assert!(!sensor.update(false, dt));
+        assert!(sensor.update(true, dt));
+        // Round trip of 2 * 0.9855 m takes about 5.75 ms
+        let width = (0..100).take_while(|_| sensor.update(false, dt)).count() + 1;
+        assert_eq!(width, 57);
+        assert!((sensor.distance().unwrap() - 0.9855).abs() < 1e-9);
+        assert!(!sensor.update(false, dt));
+        // Each step of echo is worth about 1.7 cm
+        let resolution = UltrasonicSensor::resolution(dt);
+        assert!((width as f64 * resolution - 0.9855).abs() <= resolution);
+    }
+
+    #[test]
+    fn ir_curve() {
+        let near = IrSensor::voltage(Some(0.1));
+        let far = IrSensor::voltage(Some(0.8));
+        assert!((near - 2.33).abs() < 0.01);
+        assert!((far - 0.41).abs() < 0.01);
+        assert_eq!(IrSensor::voltage(None), far);
+        assert_eq!(IrSensor::voltage(Some(0.05)), near);
+    }
+}
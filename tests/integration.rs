@@ -2,20 +2,41 @@ use std::{
     fs::{self, File},
     io::Write,
     io::{BufReader, Read},
+    net::{TcpListener, TcpStream},
     path::PathBuf,
+    rc::Rc,
+    sync::mpsc,
     thread,
-    time::Duration,
+    time::{Duration, Instant, SystemTime},
 };
 
 use smce_rs::{
-    board::{Board, Status},
+    actuator::{Servo, ServoConfig, Stepper},
+    board::{Board, BoardHandle, ExitStatus, Status, StopOutcome},
     board_config::SecureDigitalStorage,
     board_config::{BoardConfig, GpioDriver, UartChannel},
-    board_view::GpioPin,
+    events::{BoardEvent, EventConfig},
+    expect::{Direction, Expect, ExpectError},
+    fault::{FaultConfig, FaultKind},
+    input::{Bounce, Keypad, PushButton, RotaryEncoder, ToggleSwitch},
+    limits::{Limit, ResourceLimits},
+    meter::MeterConfig,
+    pacing::FrameFormat,
+    replay::{Mismatch, Recorder, Recording},
+    runtime_log::LogStream,
+    sandbox::Sandbox,
+    signal::{SignalConfig, SignalError, Source},
+    simulation::{Led, PeripheralError, Simulation},
     sketch::Sketch,
     sketch_config::{PluginManifest, SketchConfig},
+    stimulus::{Schedule, StimulusError},
+    supervisor::{Backoff, RestartPolicy, Supervision, Supervisor},
     toolchain::BuildLogReader,
     toolchain::Toolchain,
+    vcd::VcdConfig,
+    vehicle::{Motor, Odometers, Pose, PoseTracker, Vehicle, VehicleConfig},
+    wait::{Edge, Observed},
+    world::{UltrasonicSensor, World},
 };
 
 const TEST_HOME: &str = env!("SMCE_TEST_HOME");
@@ -34,121 +55,1582 @@ fn build_sketch(
     Ok((sketch, tclog))
 }
 
+// Prepares a board for the pins sketch, which reads pin 0 and writes pin 2, ready to be started
+fn prepare_pins(board: &mut Board) -> anyhow::Result<BoardHandle<'_>> {
+    let sketch = build_sketch("./tests/sketches/pins", Default::default())?.0;
+    let config = BoardConfig {
+        gpio_drivers: vec![
+            GpioDriver {
+                pin_id: 0,
+                allow_read: true,
+                allow_write: false,
+            },
+            GpioDriver {
+                pin_id: 2,
+                allow_read: false,
+                allow_write: true,
+            },
+        ],
+        ..Default::default()
+    };
+    Ok(board.prepare(&config, &sketch)?)
+}
+
+fn start_pins(board: &mut Board) -> anyhow::Result<BoardHandle<'_>> {
+    let handle = prepare_pins(board)?;
+    assert!(handle.start());
+    Ok(handle)
+}
+
+#[test]
+fn noop_compile() -> anyhow::Result<()> {
+    let sketch = build_sketch("./tests/sketches/noop", Default::default())?.0;
+    assert!(sketch.compiled());
+    Ok(())
+}
+
+#[test]
+fn tick_crash() -> anyhow::Result<()> {
+    let sketch = build_sketch("./tests/sketches/uncaught", Default::default())?.0;
+
+    let mut board = Board::new();
+    let handle = board.prepare(&Default::default(), &sketch)?;
+    assert!(handle.start());
+
+    for _ in 0..10 {
+        if handle.tick().is_err() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+
+    assert_eq!(handle.status(), Status::Stopped);
+    assert_ne!(handle.stop(), 0, "Expected non zero exitcode");
+    Ok(())
+}
+
+#[test]
+fn runtime_log_after_exit() -> anyhow::Result<()> {
+    let sketch = build_sketch("./tests/sketches/uncaught", Default::default())?.0;
+
+    let mut board = Board::new();
+    let handle = board.prepare(&Default::default(), &sketch)?;
+    assert!(handle.start());
+
+    for _ in 0..10 {
+        if handle.tick().is_err() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    handle.stop();
+
+    let log = board.runtime_log();
+    let notices: Vec<_> = log.stream(LogStream::Runtime).collect();
+    assert!(notices.first().unwrap().line.starts_with("Sketch started"));
+    assert!(notices.last().unwrap().line.starts_with("Sketch exited"));
+    Ok(())
+}
+
+#[test]
+fn runtime_log_streams() -> anyhow::Result<()> {
+    let sketch = build_sketch("./tests/sketches/streams", Default::default())?.0;
+
+    let mut board = Board::new();
+    let handle = board.prepare(&Default::default(), &sketch)?;
+    let before_start = SystemTime::now();
+    assert!(handle.start());
+
+    let lines = |stream| -> Vec<String> {
+        let log = handle.runtime_log();
+        log.stream(stream)
+            .map(|record| record.line.clone())
+            .collect()
+    };
+    for _ in 0..100 {
+        if !lines(LogStream::Stdout).is_empty() && !lines(LogStream::Stderr).is_empty() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    assert_eq!(lines(LogStream::Stdout), ["to stdout"]);
+    assert_eq!(lines(LogStream::Stderr), ["to stderr"]);
+
+    // The snapshot does not stand in the way of the board
+    let log = handle.runtime_log();
+    assert!(handle.tick().is_ok());
+    let record = log.stream(LogStream::Stdout).next().unwrap();
+    assert!(record.timestamp >= before_start);
+
+    handle.stop();
+    Ok(())
+}
+
+#[test]
+fn suspend_resume() -> anyhow::Result<()> {
+    let sketch = build_sketch("./tests/sketches/noop", Default::default())?.0;
+
+    let mut board = Board::new();
+    let handle = board.prepare(&Default::default(), &sketch)?;
+    assert!(handle.start());
+
+    assert_eq!(handle.status(), Status::Running);
+    assert!(handle.suspend());
+    assert_eq!(handle.status(), Status::Suspended);
+    assert!(handle.resume());
+    assert_eq!(handle.status(), Status::Running);
+    assert!(!handle.resume());
+
+    Ok(())
+}
+
+#[test]
+fn cpu_limit() -> anyhow::Result<()> {
+    let sketch = build_sketch("./tests/sketches/busy", Default::default())?.0;
+
+    let mut board = Board::new();
+    board.set_limits(ResourceLimits {
+        cpu_time: Some(Duration::from_secs(1)),
+        ..Default::default()
+    });
+    let handle = board.prepare(&Default::default(), &sketch)?;
+    assert!(handle.start());
+
+    for _ in 0..100 {
+        if handle.tick().is_err() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+
+    assert_eq!(
+        handle.exit_status(),
+        Some(ExitStatus::LimitExceeded(Limit::CpuTime))
+    );
+    Ok(())
+}
+
+#[test]
+fn memory_limit() -> anyhow::Result<()> {
+    let sketch = build_sketch("./tests/sketches/hog", Default::default())?.0;
+
+    let mut board = Board::new();
+    board.set_limits(ResourceLimits {
+        memory: Some(512 << 20),
+        ..Default::default()
+    });
+    let handle = board.prepare(&Default::default(), &sketch)?;
+    assert!(handle.start());
+
+    for _ in 0..100 {
+        if handle.tick().is_err() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+
+    assert_eq!(
+        handle.exit_status(),
+        Some(ExitStatus::LimitExceeded(Limit::Memory))
+    );
+    Ok(())
+}
+
+// The sketch reads its limit during static initialization, which only sees it if it was set
+// before the sketch got to run
+#[cfg(target_os = "linux")]
+#[test]
+fn limits_applied_before_exec() -> anyhow::Result<()> {
+    let sketch = build_sketch("./tests/sketches/rlimit", Default::default())?.0;
+
+    let mut board = Board::new();
+    board.set_limits(ResourceLimits {
+        open_files: Some(37),
+        ..Default::default()
+    });
+    let handle = board.prepare(
+        &BoardConfig {
+            uart_channels: vec![UartChannel::default()],
+            ..Default::default()
+        },
+        &sketch,
+    )?;
+    assert!(handle.start());
+
+    let mut session = Expect::new(&handle.view().uart_channels[0]);
+    session.expect(r"NOFILE 37\r?\n", PIN_TIMEOUT)?;
+    Ok(())
+}
+
+#[test]
+fn sandboxed_start() -> anyhow::Result<()> {
+    let sketch = build_sketch("./tests/sketches/noop", Default::default())?.0;
+
+    let mut board = Board::new();
+    board.set_sandbox(Some(Sandbox::default()));
+    let handle = board.prepare(&Default::default(), &sketch)?;
+    assert!(handle.start());
+
+    thread::sleep(Duration::from_millis(100));
+    assert!(handle.tick().is_ok());
+    assert_eq!(handle.status(), Status::Running);
+
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn sandbox_denies_escapes() -> anyhow::Result<()> {
+    let sketch = build_sketch("./tests/sketches/escape", Default::default())?.0;
+
+    let secret = std::env::temp_dir().join(format!("smce-rs-secret-{}", std::process::id()));
+    fs::write(&secret, "secret")?;
+
+    // The shared memory of another board is what appears while preparing it
+    let shm = || -> std::io::Result<Vec<PathBuf>> {
+        fs::read_dir("/dev/shm")?
+            .map(|e| e.map(|e| e.path()))
+            .collect()
+    };
+    let before = shm()?;
+    let mut other = Board::new();
+    let _other = other.prepare(&Default::default(), &sketch)?;
+    let segment = shm()?
+        .into_iter()
+        .find(|path| !before.contains(path))
+        .expect("no shared memory for the other board");
+
+    let mut board = Board::new();
+    board.set_sandbox(Some(Sandbox::default()));
+    let handle = board.prepare(
+        &BoardConfig {
+            uart_channels: vec![UartChannel::default()],
+            ..Default::default()
+        },
+        &sketch,
+    )?;
+    assert!(handle.start());
+
+    let mut session = Expect::new(&handle.view().uart_channels[0]);
+    let mut attempt = |command: String| -> anyhow::Result<bool> {
+        session.send_line(&command)?;
+        let (index, _) = session.expect_any(&["ALLOWED", "DENIED"], Duration::from_secs(16))?;
+        Ok(index == 0)
+    };
+
+    assert!(attempt("write /dev/null".into())?);
+    assert!(!attempt(format!("read {}", secret.display()))?);
+    assert!(!attempt(format!("write {}", segment.display()))?);
+    for domain in ["inet", "inet6", "unix", "netlink"] {
+        assert!(!attempt(format!("socket {}", domain))?, "{}", domain);
+    }
+    for command in ["x32socket", "socketpair", "io_uring"] {
+        assert!(!attempt(command.into())?, "{}", command);
+    }
+
+    fs::remove_file(&secret)?;
+    Ok(())
+}
+
+#[test]
+fn supervised_crash_loop() -> anyhow::Result<()> {
+    let sketch = build_sketch("./tests/sketches/uncaught", Default::default())?.0;
+
+    let policy = RestartPolicy::OnFailure(Backoff {
+        initial: Duration::from_millis(10),
+        max: Duration::from_millis(50),
+        max_retries: 2,
+    });
+    let mut board = Board::new();
+    let mut supervisor =
+        Supervisor::start(&mut board, Default::default(), &sketch, policy.clone())?;
+
+    let mut gave_up = None;
+    for _ in 0..100 {
+        if let Supervision::GaveUp(exit_status) = supervisor.tick()? {
+            gave_up = Some(exit_status);
+            break;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+
+    assert!(matches!(gave_up, Some(ExitStatus::Exited(code)) if code != 0));
+    assert_eq!(supervisor.history().len(), 2);
+
+    // Only failures in a row count, and with every run healthy none of them add up
+    let mut board = Board::new();
+    let mut supervisor = Supervisor::start(&mut board, Default::default(), &sketch, policy)?;
+    supervisor.set_healthy_uptime(Duration::ZERO);
+    for _ in 0..100 {
+        assert!(!matches!(supervisor.tick()?, Supervision::GaveUp(_)));
+        if supervisor.history().len() > 3 {
+            break;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    assert!(supervisor.history().len() > 3);
+    assert!(supervisor
+        .history()
+        .iter()
+        .all(|restart| restart.attempt == 1));
+    supervisor.stop();
+    Ok(())
+}
+
+#[test]
+fn supervised_restart_keeps_view() -> anyhow::Result<()> {
+    let sketch = build_sketch("./tests/sketches/flaky", Default::default())?.0;
+
+    let mut board = Board::new();
+    let mut supervisor = Supervisor::start(
+        &mut board,
+        BoardConfig {
+            gpio_drivers: vec![GpioDriver {
+                pin_id: 2,
+                allow_read: false,
+                allow_write: true,
+            }],
+            uart_channels: vec![UartChannel::default()],
+            ..Default::default()
+        },
+        &sketch,
+        RestartPolicy::Always,
+    )?;
+    let events = supervisor.handle().events(EventConfig {
+        interval: Duration::from_millis(1),
+        ..Default::default()
+    });
+    // Never read by the sketch, so it has to make it through every restart
+    let mut uart0 = &supervisor.view().uart_channels[0];
+    uart0.write_all(b"kept")?;
+    let view: *const _ = supervisor.view();
+
+    let mut backing_off = None;
+    for _ in 0..100 {
+        match supervisor.tick()? {
+            Supervision::BackingOff(delay) => backing_off = Some(delay),
+            Supervision::Restarted => break,
+            _ => thread::sleep(Duration::from_millis(10)),
+        }
+    }
+
+    // Always still waits before restarting a sketch that exits right away
+    assert!(backing_off.unwrap() > Duration::from_millis(50));
+    assert_eq!(supervisor.history().len(), 1);
+    assert!(std::ptr::eq(view, supervisor.view()));
+    assert_eq!(supervisor.view().uart_channels[0].pending(), 4);
+    // Sketch output is only kept when asked for
+    assert_eq!(supervisor.uart_backlog(0), Some(vec![]));
+    assert_eq!(supervisor.uart_backlog(1), None);
+
+    // The pollers of the first run are done, whatever comes next is from the restarted sketch
+    let before: Vec<_> = events.try_iter().collect();
+    assert!(before.contains(&BoardEvent::DigitalChanged {
+        pin: 2,
+        old: false,
+        new: true
+    }));
+    let deadline = Instant::now() + PIN_TIMEOUT;
+    let raised = loop {
+        supervisor.tick()?;
+        match events.recv_timeout(Duration::from_millis(10)) {
+            Ok(BoardEvent::DigitalChanged { pin: 2, new, .. }) => break new,
+            _ if Instant::now() >= deadline => panic!("no pin change after the restart"),
+            _ => {}
+        }
+    };
+    assert!(raised);
+    supervisor.stop();
+    Ok(())
+}
+
+#[test]
+fn graceful_stop() -> anyhow::Result<()> {
+    let cleanup = build_sketch("./tests/sketches/cleanup", Default::default())?.0;
+    let noop = build_sketch("./tests/sketches/noop", Default::default())?.0;
+
+    let mut board = Board::new();
+    let handle = board.prepare(&Default::default(), &cleanup)?;
+    assert!(handle.start());
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(
+        handle.stop_graceful(Duration::from_secs(5)),
+        StopOutcome::Graceful(3)
+    );
+
+    // Without a handler the signal kills it, which is no graceful stop
+    let handle = board.prepare(&Default::default(), &noop)?;
+    assert!(handle.start());
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(
+        handle.stop_graceful(Duration::from_secs(5)),
+        StopOutcome::Terminated
+    );
+    Ok(())
+}
+
+// Plenty for a freshly started sketch to get going
+const PIN_TIMEOUT: Duration = Duration::from_secs(16);
+
+#[test]
+fn boardview_gpio() -> anyhow::Result<()> {
+    let mut board = Board::new();
+    let handle = start_pins(&mut board)?;
+
+    let bv = handle.view();
+
+    let pin0 = &bv.pins[0];
+    assert!(bv.pins.get(1).is_none());
+    let pin2 = &bv.pins[2];
+
+    thread::sleep(Duration::from_millis(1));
+
+    pin0.digital_write(false);
+
+    let read_log = || {
+        let mut buf = String::new();
+        let _ = handle.log().read_to_string(&mut buf);
+        buf
+    };
+
+    if let Err(err) = pin2.wait_for_level(true, PIN_TIMEOUT) {
+        panic!("{}\n{}", err, read_log());
+    }
+    pin0.digital_write(true);
+    let low = pin2.wait_for_level(false, PIN_TIMEOUT);
+    assert!(
+        matches!(low, Ok(Observed { value: false, .. })),
+        "{}",
+        read_log()
+    );
+
+    // Only built with --features tokio
+    #[cfg(feature = "tokio")]
+    {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .build()?;
+        let (high, low) = runtime.block_on(async {
+            pin0.digital_write(false);
+            let high = pin2.wait_for_level_async(true, PIN_TIMEOUT).await;
+            pin0.digital_write(true);
+            let low = pin2.wait_for_level_async(false, PIN_TIMEOUT).await;
+            (high, low)
+        });
+        assert!(high?.value);
+        assert!(!low?.value);
+    }
+
+    Ok(())
+}
+
+#[test]
+fn board_events() -> anyhow::Result<()> {
+    let mut board = Board::new();
+    let handle = prepare_pins(&mut board)?;
+    let events = handle.events(EventConfig::default());
+    assert!(handle.start());
+
+    let pin0 = &handle.view().pins[0];
+    pin0.digital_write(false);
+    thread::sleep(Duration::from_millis(100));
+    pin0.digital_write(true);
+
+    let mut seen = vec![];
+    for _ in 0..100 {
+        handle.tick().unwrap();
+        seen.extend(events.try_iter());
+        if seen.contains(&BoardEvent::DigitalChanged {
+            pin: 2,
+            old: true,
+            new: false,
+        }) {
+            break;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+
+    assert!(seen.contains(&BoardEvent::StatusChanged {
+        old: Status::Stopped,
+        new: Status::Running
+    }));
+    assert!(seen.iter().any(|event| matches!(event, BoardEvent::Log(_))));
+    assert!(seen.contains(&BoardEvent::DigitalChanged {
+        pin: 2,
+        old: true,
+        new: false
+    }));
+
+    handle.stop();
+    let rest: Vec<_> = events.iter().collect();
+    assert!(rest.contains(&BoardEvent::StatusChanged {
+        old: Status::Running,
+        new: Status::Stopped
+    }));
+    Ok(())
+}
+
+#[test]
+fn vcd_recording() -> anyhow::Result<()> {
+    let mut board = Board::new();
+    let handle = prepare_pins(&mut board)?;
+    let path = std::env::temp_dir().join(format!("smce-rs-{}.vcd", std::process::id()));
+    let recording = handle.record_vcd(&path, VcdConfig::default())?;
+    assert!(handle.start());
+
+    let pin0 = &handle.view().pins[0];
+    pin0.digital_write(false);
+    thread::sleep(Duration::from_millis(100));
+    pin0.digital_write(true);
+    thread::sleep(Duration::from_millis(100));
+
+    handle.stop();
+    recording.stop()?;
+
+    let vcd = fs::read_to_string(&path)?;
+    fs::remove_file(&path)?;
+    assert!(vcd.contains("$var wire 1 ! pin0 $end"));
+    assert!(vcd.contains("$var wire 1 # pin2 $end"));
+    // pin2 follows the inverse of pin0, so it has to have gone high and low again
+    assert!(vcd.contains("\n1#\n") && vcd.matches("\n0#\n").count() >= 2);
+    Ok(())
+}
+
+#[test]
+fn stimulus_playback() -> anyhow::Result<()> {
+    let mut board = Board::new();
+    let handle = prepare_pins(&mut board)?;
+    let events = handle.events(EventConfig::default());
+    assert!(handle.start());
+
+    let schedule =
+        Schedule::square(0, Duration::from_millis(200), 3).delay(Duration::from_millis(100));
+    assert!(matches!(
+        handle.play(Schedule::square(1, Duration::from_millis(1), 1)),
+        Err(StimulusError::UnknownPin(1))
+    ));
+    // The sketch drives pin 2 itself
+    assert!(matches!(
+        handle.play(Schedule::square(2, Duration::from_millis(1), 1)),
+        Err(StimulusError::NotAnInput(2))
+    ));
+    handle.play(schedule)?.wait();
+    thread::sleep(Duration::from_millis(100));
+    handle.tick().unwrap();
+
+    let falls = events
+        .try_iter()
+        .filter(|event| {
+            *event
+                == BoardEvent::DigitalChanged {
+                    pin: 2,
+                    old: true,
+                    new: false,
+                }
+        })
+        .count();
+    assert_eq!(falls, 3);
+    Ok(())
+}
+
+#[test]
+fn wait_for_edge() -> anyhow::Result<()> {
+    let mut board = Board::new();
+    let handle = start_pins(&mut board)?;
+    let pin2 = &handle.view().pins[2];
+    pin2.wait_for_level(true, PIN_TIMEOUT)?;
+
+    // The pulse only starts after 100ms, so the edge can not be missed
+    let pulse =
+        || Schedule::square(0, Duration::from_millis(200), 1).delay(Duration::from_millis(100));
+    let playback = handle.play(pulse())?;
+    let fall = pin2.wait_for_edge(Edge::Falling, Duration::from_secs(1))?;
+    assert!(!fall.value && fall.elapsed >= Duration::from_millis(50));
+    let timeout = pin2.wait_for_edge(Edge::Falling, Duration::from_millis(10));
+    assert!(timeout
+        .unwrap_err()
+        .to_string()
+        .contains("pin 2 to change level"));
+    let rise = pin2.wait_for_edge(Edge::Rising, Duration::from_secs(1))?;
+    assert!(rise.value);
+    playback.wait();
+
+    // Only built with --features tokio
+    #[cfg(feature = "tokio")]
+    {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .build()?;
+        let playback = handle.play(pulse())?;
+        // The futures own their pin, so they can run as tasks of their own
+        let (fall, timeout, rise) = runtime.block_on(async {
+            let fall =
+                tokio::spawn(pin2.wait_for_edge_async(Edge::Falling, Duration::from_secs(1)));
+            let fall = fall.await?;
+            let timeout =
+                tokio::spawn(pin2.wait_for_edge_async(Edge::Falling, Duration::from_millis(10)));
+            let timeout = timeout.await?;
+            let rise = tokio::spawn(pin2.wait_for_edge_async(Edge::Any, Duration::from_secs(1)));
+            anyhow::Ok((fall, timeout, rise.await?))
+        })?;
+        assert!(!fall?.value);
+        assert!(timeout.is_err());
+        assert!(rise?.value);
+        playback.wait();
+    }
+
+    handle.stop();
+    Ok(())
+}
+
+#[test]
+fn analog_signal() -> anyhow::Result<()> {
+    let mut board = Board::new();
+    let handle = start_pins(&mut board)?;
+
+    let signal = Source::step(100.0, 2.0).with_offset(400.0) + Source::noise(2.0).with_seed(1);
+    assert_eq!(
+        handle
+            .drive_analog(2, signal.clone(), SignalConfig::default())
+            .err(),
+        Some(SignalError::NotAnInput(2))
+    );
+
+    let driver = handle.drive_analog(0, signal, SignalConfig::default())?;
+    thread::sleep(Duration::from_millis(100));
+    let high = handle.view().pins[0].analog_read();
+    thread::sleep(Duration::from_millis(300));
+    let low = handle.view().pins[0].analog_read();
+    driver.stop();
+
+    assert!((480..=520).contains(&high), "{}", high);
+    assert!((280..=320).contains(&low), "{}", low);
+    Ok(())
+}
+
+#[test]
+fn pin_meter() -> anyhow::Result<()> {
+    let mut board = Board::new();
+    let handle = start_pins(&mut board)?;
+    assert!(handle.meter(1, MeterConfig::default()).is_none());
+
+    let meter = handle.meter(2, MeterConfig::default()).unwrap();
+    handle
+        .play(Schedule::pulses(
+            0,
+            Duration::from_millis(25),
+            Duration::from_millis(100),
+            15,
+        ))?
+        .wait();
+
+    // pin2 mirrors the inverse of pin0, 10 Hz low for 25%
+    let report = meter.report();
+    let frequency = report.frequency.unwrap();
+    let duty_cycle = report.duty_cycle.unwrap();
+    assert!((9.0..11.0).contains(&frequency), "{}", frequency);
+    assert!((0.65..0.85).contains(&duty_cycle), "{}", duty_cycle);
+    Ok(())
+}
+
+#[test]
+fn simulation() -> anyhow::Result<()> {
+    let mut board = Board::new();
+    let handle = start_pins(&mut board)?;
+
+    let mut sim = Simulation::new(handle, Duration::from_millis(5));
+    assert_eq!(sim.add(Led::new(3)), Err(PeripheralError::MissingPin(3)));
+    let led = sim.add(Led::new(2))?;
+
+    let _playback = sim
+        .handle()
+        .play(Schedule::square(0, Duration::from_millis(100), 5))?;
+    let toggled = sim
+        .run_until(
+            |sim| sim.peripheral::<Led>(led).unwrap().toggles() >= 4,
+            Duration::from_secs(2),
+        )
+        .unwrap();
+    assert!(toggled);
+    assert!(sim.peripheral::<Led>(led).unwrap().on_time() > Duration::ZERO);
+    Ok(())
+}
+
+#[test]
+fn input_devices() -> anyhow::Result<()> {
+    let mut board = Board::new();
+    let handle = start_pins(&mut board)?;
+
+    let mut sim = Simulation::new(handle, Duration::from_millis(1));
+    assert_eq!(
+        sim.add(ToggleSwitch::new(2, false)),
+        Err(PeripheralError::NotAnInput(2))
+    );
+    let switch = sim.add(ToggleSwitch::new(0, true))?;
+    let led = sim.add(Led::new(2))?;
+    let led_on = |sim: &Simulation, on: bool| sim.peripheral::<Led>(led).unwrap().is_on() == on;
+
+    assert!(sim
+        .run_until(|sim| led_on(sim, false), Duration::from_secs(2))
+        .unwrap());
+    sim.peripheral_mut::<ToggleSwitch>(switch).unwrap().toggle();
+    assert!(sim
+        .run_until(|sim| led_on(sim, true), Duration::from_secs(2))
+        .unwrap());
+
+    // Swap the switch for a bouncy button on the same pin, pressing pulls it low
+    let mut sim = Simulation::new(sim.into_handle(), Duration::from_millis(1));
+    let button = sim.add(PushButton::new(0).with_bounce(Bounce {
+        duration: Duration::from_millis(20),
+        transitions: 6,
+        seed: 1,
+    }))?;
+    let led = sim.add(Led::new(2))?;
+    sim.run_for(Duration::from_millis(50)).unwrap();
+    sim.peripheral_mut::<PushButton>(button).unwrap().press();
+    sim.run_for(Duration::from_millis(100)).unwrap();
+
+    let led = sim.peripheral::<Led>(led).unwrap();
+    assert!(led.is_on());
+    assert!(led.toggles() > 1, "{}", led.toggles());
+    Ok(())
+}
+
+// Steps the simulation until the sketch printed `expected` on its first uart
+fn sim_until_output(sim: &mut Simulation, expected: &str) -> bool {
+    let mut output = String::new();
+    sim.run_until(
+        |sim| {
+            let mut buf = [0; 64];
+            let mut uart0 = &sim.view().uart_channels[0];
+            let read = uart0.read(&mut buf).unwrap();
+            output.push_str(&String::from_utf8_lossy(&buf[..read]));
+            output.contains(expected)
+        },
+        PIN_TIMEOUT,
+    )
+    .unwrap()
+}
+
+#[test]
+fn keypad_and_encoder() -> anyhow::Result<()> {
+    let sketch = build_sketch("./tests/sketches/inputs", Default::default())?.0;
+
+    // The sketch drives the keypad rows on 0-3 and reads everything else
+    let mut board = Board::new();
+    let handle = board.prepare(
+        &BoardConfig {
+            gpio_drivers: (0..10)
+                .map(|pin_id| GpioDriver {
+                    pin_id,
+                    allow_read: pin_id >= 4,
+                    allow_write: pin_id < 4,
+                })
+                .collect(),
+            uart_channels: vec![UartChannel::default()],
+            ..Default::default()
+        },
+        &sketch,
+    )?;
+    assert!(handle.start());
+
+    let mut sim = Simulation::new(handle, Duration::from_millis(1));
+    let keypad = sim.add(Keypad::new([0, 1, 2, 3], [4, 5, 6, 7]))?;
+    // Slow enough for the sketch to see every phase while scanning the keypad
+    let encoder = sim.add(RotaryEncoder::new(8, 9).with_phase(Duration::from_millis(20)))?;
+
+    sim.peripheral_mut::<Keypad>(keypad).unwrap().press('5');
+    assert!(sim_until_output(&mut sim, "KEY 5\r\n"));
+    let keypad = sim.peripheral_mut::<Keypad>(keypad).unwrap();
+    keypad.release_all();
+    keypad.press('#');
+    assert!(sim_until_output(&mut sim, "KEY #\r\n"));
+
+    sim.peripheral_mut::<RotaryEncoder>(encoder)
+        .unwrap()
+        .rotate(2);
+    assert!(sim_until_output(&mut sim, "POS 2\r\n"));
+    sim.peripheral_mut::<RotaryEncoder>(encoder)
+        .unwrap()
+        .rotate(-1);
+    assert!(sim_until_output(&mut sim, "POS 1\r\n"));
+    assert_eq!(
+        sim.peripheral::<RotaryEncoder>(encoder).unwrap().position(),
+        1
+    );
+    Ok(())
+}
+
+#[test]
+fn vehicle_odometers() -> anyhow::Result<()> {
+    let sketch = build_sketch("./tests/sketches/car", Default::default())?.0;
+
+    // Motors on 0-3, odometers on 4 and 5
+    let mut board = Board::new();
+    let handle = board.prepare(
+        &BoardConfig {
+            gpio_drivers: (0..6)
+                .map(|pin_id| GpioDriver {
+                    pin_id,
+                    allow_read: pin_id >= 4,
+                    allow_write: pin_id < 4,
+                })
+                .collect(),
+            uart_channels: vec![UartChannel::default()],
+            ..Default::default()
+        },
+        &sketch,
+    )?;
+    assert!(handle.start());
+
+    let motor = |pwm, direction| Motor {
+        pwm,
+        direction,
+        reversed: false,
+    };
+    let pulses_per_meter = 20.0;
+    let mut sim = Simulation::new(handle, Duration::from_millis(1));
+    let car = sim.add(Vehicle::new(VehicleConfig {
+        left: motor(0, 1),
+        right: motor(2, 3),
+        odometers: Some(Odometers {
+            left: 4,
+            right: 5,
+            pulses_per_meter,
+        }),
+        wheel_base: 0.2,
+        max_speed: 0.5,
+        pwm_max: 255,
+        motor_response: Duration::ZERO,
+    }))?;
+
+    // Far enough for a couple of pulses
+    assert!(sim
+        .run_until(
+            |sim| sim.peripheral::<Vehicle>(car).unwrap().distance() > 0.5,
+            Duration::from_secs(10)
+        )
+        .unwrap());
+    let mut output = String::new();
+    sim.run_until(
+        |sim| {
+            let mut buf = [0; 64];
+            let mut uart0 = &sim.view().uart_channels[0];
+            let read = uart0.read(&mut buf).unwrap();
+            output.push_str(&String::from_utf8_lossy(&buf[..read]));
+            output.matches("ODO").count() > 1 && output.ends_with("\r\n")
+        },
+        PIN_TIMEOUT,
+    )
+    .unwrap();
+
+    let vehicle = sim.peripheral::<Vehicle>(car).unwrap();
+    let pose = vehicle.pose();
+    assert!(pose.x > 0.5 && pose.y.abs() < 1e-6, "{:?}", pose);
+    let report = output.lines().rev().find(|line| line.starts_with("ODO"));
+    let counts: Vec<f64> = report
+        .unwrap()
+        .split_whitespace()
+        .skip(1)
+        .map(|count| count.parse().unwrap())
+        .collect();
+    // Reports lag the car by up to 100 ms
+    let expected = vehicle.distance() * pulses_per_meter;
+    for count in counts {
+        assert!(
+            count <= expected + 1.0 && count >= expected - 3.0,
+            "{} {}",
+            count,
+            expected
+        );
+    }
+    Ok(())
+}
+
+#[test]
+fn ultrasonic_ping() -> anyhow::Result<()> {
+    let sketch = build_sketch("./tests/sketches/ping", Default::default())?.0;
+
+    let mut board = Board::new();
+    let handle = board.prepare(
+        &BoardConfig {
+            gpio_drivers: vec![
+                GpioDriver {
+                    pin_id: 0,
+                    allow_read: false,
+                    allow_write: true,
+                },
+                GpioDriver {
+                    pin_id: 1,
+                    allow_read: true,
+                    allow_write: false,
+                },
+            ],
+            uart_channels: vec![UartChannel::default()],
+            ..Default::default()
+        },
+        &sketch,
+    )?;
+    assert!(handle.start());
+
+    // A wall a meter ahead
+    let world = Rc::new(World::new().push((1.0, -1.0), (1.0, 1.0)));
+    let interval = Duration::from_millis(1);
+    let mut sim = Simulation::new(handle, interval);
+    let sensor = sim.add(
+        UltrasonicSensor::new(
+            world,
+            PoseTracker::fixed(Pose::default()),
+            Pose::default(),
+            0,
+            1,
+        )
+        .with_beam(0.0),
+    )?;
+
+    let mut output = String::new();
+    sim.run_until(
+        |sim| {
+            let mut buf = [0; 64];
+            let mut uart0 = &sim.view().uart_channels[0];
+            let read = uart0.read(&mut buf).unwrap();
+            output.push_str(&String::from_utf8_lossy(&buf[..read]));
+            output.matches("\r\n").count() >= 3 && output.ends_with("\r\n")
+        },
+        PIN_TIMEOUT,
+    )
+    .unwrap();
+
+    assert_eq!(
+        sim.peripheral::<UltrasonicSensor>(sensor)
+            .unwrap()
+            .distance(),
+        Some(1.0)
+    );
+    // The echo is only as wide as whole steps, plus the time it takes the sketch to notice
+    let resolution = UltrasonicSensor::resolution(interval);
+    for line in output.lines() {
+        let cm: f64 = line.trim_start_matches("CM ").parse()?;
+        assert!((cm / 100.0 - 1.0).abs() <= 2.0 * resolution, "{}", line);
+    }
+    Ok(())
+}
+
+#[test]
+fn servo_and_stepper() -> anyhow::Result<()> {
+    let sketch = build_sketch("./tests/sketches/actuators", Default::default())?.0;
+
+    let mut board = Board::new();
+    let handle = board.prepare(
+        &BoardConfig {
+            gpio_drivers: (0..3)
+                .map(|pin_id| GpioDriver {
+                    pin_id,
+                    allow_read: false,
+                    allow_write: true,
+                })
+                .collect(),
+            uart_channels: vec![UartChannel::default()],
+            ..Default::default()
+        },
+        &sketch,
+    )?;
+    assert!(handle.start());
+
+    // Every pulse is shorter than a step, only timing edges between steps catches them
+    let mut sim = Simulation::new(handle, Duration::from_millis(1));
+    let servo = sim.add(Servo::new(0, ServoConfig::default()))?;
+    let stepper = sim.add(Stepper::new(1, 2))?;
+    (&sim.view().uart_channels[0]).write_all(b"go")?;
+
+    assert!(sim_until_output(&mut sim, "STEPPED\r\n"));
+    assert_eq!(sim.peripheral::<Stepper>(stepper).unwrap().position(), 100);
+
+    // A few pulses in and far enough to have turned all the way
+    sim.run_for(Duration::from_millis(300)).unwrap();
+    let angle = sim.peripheral::<Servo>(servo).unwrap().angle();
+    // Off by the sample interval on both edges, plus the sketch's own delay accuracy
+    assert!((angle - 135.0).abs() < 10.0, "{}", angle);
+    Ok(())
+}
+
+#[test]
+fn simulation_reports_exit() -> anyhow::Result<()> {
+    let sketch = build_sketch("./tests/sketches/uncaught", Default::default())?.0;
+
+    let mut board = Board::new();
+    let handle = board.prepare(&Default::default(), &sketch)?;
+    assert!(handle.start());
+
+    let mut sim = Simulation::new(handle, Duration::from_millis(10));
+    let outcome = sim.run_for(Duration::from_secs(5));
+    assert!(matches!(outcome, Err(ExitStatus::Exited(code)) if code != 0));
+    sim.into_handle().stop();
+    Ok(())
+}
+
+// Reads until `len` bytes arrived or PIN_TIMEOUT passed, whatever the reader wraps the uart in
+fn read_uart(mut uart: impl Read, len: usize) -> std::io::Result<Vec<u8>> {
+    let mut read = vec![];
+    let deadline = Instant::now() + PIN_TIMEOUT;
+    while read.len() < len && Instant::now() < deadline {
+        let mut buf = [0; 64];
+        let want = (len - read.len()).min(buf.len());
+        match uart.read(&mut buf[..want])? {
+            0 => thread::sleep(Duration::from_millis(1)),
+            n => read.extend_from_slice(&buf[..n]),
+        }
+    }
+    Ok(read)
+}
+
+#[test]
+fn uart() -> anyhow::Result<()> {
+    let sketch = build_sketch("./tests/sketches/uart", Default::default())?.0;
+
+    let mut board = Board::new();
+    let handle = board.prepare(
+        &BoardConfig {
+            uart_channels: vec![UartChannel::default()],
+            ..Default::default()
+        },
+        &sketch,
+    )?;
+    assert!(handle.start());
+
+    let mut uart0 = &handle.view().uart_channels[0];
+
+    let mut echo_test = |input: &str| {
+        assert_eq!(uart0.write(input.as_bytes()).unwrap(), input.len());
+        assert_eq!(read_uart(uart0, input.len()).unwrap(), input.as_bytes());
+    };
+
+    echo_test("HELLO UART");
+    echo_test("RUST IS COOL!");
+
+    Ok(())
+}
+
 #[test]
-fn noop_compile() -> anyhow::Result<()> {
-    let sketch = build_sketch("./tests/sketches/noop", Default::default())?.0;
-    assert!(sketch.compiled());
+fn uart_expect() -> anyhow::Result<()> {
+    let sketch = build_sketch("./tests/sketches/uart", Default::default())?.0;
+
+    let mut board = Board::new();
+    let handle = board.prepare(
+        &BoardConfig {
+            uart_channels: vec![UartChannel::default()],
+            ..Default::default()
+        },
+        &sketch,
+    )?;
+    assert!(handle.start());
+
+    let mut session = Expect::new(&handle.view().uart_channels[0]);
+
+    session.send_line("VALUE=42")?;
+    let captures = session.expect(r"VALUE=(?P<value>\d+)\n", Duration::from_secs(16))?;
+    assert_eq!(captures.name("value"), Some("42"));
+
+    session.send("READY")?;
+    let (index, _) = session.expect_any(&["ERROR", "READY"], Duration::from_secs(16))?;
+    assert_eq!(index, 1);
+
+    let err = session
+        .expect("NEVER", Duration::from_millis(100))
+        .unwrap_err();
+    assert!(matches!(err, ExpectError::Timeout { .. }));
+    assert!(err.to_string().contains("<< \"READY\""));
+
     Ok(())
 }
 
 #[test]
-fn tick_crash() -> anyhow::Result<()> {
-    let sketch = build_sketch("./tests/sketches/uncaught", Default::default())?.0;
+fn uart_paced() -> anyhow::Result<()> {
+    let sketch = build_sketch("./tests/sketches/uart", Default::default())?.0;
 
     let mut board = Board::new();
-    let handle = board.prepare(&Default::default(), &sketch)?;
+    let handle = board.prepare(
+        &BoardConfig {
+            uart_channels: vec![UartChannel {
+                baud_rate: 9600,
+                rx_buffer_length: 128,
+                tx_buffer_length: 128,
+                ..Default::default()
+            }],
+            ..Default::default()
+        },
+        &sketch,
+    )?;
     assert!(handle.start());
 
-    for _ in 0..10 {
-        if handle.tick().is_err() {
-            break;
-        }
-        thread::sleep(Duration::from_millis(100));
-    }
+    // 8N1 at 9600 baud carries 960 bytes per second
+    let mut uart0 = handle.view().uart_channels[0].paced(FrameFormat::default());
+    let payload = [b'A'; 96];
+    let start = Instant::now();
+    uart0.write_all(&payload)?;
+    assert!(start.elapsed() >= Duration::from_millis(90));
+
+    assert_eq!(read_uart(&mut uart0, payload.len())?, payload);
+
+    let stats = uart0.stats();
+    assert_eq!((stats.sent, stats.received), (96, 96));
+    assert!(stats.utilization() <= 1.0);
 
-    assert_eq!(handle.status(), Status::Stopped);
-    assert_ne!(handle.stop(), 0, "Expected non zero exitcode");
     Ok(())
 }
 
 #[test]
-fn suspend_resume() -> anyhow::Result<()> {
-    let sketch = build_sketch("./tests/sketches/noop", Default::default())?.0;
+fn uart_faults() -> anyhow::Result<()> {
+    let sketch = build_sketch("./tests/sketches/uart", Default::default())?.0;
 
     let mut board = Board::new();
-    let handle = board.prepare(&Default::default(), &sketch)?;
+    let handle = board.prepare(
+        &BoardConfig {
+            uart_channels: vec![UartChannel::default()],
+            ..Default::default()
+        },
+        &sketch,
+    )?;
     assert!(handle.start());
 
-    assert_eq!(handle.status(), Status::Running);
-    assert!(handle.suspend());
-    assert_eq!(handle.status(), Status::Suspended);
-    assert!(handle.resume());
-    assert_eq!(handle.status(), Status::Running);
-    assert!(!handle.resume());
+    let mut uart0 = handle.view().uart_channels[0]
+        .faulty(FaultConfig {
+            bit_flip: 1.0,
+            seed: 3,
+            ..Default::default()
+        })
+        .only(Direction::Sent);
 
+    uart0.write_all(b"HELLO UART")?;
+    uart0.flush()?;
+
+    let echoed = read_uart(&mut uart0, 10)?;
+
+    let corrupted: Vec<_> = uart0
+        .log()
+        .iter()
+        .map(|fault| match fault.kind {
+            FaultKind::BitFlip { corrupted, .. } => corrupted,
+            _ => panic!("unexpected fault {:?}", fault),
+        })
+        .collect();
+    assert_eq!(corrupted.len(), 10);
+    assert_ne!(echoed, b"HELLO UART");
+    assert_eq!(echoed, corrupted);
+
+    Ok(())
+}
+
+#[test]
+fn uart_record_replay() -> anyhow::Result<()> {
+    let sketch = build_sketch("./tests/sketches/uart", Default::default())?.0;
+    let config = BoardConfig {
+        uart_channels: vec![UartChannel::default()],
+        ..Default::default()
+    };
+
+    let mut board = Board::new();
+    let handle = board.prepare(&config, &sketch)?;
+    assert!(handle.start());
+
+    let recorder = Recorder::default();
+    let mut uart0 = recorder.wrap(0, &handle.view().uart_channels[0]);
+    uart0.write_all(b"RECORD ME")?;
+    assert_eq!(read_uart(&mut uart0, 9)?, b"RECORD ME");
+    drop(board);
+
+    let mut csv = vec![];
+    recorder.recording().write_csv(&mut csv)?;
+    let mut recording = Recording::from_csv(csv.as_slice())?;
+
+    let replay = |recording: &Recording| -> anyhow::Result<_> {
+        let mut board = Board::new();
+        let handle = board.prepare(&config, &sketch)?;
+        assert!(handle.start());
+        Ok(recording.replay(handle.view(), Duration::from_millis(500))?)
+    };
+    assert!(replay(&recording)?.matches());
+
+    let answer = recording
+        .events
+        .iter_mut()
+        .find(|event| event.direction == Direction::Received)
+        .unwrap();
+    answer.bytes = b"SOMETHING ELSE".to_vec();
+    let report = replay(&recording)?;
+    assert!(matches!(
+        report.mismatches.as_slice(),
+        [Mismatch::Bytes {
+            channel: 0,
+            offset: 0,
+            ..
+        }]
+    ));
+
+    // A sketch that never reads has no room for more than its buffer holds
+    let noop = build_sketch("./tests/sketches/noop", Default::default())?.0;
+    let mut board = Board::new();
+    let handle = board.prepare(
+        &BoardConfig {
+            uart_channels: vec![UartChannel {
+                rx_buffer_length: 16,
+                ..Default::default()
+            }],
+            ..Default::default()
+        },
+        &noop,
+    )?;
+    assert!(handle.start());
+    let sent = recording
+        .events
+        .iter_mut()
+        .find(|event| event.direction == Direction::Sent)
+        .unwrap();
+    sent.bytes = vec![b'x'; 64];
+    let sent_at = sent.at;
+    let report = recording.replay(handle.view(), Duration::from_millis(100))?;
+    assert_eq!(
+        report.mismatches,
+        [Mismatch::Unsent {
+            channel: 0,
+            expected: sent_at
+        }]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn uart_split() -> anyhow::Result<()> {
+    let sketch = build_sketch("./tests/sketches/uart", Default::default())?.0;
+
+    let mut board = Board::new();
+    let handle = board.prepare(
+        &BoardConfig {
+            uart_channels: vec![UartChannel::default()],
+            ..Default::default()
+        },
+        &sketch,
+    )?;
+    assert!(handle.start());
+
+    let (mut rx, mut tx) = handle.view().uart_channels[0].split();
+    let echoed = thread::scope(|scope| {
+        scope.spawn(move || tx.write_all(b"SPLIT UART").unwrap());
+        let reader = scope.spawn(move || read_uart(&mut rx, 10).unwrap());
+        reader.join().unwrap()
+    });
+    assert_eq!(echoed, b"SPLIT UART");
+
+    handle.stop();
+    Ok(())
+}
+
+#[test]
+fn uart_flush_after_exit() -> anyhow::Result<()> {
+    let sketch = build_sketch("./tests/sketches/uncaught", Default::default())?.0;
+
+    let mut board = Board::new();
+    let handle = board.prepare(
+        &BoardConfig {
+            uart_channels: vec![UartChannel::default()],
+            ..Default::default()
+        },
+        &sketch,
+    )?;
+    assert!(handle.start());
+
+    // The sketch never reads, flushing only ends once the ticking below notices the exit
+    let (_, mut tx) = handle.view().uart_channels[0].split();
+    tx.write_all(b"NEVER READ")?;
+    let flushed = thread::scope(|scope| {
+        let flusher = scope.spawn(move || tx.flush());
+        let deadline = Instant::now() + PIN_TIMEOUT;
+        while handle.tick().is_ok() && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(1));
+        }
+        flusher.join().unwrap()
+    });
+    assert_eq!(flushed.unwrap_err().kind(), std::io::ErrorKind::BrokenPipe);
     Ok(())
 }
 
-fn test_digital_pin_delayable(pin: &GpioPin, expected_value: bool) -> bool {
-    for _ in 0..16384 {
-        if pin.digital_read() == expected_value {
-            return true;
+#[test]
+fn uart_backpressure() -> anyhow::Result<()> {
+    let sketch = build_sketch("./tests/sketches/uart", Default::default())?.0;
+
+    let mut board = Board::new();
+    let handle = board.prepare(
+        &BoardConfig {
+            uart_channels: vec![UartChannel {
+                rx_buffer_length: 16,
+                tx_buffer_length: 512,
+                ..Default::default()
+            }],
+            ..Default::default()
+        },
+        &sketch,
+    )?;
+    assert!(handle.start());
+
+    let uart0 = &handle.view().uart_channels[0];
+    assert_eq!(uart0.max_write(), 16);
+    assert_eq!(uart0.max_read(), 512);
+    assert_eq!(uart0.peek(), None);
+
+    // Far more than fits in the buffer at once
+    let payload: String = (0..200).map(|i| (b'a' + i % 26) as char).collect();
+    uart0.write_all_timeout(payload.as_bytes(), PIN_TIMEOUT)?;
+    uart0.flush_timeout(PIN_TIMEOUT)?;
+    assert_eq!(uart0.pending(), 0);
+
+    let mut echoed = String::new();
+    for _ in 0..16000 {
+        if uart0.readable() > 0 {
+            assert_eq!(uart0.peek(), payload.as_bytes().get(echoed.len()).copied());
+        }
+        (&*uart0).read_to_string(&mut echoed)?;
+        if echoed.len() >= payload.len() {
+            break;
         }
         thread::sleep(Duration::from_millis(1));
     }
-    false
+    assert_eq!(echoed, payload);
+
+    handle.stop();
+    Ok(())
 }
 
-fn test_analog_pin_delayable(pin: &GpioPin, expected_value: u16) -> bool {
-    for _ in 0..16384 {
-        if pin.analog_read() == expected_value {
-            return true;
+// The async tests below only build with --features tokio, CI runs with all features
+#[cfg(feature = "tokio")]
+#[test]
+fn async_uart() -> anyhow::Result<()> {
+    use std::future::poll_fn;
+    use std::pin::Pin;
+    use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+    let sketch = build_sketch("./tests/sketches/uart", Default::default())?.0;
+
+    let mut board = Board::new();
+    let handle = board.prepare(
+        &BoardConfig {
+            uart_channels: vec![UartChannel::default()],
+            ..Default::default()
+        },
+        &sketch,
+    )?;
+    assert!(handle.start());
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_time()
+        .build()?;
+    let (mut rx, mut tx) = handle.view().uart_channels[0].split_async();
+    let input = b"ASYNC UART";
+    let echoed = runtime.block_on(async {
+        let mut written = 0;
+        while written < input.len() {
+            written += poll_fn(|cx| Pin::new(&mut tx).poll_write(cx, &input[written..])).await?;
         }
-        thread::sleep(Duration::from_millis(1));
+        poll_fn(|cx| Pin::new(&mut tx).poll_flush(cx)).await?;
+
+        let mut echoed = vec![];
+        while echoed.len() < input.len() {
+            let mut chunk = [0; 64];
+            let mut buf = ReadBuf::new(&mut chunk);
+            let read = poll_fn(|cx| Pin::new(&mut rx).poll_read(cx, &mut buf));
+            tokio::time::timeout(PIN_TIMEOUT, read).await??;
+            echoed.extend_from_slice(buf.filled());
+        }
+        anyhow::Ok(echoed)
+    })?;
+    assert_eq!(echoed, input);
+
+    handle.stop();
+    Ok(())
+}
+
+#[cfg(feature = "tokio")]
+#[test]
+fn async_build_log() -> anyhow::Result<()> {
+    use std::future::poll_fn;
+    use std::pin::Pin;
+    use tokio::io::{AsyncRead, ReadBuf};
+
+    let (tc, log) = Toolchain::new(TEST_HOME)?;
+    let mut log = log.into_async();
+    let mut sketch = Sketch::new("./tests/sketches/noop", Default::default()).unwrap();
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_time()
+        .build()?;
+    let (compiled, output) = thread::scope(|scope| {
+        let compiler = scope.spawn(|| tc.compile(&mut sketch));
+        // Reading ends with an empty read once the compilation is done
+        let output = runtime.block_on(async {
+            let mut output = vec![];
+            loop {
+                let mut chunk = [0; 256];
+                let mut buf = ReadBuf::new(&mut chunk);
+                let read = poll_fn(|cx| Pin::new(&mut log).poll_read(cx, &mut buf));
+                tokio::time::timeout(Duration::from_secs(600), read).await??;
+                if buf.filled().is_empty() {
+                    break;
+                }
+                output.extend_from_slice(buf.filled());
+            }
+            anyhow::Ok(output)
+        });
+        (compiler.join().unwrap(), output)
+    });
+    compiled?;
+    assert!(sketch.compiled());
+    assert!(!output?.is_empty());
+    Ok(())
+}
+
+#[cfg(feature = "tokio")]
+#[test]
+fn async_wait() -> anyhow::Result<()> {
+    let sketch = build_sketch("./tests/sketches/uncaught", Default::default())?.0;
+
+    use std::future::{poll_fn, Future};
+    use std::pin::Pin;
+    use tokio::io::AsyncWrite;
+
+    let mut board = Board::new();
+    let mut handle = board.prepare(
+        &BoardConfig {
+            uart_channels: vec![UartChannel::default()],
+            ..Default::default()
+        },
+        &sketch,
+    )?;
+    assert!(handle.start());
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_time()
+        .build()?;
+    let (_, mut tx) = handle.view().uart_channels[0].split_async();
+    runtime.block_on(poll_fn(|cx| {
+        Pin::new(&mut tx).poll_write(cx, b"NEVER READ")
+    }))?;
+
+    fn sendable<F: Future + Send>(future: F) -> F {
+        future
     }
-    false
+    let status = runtime.block_on(async {
+        tokio::time::timeout(Duration::from_secs(5), sendable(handle.wait_async())).await
+    })?;
+
+    // The sketch never read what was written, flushing gives up now that it exited
+    let (_, mut tx) = handle.view().uart_channels[0].split_async();
+    let flushed = runtime.block_on(async {
+        tokio::time::timeout(
+            Duration::from_secs(5),
+            poll_fn(|cx| Pin::new(&mut tx).poll_flush(cx)),
+        )
+        .await
+    })?;
+    assert!(matches!(status, ExitStatus::Exited(code) if code != 0));
+    assert_eq!(flushed.unwrap_err().kind(), std::io::ErrorKind::BrokenPipe);
+    handle.stop();
+    Ok(())
 }
 
+#[cfg(target_os = "linux")]
 #[test]
-fn boardview_gpio() -> anyhow::Result<()> {
-    let sketch = build_sketch("./tests/sketches/pins", Default::default())?.0;
+fn uart_pty_bridge() -> anyhow::Result<()> {
+    let sketch = build_sketch("./tests/sketches/uart", Default::default())?.0;
 
     let mut board = Board::new();
     let handle = board.prepare(
         &BoardConfig {
-            gpio_drivers: vec![
-                GpioDriver {
-                    pin_id: 0,
-                    allow_read: true,
-                    allow_write: false,
-                },
-                GpioDriver {
-                    pin_id: 2,
-                    allow_read: false,
-                    allow_write: true,
-                },
-            ],
+            uart_channels: vec![UartChannel::default()],
             ..Default::default()
         },
         &sketch,
     )?;
     assert!(handle.start());
 
-    let bv = handle.view();
+    let link = std::env::temp_dir().join(format!("smce-pty-{}", std::process::id()));
+    let bridge = handle.view().uart_channels[0].bridge_pty(Some(&link))?;
+    assert!(bridge.path().starts_with("/dev/pts"));
+    assert_eq!(fs::read_link(&link)?, bridge.path());
 
-    let pin0 = &bv.pins[0];
-    assert!(bv.pins.get(1).is_none());
-    let pin2 = &bv.pins[2];
+    let mut port = fs::OpenOptions::new().read(true).write(true).open(&link)?;
+    port.write_all(b"HELLO PTY")?;
 
-    thread::sleep(Duration::from_millis(1));
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let mut buf = [0; 9];
+        let _ = sender.send(port.read_exact(&mut buf).map(|_| buf));
+    });
+    let echoed = receiver.recv_timeout(PIN_TIMEOUT)??;
+    assert_eq!(&echoed, b"HELLO PTY");
 
-    pin0.digital_write(false);
+    bridge.stop()?;
+    assert!(fs::symlink_metadata(&link).is_err());
 
-    let read_log = || {
-        let mut buf = String::new();
-        let _ = handle.log().read_to_string(&mut buf);
-        buf
-    };
+    // Dropping cleans up as well
+    let bridge = handle.view().uart_channels[0].bridge_pty(Some(&link))?;
+    assert_eq!(fs::read_link(&link)?, bridge.path());
+    drop(bridge);
+    assert!(fs::symlink_metadata(&link).is_err());
+    handle.stop();
+    Ok(())
+}
 
-    assert!(test_digital_pin_delayable(pin2, true), "{}", read_log());
-    pin0.digital_write(true);
-    assert!(test_digital_pin_delayable(pin2, false), "{}", read_log());
+#[test]
+fn uart_tcp_bridge() -> anyhow::Result<()> {
+    let sketch = build_sketch("./tests/sketches/uart", Default::default())?.0;
+
+    let mut board = Board::new();
+    let handle = board.prepare(
+        &BoardConfig {
+            uart_channels: vec![UartChannel::default()],
+            ..Default::default()
+        },
+        &sketch,
+    )?;
+    assert!(handle.start());
+
+    let bridge = handle.view().uart_channels[0].serve_tcp("127.0.0.1:0")?;
+    let mut client = TcpStream::connect(bridge.local_addr())?;
+    client.set_read_timeout(Some(PIN_TIMEOUT))?;
+    client.write_all(b"HELLO TCP")?;
+    let mut echoed = [0; 9];
+    client.read_exact(&mut echoed)?;
+    assert_eq!(&echoed, b"HELLO TCP");
+    assert!(bridge.is_connected());
+
+    // A second client is turned away while the first is connected
+    let mut other = TcpStream::connect(bridge.local_addr())?;
+    other.set_read_timeout(Some(PIN_TIMEOUT))?;
+    assert_eq!(other.read(&mut echoed)?, 0);
 
+    // Stopping and dropping both let go of the port
+    let addr = bridge.local_addr();
+    bridge.stop()?;
+    drop(TcpListener::bind(addr)?);
+    let bridge = handle.view().uart_channels[0].serve_tcp(addr)?;
+    drop(bridge);
+    drop(TcpListener::bind(addr)?);
+
+    handle.stop();
     Ok(())
 }
 
 #[test]
-fn uart() -> anyhow::Result<()> {
+fn uart_telnet_bridge() -> anyhow::Result<()> {
+    const IAC: u8 = 255;
+    const DONT: u8 = 254;
+    const DO: u8 = 253;
+    const WILL: u8 = 251;
+    const SB: u8 = 250;
+    const SE: u8 = 240;
+    const COM_PORT_OPTION: u8 = 44;
+    const TERMINAL_TYPE: u8 = 24;
+
     let sketch = build_sketch("./tests/sketches/uart", Default::default())?.0;
 
     let mut board = Board::new();
@@ -161,24 +1643,36 @@ fn uart() -> anyhow::Result<()> {
     )?;
     assert!(handle.start());
 
-    let mut uart0 = &handle.view().uart_channels[0];
+    let bridge = handle.view().uart_channels[0].serve_telnet("127.0.0.1:0")?;
+    let mut client = TcpStream::connect(bridge.local_addr())?;
+    client.set_read_timeout(Some(PIN_TIMEOUT))?;
 
-    let mut echo_test = |input: &str| {
-        assert_eq!(uart0.write(input.as_bytes()).unwrap(), input.len());
+    // Binary and suppress go ahead both ways, the server leaves offering com port control to us
+    let mut opening = [0; 12];
+    client.read_exact(&mut opening)?;
+    assert_eq!(
+        opening,
+        [IAC, WILL, 0, IAC, DO, 0, IAC, WILL, 3, IAC, DO, 3]
+    );
 
-        let mut buf = String::with_capacity(input.len());
-        for _ in 0..16000 {
-            if uart0.read_to_string(&mut buf).unwrap() > 0 {
-                break;
-            }
-            thread::sleep(Duration::from_millis(1));
-        }
-        assert_eq!(buf, input);
-    };
+    // Com port control and a terminal type, then 115200 baud and data with an escaped IAC
+    let mut request = vec![IAC, WILL, COM_PORT_OPTION, IAC, WILL, TERMINAL_TYPE];
+    request.extend_from_slice(&[IAC, SB, COM_PORT_OPTION, 1, 0, 1, 0xc2, 0, IAC, SE]);
+    request.extend_from_slice(b"TELNET");
+    request.extend_from_slice(&[IAC, IAC]);
+    client.write_all(&request)?;
 
-    echo_test("HELLO UART");
-    echo_test("RUST IS COOL!");
+    let mut expected = vec![IAC, DO, COM_PORT_OPTION, IAC, DONT, TERMINAL_TYPE];
+    expected.extend_from_slice(&[IAC, SB, COM_PORT_OPTION, 101, 0, 1, 0xc2, 0, IAC, SE]);
+    expected.extend_from_slice(b"TELNET");
+    expected.extend_from_slice(&[IAC, IAC]);
+    let mut answer = vec![0; expected.len()];
+    client.read_exact(&mut answer)?;
+    assert_eq!(answer, expected);
+    assert_eq!(bridge.baud_rate(), Some(115200));
 
+    bridge.stop()?;
+    handle.stop();
     Ok(())
 }
 
@@ -249,7 +1743,27 @@ fn patched_lib() -> anyhow::Result<()> {
 
     thread::sleep(Duration::from_millis(1));
 
-    assert!(test_analog_pin_delayable(&handle.view().pins[0], 42));
+    let observed = handle.view().pins[0].wait_for_analog(|val| val == 42, PIN_TIMEOUT)?;
+    assert_eq!(observed.value, 42);
+
+    // Only built with --features tokio
+    #[cfg(feature = "tokio")]
+    {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .build()?;
+        let pin0 = &handle.view().pins[0];
+        let observed = runtime.block_on(async {
+            pin0.wait_for_analog_async(|val| val == 42, PIN_TIMEOUT)
+                .await
+        })?;
+        assert_eq!(observed.value, 42);
+        let timeout = runtime.block_on(async {
+            pin0.wait_for_analog_async(|val| val == 7, Duration::from_millis(10))
+                .await
+        });
+        assert!(timeout.is_err());
+    }
 
     Ok(())
 }
@@ -294,7 +1808,7 @@ fn sdcard() -> anyhow::Result<()> {
     )?;
     assert!(handle.start());
 
-    assert!(test_digital_pin_delayable(&handle.view().pins[0], true));
+    handle.view().pins[0].wait_for_level(true, PIN_TIMEOUT)?;
     handle.stop();
 
     root_dir.push("foo");
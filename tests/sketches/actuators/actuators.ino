@@ -0,0 +1,35 @@
+// Holds a servo on pin 0 at 135 degrees and takes 100 steps on a stepper with STEP on pin 1
+// and DIR on pin 2, all with pulses shorter than a millisecond
+
+const int servo = 0;
+const int step = 1;
+const int dir = 2;
+
+void setup() {
+    Serial.begin(9600);
+    pinMode(servo, OUTPUT);
+    pinMode(step, OUTPUT);
+    pinMode(dir, OUTPUT);
+
+    // Wait for the host to have everything attached
+    while (!Serial.available())
+        delay(1);
+    Serial.read();
+
+    digitalWrite(dir, HIGH);
+    for (int i = 0; i < 100; ++i) {
+        digitalWrite(step, HIGH);
+        delayMicroseconds(400);
+        digitalWrite(step, LOW);
+        delayMicroseconds(400);
+    }
+    Serial.println("STEPPED");
+}
+
+void loop() {
+    // 1936 us is three quarters of the default 544-2400 us range
+    digitalWrite(servo, HIGH);
+    delayMicroseconds(1936);
+    digitalWrite(servo, LOW);
+    delay(18);
+}
@@ -0,0 +1,43 @@
+// Drives both motors forward at full speed and reports the odometer pulses every 100 ms
+
+const int left_pwm = 0;
+const int left_dir = 1;
+const int right_pwm = 2;
+const int right_dir = 3;
+const int left_odo = 4;
+const int right_odo = 5;
+
+long left_pulses = 0;
+long right_pulses = 0;
+bool left_level = false;
+bool right_level = false;
+unsigned long reported = 0;
+
+void setup() {
+    Serial.begin(9600);
+    for (int pin : {left_pwm, left_dir, right_pwm, right_dir})
+        pinMode(pin, OUTPUT);
+    pinMode(left_odo, INPUT);
+    pinMode(right_odo, INPUT);
+    digitalWrite(left_dir, HIGH);
+    digitalWrite(right_dir, HIGH);
+    analogWrite(left_pwm, 255);
+    analogWrite(right_pwm, 255);
+}
+
+void loop() {
+    const bool left = digitalRead(left_odo);
+    const bool right = digitalRead(right_odo);
+    left_pulses += left && !left_level;
+    right_pulses += right && !right_level;
+    left_level = left;
+    right_level = right;
+
+    if (millis() - reported >= 100) {
+        reported = millis();
+        Serial.print("ODO ");
+        Serial.print(left_pulses);
+        Serial.print(' ');
+        Serial.println(right_pulses);
+    }
+}
@@ -0,0 +1,15 @@
+#include <csignal>
+#include <cstdlib>
+
+// Finishes up with exit code 3 when asked to stop
+volatile std::sig_atomic_t stopping = 0;
+
+void setup() {
+    std::signal(SIGTERM, [](int) { stopping = 1; });
+}
+
+void loop() {
+    if (stopping)
+        std::exit(3);
+    delay(1);
+}
@@ -0,0 +1,46 @@
+#include <fcntl.h>
+#include <sys/socket.h>
+#include <sys/syscall.h>
+#include <unistd.h>
+
+#ifndef __NR_io_uring_setup
+#define __NR_io_uring_setup 425
+#endif
+
+// Tries what it is told over serial, one of read <path>, write <path>,
+// socket <inet|inet6|unix|netlink>, x32socket, socketpair or io_uring
+void setup() { Serial.begin(9600); }
+
+void loop() {
+    if (Serial.available() <= 0)
+        return;
+    String command = Serial.readStringUntil('\n');
+    String arg = command.substring(command.indexOf(' ') + 1);
+
+    int fd = -1;
+    if (command.startsWith("read ")) {
+        fd = open(arg.c_str(), O_RDONLY);
+    } else if (command.startsWith("write ")) {
+        fd = open(arg.c_str(), O_RDWR);
+    } else if (command.startsWith("socket ")) {
+        int domain = arg == "inet" ? AF_INET : arg == "inet6" ? AF_INET6 : arg == "unix" ? AF_UNIX : AF_NETLINK;
+        fd = socket(domain, SOCK_DGRAM, 0);
+    } else if (command == "x32socket") {
+#ifdef __x86_64__
+        fd = syscall(0x40000000 | __NR_socket, AF_INET, SOCK_DGRAM, 0);
+#endif
+    } else if (command == "socketpair") {
+        int fds[2];
+        if (socketpair(AF_UNIX, SOCK_STREAM, 0, fds) == 0) {
+            close(fds[1]);
+            fd = fds[0];
+        }
+    } else if (command == "io_uring") {
+        // Zeroed struct io_uring_params
+        char params[120] = {};
+        fd = syscall(__NR_io_uring_setup, 1, params);
+    }
+    Serial.println(fd >= 0 ? "ALLOWED" : "DENIED");
+    if (fd >= 0)
+        close(fd);
+}
@@ -0,0 +1,10 @@
+// Raises pin 2 a moment after starting, then crashes
+
+void setup() {
+    pinMode(2, OUTPUT);
+    delay(50);
+    digitalWrite(2, HIGH);
+    delay(50);
+}
+
+void loop() { throw nullptr; }
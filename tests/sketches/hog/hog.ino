@@ -0,0 +1,9 @@
+#include <cstring>
+
+void setup() {}
+
+void loop() {
+    // Never freed, grows until the allocation fails
+    char* block = new char[1 << 20];
+    std::memset(block, 1, 1 << 20);
+}
@@ -0,0 +1,72 @@
+// Reports keys of a 4x4 keypad on pins 0-7 and the detents of a rotary encoder on pins 8 and 9
+
+const int rows[4] = {0, 1, 2, 3};
+const int cols[4] = {4, 5, 6, 7};
+const char keys[4][4] = {
+    {'1', '2', '3', 'A'},
+    {'4', '5', '6', 'B'},
+    {'7', '8', '9', 'C'},
+    {'*', '0', '#', 'D'},
+};
+const int enc_a = 8;
+const int enc_b = 9;
+
+char last_key = 0;
+int phase = 0;
+long steps = 0;
+long detents = 0;
+
+// Position in the gray code sequence 00, 10, 11, 01
+int read_phase() {
+    const bool a = digitalRead(enc_a);
+    const bool b = digitalRead(enc_b);
+    return a ? (b ? 2 : 1) : (b ? 3 : 0);
+}
+
+void update_encoder() {
+    const int next = read_phase();
+    const int diff = (next - phase + 4) % 4;
+    if (diff == 1)
+        ++steps;
+    else if (diff == 3)
+        --steps;
+    phase = next;
+    if (steps / 4 != detents) {
+        detents = steps / 4;
+        Serial.print("POS ");
+        Serial.println(detents);
+    }
+}
+
+void setup() {
+    Serial.begin(9600);
+    for (int row : rows) {
+        pinMode(row, OUTPUT);
+        digitalWrite(row, HIGH);
+    }
+    for (int col : cols)
+        pinMode(col, INPUT_PULLUP);
+    pinMode(enc_a, INPUT);
+    pinMode(enc_b, INPUT);
+    phase = read_phase();
+}
+
+void loop() {
+    char key = 0;
+    for (int r = 0; r < 4; ++r) {
+        digitalWrite(rows[r], LOW);
+        // Give the keypad a moment to answer
+        delay(2);
+        for (int c = 0; c < 4; ++c) {
+            if (!digitalRead(cols[c]))
+                key = keys[r][c];
+        }
+        digitalWrite(rows[r], HIGH);
+        update_encoder();
+    }
+    if (key && key != last_key) {
+        Serial.print("KEY ");
+        Serial.println(key);
+    }
+    last_key = key;
+}
@@ -0,0 +1,39 @@
+// Measures the distance of an HC-SR04 on pins 0 (trigger) and 1 (echo) and reports it in cm
+
+const int trigger = 0;
+const int echo = 1;
+
+void setup() {
+    Serial.begin(9600);
+    pinMode(trigger, OUTPUT);
+    pinMode(echo, INPUT);
+}
+
+// Microseconds the pin stays at `level`, 0 if it does not get there within the timeout
+unsigned long pulse_width(int pin, bool level, unsigned long timeout) {
+    const unsigned long start = micros();
+    while (digitalRead(pin) != level) {
+        if (micros() - start > timeout)
+            return 0;
+    }
+    const unsigned long rise = micros();
+    while (digitalRead(pin) == level) {
+        if (micros() - rise > timeout)
+            return 0;
+    }
+    return micros() - rise;
+}
+
+void loop() {
+    // Long enough for a simulation stepping every millisecond to notice
+    digitalWrite(trigger, HIGH);
+    delay(3);
+    digitalWrite(trigger, LOW);
+
+    const unsigned long width = pulse_width(echo, HIGH, 100000);
+    if (width > 0) {
+        Serial.print("CM ");
+        Serial.println(width / 58);
+    }
+    delay(50);
+}
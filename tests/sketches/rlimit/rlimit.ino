@@ -0,0 +1,16 @@
+#include <sys/resource.h>
+
+// The open file limit as it was before any sketch code ran
+const rlim_t open_files = [] {
+    rlimit limit{};
+    getrlimit(RLIMIT_NOFILE, &limit);
+    return limit.rlim_cur;
+}();
+
+void setup() {
+    Serial.begin(9600);
+    Serial.print("NOFILE ");
+    Serial.println(static_cast<unsigned long>(open_files));
+}
+
+void loop() { delay(1); }
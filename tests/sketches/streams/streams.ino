@@ -0,0 +1,9 @@
+#include <cstdio>
+
+void setup() {
+    std::puts("to stdout");
+    std::fflush(stdout);
+    std::fputs("to stderr\n", stderr);
+}
+
+void loop() {}